@@ -4,25 +4,43 @@
 use hmac::{Hmac, Mac};
 use sha1::Sha1;
 
-use crate::errors::{AuthParsingError, AuthValidationError};
+use crate::{
+    errors::{AuthParsingError, AuthValidationError},
+    image_transform::ImageTransform,
+};
 
 /// The machinery to parse and build Authenticated Target URLs.
 pub struct AuthenticatedTarget {
     key: Vec<u8>,
     digest: Vec<u8>,
     target: String,
+    transform: Option<ImageTransform>,
 }
 
 impl AuthenticatedTarget {
     /// Takes a known key and a target URL, useful for converting known plain
     /// data into a Camo URL.
     pub fn from_target(key: &[u8], target: &str) -> Self {
-        let digest = Self::calculate_hmac(key, target.as_bytes());
+        let digest = Self::calculate_hmac(key, target.as_bytes(), None);
 
         Self {
             key: key.to_vec(),
             digest,
             target: target.to_owned(),
+            transform: None,
+        }
+    }
+
+    /// Like `from_target`, but also signs image transformation parameters,
+    /// which end up in the query string of the Camo URL.
+    pub fn from_target_with_transform(key: &[u8], target: &str, transform: ImageTransform) -> Self {
+        let digest = Self::calculate_hmac(key, target.as_bytes(), Some(&transform));
+
+        Self {
+            key: key.to_vec(),
+            digest,
+            target: target.to_owned(),
+            transform: Some(transform),
         }
     }
 
@@ -47,15 +65,27 @@ impl AuthenticatedTarget {
             key: key.to_vec(),
             digest,
             target,
+            transform: None,
         })
     }
 
+    /// Adds the image transformation parameters from the query string of a
+    /// request. They are covered by the digest, so parameters that weren't
+    /// signed make the validation fail. An empty query string is ignored.
+    pub fn with_encoded_transform(mut self, query: &str) -> Result<Self, AuthParsingError> {
+        if !query.is_empty() {
+            self.transform = Some(ImageTransform::from_query(query)?);
+        }
+
+        Ok(self)
+    }
+
     /// Tries to validate the Target URL by calculating the HMAC and comparing
     /// it with the user-provided value. Returns the plain Target URL if it is
     /// valid, and a `AuthValidationError` otherwise.
     pub fn validated_target_url(&self) -> Result<String, AuthValidationError> {
         let target = self.target.as_bytes();
-        let expected = Self::calculate_hmac(&self.key, target);
+        let expected = Self::calculate_hmac(&self.key, target, self.transform.as_ref());
 
         if self.digest == expected {
             Ok(self.target.to_owned())
@@ -64,6 +94,11 @@ impl AuthenticatedTarget {
         }
     }
 
+    /// Returns the image transformation parameters, if there are any.
+    pub fn transform(&self) -> Option<&ImageTransform> {
+        self.transform.as_ref()
+    }
+
     /// Returns the hex-encoded Digest part (the first URL segment).
     pub fn encoded_digest(&self) -> String {
         hex::encode(self.digest.as_slice())
@@ -74,15 +109,26 @@ impl AuthenticatedTarget {
         hex::encode(self.target.as_bytes())
     }
 
-    /// Returns a full Camo URL, without a leading slash.
+    /// Returns a full Camo URL, without a leading slash. Image transformation
+    /// parameters are added as the query string.
     pub fn encoded_full_path(&self) -> String {
-        format!("{}/{}", self.encoded_digest(), self.encoded_target_url())
+        let path = format!("{}/{}", self.encoded_digest(), self.encoded_target_url());
+        match &self.transform {
+            Some(transform) => format!("{path}?{}", transform.to_query()),
+            None => path,
+        }
     }
 
-    /// Calculates the HMAC from a key and a target.
-    fn calculate_hmac(key: &[u8], target: &[u8]) -> Vec<u8> {
+    /// Calculates the HMAC from a key and a target. Image transformation
+    /// parameters are appended to the target, separated by a NUL byte, so
+    /// URLs without them keep their original digest.
+    fn calculate_hmac(key: &[u8], target: &[u8], transform: Option<&ImageTransform>) -> Vec<u8> {
         let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC can take key of any size");
         mac.update(target);
+        if let Some(transform) = transform {
+            mac.update(b"\0");
+            mac.update(transform.to_query().as_bytes());
+        }
         mac.finalize().into_bytes().to_vec()
     }
 }
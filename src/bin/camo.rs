@@ -3,7 +3,7 @@ use std::{net::SocketAddr, str::FromStr};
 use clap::Parser;
 use tokio::net::TcpListener;
 
-use camo_rs::{Settings, server, settings::LogFormat};
+use camo_rs::{Settings, mime_policy::MimePolicy, server, settings::LogFormat};
 
 async fn shutdown_signal() {
     tokio::signal::ctrl_c()
@@ -35,11 +35,7 @@ async fn run(settings: Settings) {
         LogFormat::Json => subscriber.json().with_span_list(false).init(),
     }
 
-    if !(settings.allow_all_types
-        || settings.allow_audio
-        || settings.allow_image
-        || settings.allow_video)
-    {
+    if !MimePolicy::new(&settings).allows_anything() {
         println!(
             "ERROR: The configuration does not allow any content-type, and it \
             would block all requests. This isn't useful. Exiting."
@@ -50,9 +46,18 @@ async fn run(settings: Settings) {
     let listen_addr = SocketAddr::from_str(&settings.listen).unwrap();
     let listener = TcpListener::bind(&listen_addr).await.unwrap();
 
-    let server = server::build(settings);
-    axum::serve(listener, server.into_make_service())
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .unwrap()
+    let server = match server::build(settings) {
+        Ok(server) => server,
+        Err(err) => {
+            println!("ERROR: {err}. Exiting.");
+            std::process::exit(1);
+        }
+    };
+    axum::serve(
+        listener,
+        server.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .unwrap()
 }
@@ -1,6 +1,6 @@
 use clap::Parser;
 
-use camo_rs::AuthenticatedTarget;
+use camo_rs::{AuthenticatedTarget, image_transform::ImageTransform};
 
 #[derive(clap::Parser, Debug)]
 #[clap(
@@ -14,13 +14,62 @@ struct Input {
     #[clap(short = 'k', long = "key", env = "CAMO_KEY")]
     key: String,
 
+    /// The width in pixels the image should be resized to
+    #[clap(long = "width")]
+    width: Option<u32>,
+
+    /// The height in pixels the image should be resized to
+    #[clap(long = "height")]
+    height: Option<u32>,
+
+    /// How the image should fit into the width and height: contain, cover,
+    /// or fill
+    #[clap(long = "fit")]
+    fit: Option<String>,
+
+    /// The JPEG quality of the resized image, from 1 to 100
+    #[clap(long = "quality")]
+    quality: Option<u8>,
+
     /// The target URL
     #[clap()]
     target: String,
 }
 
+impl Input {
+    /// Builds the query string for the transform parameters, so they are
+    /// validated the same way camo-rs validates them. Returns `None` if no
+    /// parameters are set.
+    fn transform_query(&self) -> Option<String> {
+        let params: Vec<String> = [
+            self.width.map(|width| format!("w={width}")),
+            self.height.map(|height| format!("h={height}")),
+            self.fit.as_ref().map(|fit| format!("fit={fit}")),
+            self.quality.map(|quality| format!("q={quality}")),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+
+        (!params.is_empty()).then(|| params.join("&"))
+    }
+}
+
 fn main() {
     let input = Input::parse();
-    let target = AuthenticatedTarget::from_target(input.key.as_bytes(), &input.target);
+    let key = input.key.as_bytes();
+
+    let target = match input.transform_query() {
+        Some(query) => match ImageTransform::from_query(&query) {
+            Ok(transform) => {
+                AuthenticatedTarget::from_target_with_transform(key, &input.target, transform)
+            }
+            Err(err) => {
+                eprintln!("invalid transform parameters: {err}");
+                std::process::exit(2);
+            }
+        },
+        None => AuthenticatedTarget::from_target(key, &input.target),
+    };
     println!("/{}", target.encoded_full_path());
 }
@@ -0,0 +1,483 @@
+//! Wrappers around upstream response bodies, used to enforce limits while the
+//! body is streamed to the client.
+
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use tokio::time::{Instant, Interval, MissedTickBehavior, Sleep};
+use tracing::{Span, warn};
+
+use crate::{
+    errors::{CamoError, ProxyError},
+    image_header::FrameCounter,
+};
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Aborts the upstream body if there is no data for longer than the idle
+/// timeout, or if the whole transfer is not done before the deadline.
+///
+/// Like with `MinRateBody`, only time spent waiting for the upstream counts,
+/// so a client that streams a long video at its own pace isn't cut off.
+///
+/// Once the response headers are sent to the client, the status code can't be
+/// changed anymore, so an aborted body shows up as a truncated response. The
+/// reason gets logged inside the request's span.
+pub struct TimedBody<B> {
+    inner: B,
+    idle_timeout: Duration,
+    idle: Pin<Box<Sleep>>,
+    /// The time left until the deadline, not counting the current wait.
+    remaining: Duration,
+    deadline: Pin<Box<Sleep>>,
+    /// When the body last returned `Poll::Pending`.
+    pending_since: Option<Instant>,
+    span: Span,
+    done: bool,
+}
+
+impl<B> TimedBody<B> {
+    /// Wraps `inner`, which may wait for the upstream until `deadline`.
+    pub fn new(inner: B, idle_timeout: Duration, deadline: Instant) -> Self {
+        Self {
+            inner,
+            idle_timeout,
+            idle: Box::pin(tokio::time::sleep(idle_timeout)),
+            remaining: deadline.saturating_duration_since(Instant::now()),
+            deadline: Box::pin(tokio::time::sleep_until(deadline)),
+            pending_since: None,
+            span: Span::current(),
+            done: false,
+        }
+    }
+
+    /// Ends the body with an error, and logs why.
+    fn abort(&mut self, err: ProxyError) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        self.done = true;
+        abort_with(&self.span, err)
+    }
+}
+
+impl<B> Body for TimedBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+
+        match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(pending_since) = self.pending_since.take() {
+                    self.remaining = self.remaining.saturating_sub(pending_since.elapsed());
+                }
+                return Poll::Ready(Some(Ok(frame)));
+            }
+            Poll::Ready(Some(Err(err))) => {
+                self.done = true;
+                return Poll::Ready(Some(Err(err.into())));
+            }
+            Poll::Ready(None) => {
+                self.done = true;
+                return Poll::Ready(None);
+            }
+            Poll::Pending => {}
+        }
+
+        // Both timers start when the body starts waiting for the upstream.
+        if self.pending_since.is_none() {
+            let now = Instant::now();
+            self.pending_since = Some(now);
+            let idle_until = now + self.idle_timeout;
+            self.idle.as_mut().reset(idle_until);
+            let deadline = now + self.remaining;
+            self.deadline.as_mut().reset(deadline);
+        }
+
+        if self.deadline.as_mut().poll(cx).is_ready() {
+            return self.abort(ProxyError::TransferTimeout);
+        }
+
+        if self.idle.as_mut().poll(cx).is_ready() {
+            return self.abort(ProxyError::IdleTimeout);
+        }
+
+        Poll::Pending
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done || self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Aborts the upstream body if its throughput drops below a minimum rate,
+/// measured over a sliding window.
+///
+/// Time only counts while the body is waiting for the upstream, so a slow
+/// client that doesn't poll the body doesn't lower the rate. The first window
+/// of waiting is a grace period, and a body that is already buffered will
+/// never be aborted.
+pub struct MinRateBody<B> {
+    inner: B,
+    min_rate: u64,
+    window: Duration,
+    /// The total time spent waiting for the upstream.
+    waited: Duration,
+    /// When the body last returned `Poll::Pending`.
+    pending_since: Option<Instant>,
+    received: u64,
+    samples: VecDeque<(Duration, u64)>,
+    check: Interval,
+    span: Span,
+    done: bool,
+}
+
+impl<B> MinRateBody<B> {
+    /// Wraps `inner`, which has to deliver at least `min_rate` bytes per
+    /// second on average over every `window`.
+    pub fn new(inner: B, min_rate: u64, window: Duration) -> Self {
+        let mut check = tokio::time::interval(Duration::from_secs(1));
+        check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Self {
+            inner,
+            min_rate,
+            window,
+            waited: Duration::ZERO,
+            pending_since: None,
+            received: 0,
+            samples: VecDeque::from([(Duration::ZERO, 0)]),
+            check,
+            span: Span::current(),
+            done: false,
+        }
+    }
+
+    /// Records the current amount of received bytes, and returns the average
+    /// rate over the last window of waiting, if the grace period is over.
+    fn sample_rate(&mut self) -> Option<u64> {
+        let now = self.waited;
+        self.samples.push_back((now, self.received));
+
+        // Keep the newest sample that is at least a window old as the
+        // baseline, and drop everything before that.
+        while self
+            .samples
+            .get(1)
+            .is_some_and(|(at, _)| now - *at >= self.window)
+        {
+            self.samples.pop_front();
+        }
+
+        if now < self.window {
+            return None;
+        }
+
+        let (since, baseline) = self.samples.front().expect("samples are never empty");
+        let elapsed = (now - *since).as_secs_f64();
+        Some(((self.received - baseline) as f64 / elapsed) as u64)
+    }
+}
+
+impl<B> Body for MinRateBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+
+        if let Some(since) = self.pending_since.take() {
+            self.waited += since.elapsed();
+        }
+
+        match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    self.received += data.len() as u64;
+                }
+                return Poll::Ready(Some(Ok(frame)));
+            }
+            Poll::Ready(Some(Err(err))) => {
+                self.done = true;
+                return Poll::Ready(Some(Err(err.into())));
+            }
+            Poll::Ready(None) => {
+                self.done = true;
+                return Poll::Ready(None);
+            }
+            Poll::Pending => {}
+        }
+
+        while self.check.poll_tick(cx).is_ready() {
+            if let Some(rate) = self.sample_rate()
+                && rate < self.min_rate
+            {
+                self.done = true;
+                return abort_with(&self.span, ProxyError::TransferTooSlow(rate));
+            }
+        }
+
+        self.pending_since = Some(Instant::now());
+        Poll::Pending
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done || self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Aborts a GIF or an animated WebP once it has more frames than allowed.
+/// These don't declare their number of frames in the header, so the frames
+/// are counted while the body is streamed, and the client gets a truncated
+/// image.
+pub struct FrameLimitBody<B> {
+    inner: B,
+    counter: FrameCounter,
+    max_frames: u32,
+    span: Span,
+    done: bool,
+}
+
+impl<B> FrameLimitBody<B> {
+    /// Wraps `inner`, whose frames are counted by `counter`.
+    pub fn new(inner: B, counter: FrameCounter, max_frames: u32) -> Self {
+        Self {
+            inner,
+            counter,
+            max_frames,
+            span: Span::current(),
+            done: false,
+        }
+    }
+}
+
+impl<B> Body for FrameLimitBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+
+        match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    let frames = self.counter.feed(data);
+                    if frames > self.max_frames {
+                        self.done = true;
+                        let err = CamoError::ImageTooLarge(format!("{frames} frames"));
+                        return abort_with(&self.span, err);
+                    }
+                }
+                Poll::Ready(Some(Ok(frame)))
+            }
+            Poll::Ready(Some(Err(err))) => {
+                self.done = true;
+                Poll::Ready(Some(Err(err.into())))
+            }
+            Poll::Ready(None) => {
+                self.done = true;
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done || self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Gets notified about the progress of a streamed body. The observer is dropped
+/// together with the body, so it can also be used to hold on to resources
+/// until the client is done.
+pub trait BodyObserver: Send + 'static {
+    /// Called for every chunk of data passed to the client.
+    fn on_data(&mut self, _len: usize) {}
+
+    /// Called if the body ends with an error.
+    fn on_error(&mut self) {}
+}
+
+/// Passes a body through unchanged, but reports its progress to a
+/// `BodyObserver`.
+pub struct ObservedBody<B, O> {
+    inner: B,
+    observer: O,
+}
+
+impl<B, O> ObservedBody<B, O> {
+    /// Wraps `inner`, and reports to `observer`.
+    pub fn new(inner: B, observer: O) -> Self {
+        Self { inner, observer }
+    }
+}
+
+impl<B, O> Body for ObservedBody<B, O>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: Into<BoxError>,
+    O: BodyObserver + Unpin,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let res = Pin::new(&mut self.inner).poll_frame(cx);
+        match &res {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    let len = data.len();
+                    self.observer.on_data(len);
+                }
+            }
+            Poll::Ready(Some(Err(_))) => self.observer.on_error(),
+            Poll::Ready(None) | Poll::Pending => {}
+        }
+
+        res.map(|frame| frame.map(|frame| frame.map_err(Into::into)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Yields data that was already read from a body, followed by the rest of
+/// the body.
+pub struct PrefixedBody<B> {
+    prefix: Option<Bytes>,
+    inner: B,
+}
+
+impl<B> Body for PrefixedBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if let Some(prefix) = self.prefix.take()
+            && !prefix.is_empty()
+        {
+            return Poll::Ready(Some(Ok(Frame::data(prefix))));
+        }
+
+        Pin::new(&mut self.inner)
+            .poll_frame(cx)
+            .map(|frame| frame.map(|frame| frame.map_err(Into::into)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.prefix.as_ref().is_none_or(Bytes::is_empty) && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let prefix_len = self.prefix.as_ref().map_or(0, Bytes::len) as u64;
+        let inner = self.inner.size_hint();
+        let mut hint = SizeHint::new();
+        hint.set_lower(inner.lower() + prefix_len);
+        if let Some(upper) = inner.upper() {
+            hint.set_upper(upper + prefix_len);
+        }
+        hint
+    }
+}
+
+/// Reads from `body` until at least `len` bytes are buffered, or the body
+/// ends. Returns the buffered bytes, and a body that yields them again,
+/// followed by the rest of `body`. Trailers read while buffering are dropped.
+pub async fn read_prefix<B>(mut body: B, len: usize) -> Result<(Bytes, PrefixedBody<B>), BoxError>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: Into<BoxError>,
+{
+    let mut prefix = Vec::new();
+    while prefix.len() < len {
+        match body.frame().await {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    prefix.extend_from_slice(data);
+                }
+            }
+            Some(Err(err)) => return Err(err.into()),
+            None => break,
+        }
+    }
+
+    let prefix = Bytes::from(prefix);
+    Ok((
+        prefix.clone(),
+        PrefixedBody {
+            prefix: Some(prefix),
+            inner: body,
+        },
+    ))
+}
+
+/// Logs why a body gets aborted inside the request's span, and returns the
+/// error for the body's consumer.
+fn abort_with<E>(span: &Span, err: E) -> Poll<Option<Result<Frame<Bytes>, BoxError>>>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let _entered = span.enter();
+    warn!("{:?}", err);
+
+    Poll::Ready(Some(Err(err.into())))
+}
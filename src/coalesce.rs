@@ -0,0 +1,420 @@
+//! Coalesces concurrent requests for the same target into a single upstream
+//! request, whose outcome is shared with all waiting clients.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    io,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use axum::body::Body;
+use futures_util::stream;
+use http_body_util::BodyExt;
+use hyper::{HeaderMap, Method, Response, StatusCode, Version, body::Bytes};
+use tokio::sync::watch;
+use tracing::Instrument;
+
+use crate::{errors::CamoError, header_wrangler::HeaderPolicy};
+
+/// The outcome of a coalesced request, as seen by every waiting client.
+type Outcome = Result<SharedResponse, Arc<CamoError>>;
+
+/// How many bytes of a shared body are buffered at most. Reading from the
+/// upstream pauses until the slowest client has caught up.
+const WINDOW_SIZE: usize = 1024 * 1024;
+
+/// Keeps track of in-flight upstream requests.
+#[derive(Clone)]
+pub struct Coalescer {
+    in_flight: Arc<Mutex<HashMap<String, Arc<Flight>>>>,
+    header_policy: HeaderPolicy,
+}
+
+/// A single in-flight request. The outcome is `None` until the leading
+/// request has finished its checks.
+struct Flight {
+    outcome: watch::Sender<Option<Outcome>>,
+    body: Arc<SharedBody>,
+}
+
+/// Everything but the body needed to build a response for one of the
+/// waiting clients.
+#[derive(Clone)]
+struct SharedResponse {
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+}
+
+/// A response body that is read from the upstream once, but can be streamed
+/// to any number of clients. Chunks are kept until all clients have read
+/// them, and at most `WINDOW_SIZE` bytes are buffered. Once the first chunk
+/// is dropped, clients can no longer join, and if all clients went away,
+/// reading from the upstream is cancelled.
+struct SharedBody {
+    state: Mutex<SharedBodyState>,
+    /// Notifies readers about new chunks, or the end of the body.
+    progress: watch::Sender<()>,
+    /// Notifies the upstream reader about readers moving on, or leaving.
+    consumed: watch::Sender<()>,
+}
+
+#[derive(Default)]
+struct SharedBodyState {
+    chunks: VecDeque<Bytes>,
+    /// The position of the first chunk in `chunks`.
+    first: usize,
+    /// The length of all chunks in `chunks`.
+    buffered: usize,
+    /// The position of the next chunk for each reader.
+    readers: HashMap<u64, usize>,
+    next_reader_id: u64,
+    finished: Option<Result<(), String>>,
+    cancelled: bool,
+}
+
+/// Owned by the tasks that run a flight. If they panic or are aborted, this
+/// fails the flight when dropped, so the waiting clients don't wait forever.
+struct FlightGuard {
+    coalescer: Coalescer,
+    key: String,
+    flight: Arc<Flight>,
+}
+
+/// A client's position in a `SharedBody`. Dropping it lets the body move on
+/// without this client.
+struct Reader {
+    body: Arc<SharedBody>,
+    id: u64,
+}
+
+impl Coalescer {
+    /// Creates a new instance. The `header_policy` has to match the one used
+    /// by the `Proxy`.
+    pub fn new(header_policy: HeaderPolicy) -> Self {
+        Self {
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            header_policy,
+        }
+    }
+
+    /// Builds the key that identifies identical requests. Besides the method
+    /// and the target, this includes all request headers that get passed to
+    /// the upstream, as they can change the upstream's response.
+    pub fn key(&self, method: &Method, target: &str, headers: &HeaderMap) -> String {
+        let mut key = format!("{method} {target}");
+        for name in self.header_policy.allowed_request_headers() {
+            for value in headers.get_all(name) {
+                key.push('\n');
+                key.push_str(name.as_str());
+                key.push(':');
+                key.push_str(&String::from_utf8_lossy(value.as_bytes()));
+            }
+        }
+
+        key
+    }
+
+    /// Runs `fetch` for the given key, unless a request with the same key is
+    /// already in flight. In that case, this waits for the other request and
+    /// returns a copy of its response, or of its error.
+    ///
+    /// The fetch runs in a separate task, so it finishes even if the client
+    /// that started it goes away. The body is only read as fast as the
+    /// slowest client reads it, and reading stops once all clients went away.
+    /// Clients can join a running transfer as long as nothing was dropped
+    /// from its buffer, otherwise they start a new one.
+    pub async fn run<F>(&self, key: String, fetch: F) -> Result<Response<Body>, CamoError>
+    where
+        F: Future<Output = Result<Response<Body>, CamoError>> + Send + 'static,
+    {
+        let (flight, reader, is_leader) = {
+            let mut in_flight = self.in_flight.lock().expect("lock is never poisoned");
+            let joined = in_flight
+                .get(&key)
+                .and_then(|flight| Some((flight.clone(), flight.body.join()?)));
+            match joined {
+                Some((flight, reader)) => (flight, reader, false),
+                None => {
+                    let flight = Arc::new(Flight {
+                        outcome: watch::Sender::new(None),
+                        body: Arc::new(SharedBody::new()),
+                    });
+                    let reader = flight.body.join().expect("new bodies can be joined");
+                    in_flight.insert(key.clone(), flight.clone());
+                    (flight, reader, true)
+                }
+            }
+        };
+
+        let mut outcome = flight.outcome.subscribe();
+        if is_leader {
+            let guard = FlightGuard {
+                coalescer: self.clone(),
+                key,
+                flight: flight.clone(),
+            };
+            tokio::spawn(
+                async move {
+                    let res = fetch.await;
+                    Self::publish(guard, res);
+                }
+                .in_current_span(),
+            );
+        }
+
+        let outcome = outcome
+            .wait_for(Option::is_some)
+            .await
+            .expect("flight guards always send an outcome")
+            .clone()
+            .expect("waited for the outcome to be there");
+
+        match outcome {
+            Ok(shared) => Ok(shared.to_response(reader.into_body())),
+            Err(err) => Err(CamoError::Coalesced(err)),
+        }
+    }
+
+    /// Shares the outcome of a flight with everyone waiting for it. For
+    /// successful requests, this starts reading the body in a separate task.
+    fn publish(guard: FlightGuard, res: Result<Response<Body>, CamoError>) {
+        let res = match res {
+            Ok(res) => res,
+            Err(err) => {
+                guard.coalescer.finish(&guard.key, &guard.flight);
+                guard.flight.outcome.send_replace(Some(Err(Arc::new(err))));
+                return;
+            }
+        };
+
+        let (parts, body) = res.into_parts();
+        guard.flight.outcome.send_replace(Some(Ok(SharedResponse {
+            status: parts.status,
+            version: parts.version,
+            headers: parts.headers,
+        })));
+
+        tokio::spawn(
+            async move {
+                let finished = guard.flight.body.read_from(body).await;
+                guard.coalescer.finish(&guard.key, &guard.flight);
+                guard.flight.body.finish(finished);
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Removes a flight, so new requests for the same key start a new one.
+    fn finish(&self, key: &str, flight: &Arc<Flight>) {
+        let mut in_flight = self.in_flight.lock().expect("lock is never poisoned");
+        if in_flight
+            .get(key)
+            .is_some_and(|current| Arc::ptr_eq(current, flight))
+        {
+            in_flight.remove(key);
+        }
+    }
+}
+
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        self.coalescer.finish(&self.key, &self.flight);
+        self.flight.outcome.send_if_modified(|outcome| {
+            let is_missing = outcome.is_none();
+            if is_missing {
+                *outcome = Some(Err(Arc::new(CamoError::CoalescedFetchAborted)));
+            }
+            is_missing
+        });
+        self.flight.body.abort();
+    }
+}
+
+impl SharedResponse {
+    /// Builds a new response with the given body.
+    fn to_response(&self, body: Body) -> Response<Body> {
+        let mut res = Response::new(body);
+        *res.status_mut() = self.status;
+        *res.version_mut() = self.version;
+        *res.headers_mut() = self.headers.clone();
+        res
+    }
+}
+
+impl SharedBody {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(SharedBodyState::default()),
+            progress: watch::Sender::new(()),
+            consumed: watch::Sender::new(()),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, SharedBodyState> {
+        self.state.lock().expect("lock is never poisoned")
+    }
+
+    /// Adds a reader that starts at the beginning of the body. Returns `None`
+    /// if the beginning is no longer buffered, or if the body was cancelled.
+    fn join(self: &Arc<Self>) -> Option<Reader> {
+        let mut state = self.state();
+        if state.first > 0 || state.cancelled {
+            return None;
+        }
+
+        let id = state.next_reader_id;
+        state.next_reader_id += 1;
+        state.readers.insert(id, 0);
+        Some(Reader {
+            body: self.clone(),
+            id,
+        })
+    }
+
+    /// Reads `body` into the buffer, pausing while the buffer is full.
+    /// Returns early if all readers went away.
+    async fn read_from(&self, mut body: Body) -> Result<(), String> {
+        let mut consumed = self.consumed.subscribe();
+        loop {
+            let is_full = {
+                let mut state = self.state();
+                if state.readers.is_empty() {
+                    state.cancelled = true;
+                    return Err("all clients went away".to_owned());
+                }
+
+                state.buffered >= WINDOW_SIZE
+            };
+
+            if is_full {
+                // The sender lives as long as `self`, so this can't fail.
+                let _ = consumed.changed().await;
+                continue;
+            }
+
+            // Reading a frame can be cancelled without losing data, which
+            // allows noticing readers going away while the upstream is idle.
+            tokio::select! {
+                frame = body.frame() => match frame {
+                    Some(Ok(frame)) => {
+                        if let Ok(data) = frame.into_data() {
+                            self.push(data);
+                        }
+                    }
+                    Some(Err(err)) => return Err(err.to_string()),
+                    None => return Ok(()),
+                },
+                _ = consumed.changed() => {}
+            }
+        }
+    }
+
+    /// Appends a chunk, and wakes up all readers.
+    fn push(&self, data: Bytes) {
+        let mut state = self.state();
+        state.buffered += data.len();
+        state.chunks.push_back(data);
+        drop(state);
+        self.progress.send_replace(());
+    }
+
+    /// Marks the body as complete, or as failed, and wakes up all readers.
+    fn finish(&self, finished: Result<(), String>) {
+        self.state().finished = Some(finished);
+        self.progress.send_replace(());
+    }
+
+    /// Marks the body as failed, unless it's already complete.
+    fn abort(&self) {
+        let mut state = self.state();
+        if state.finished.is_none() {
+            state.finished = Some(Err("upstream request was aborted".to_owned()));
+            drop(state);
+            self.progress.send_replace(());
+        }
+    }
+
+    /// Moves a reader to the given position, and drops all chunks that every
+    /// reader is done with.
+    fn advance(&self, id: u64, position: usize) {
+        let mut state = self.state();
+        state.readers.insert(id, position);
+        self.release(&mut state);
+    }
+
+    /// Removes a reader, and drops all chunks that every remaining reader is
+    /// done with.
+    fn leave(&self, id: u64) {
+        let mut state = self.state();
+        state.readers.remove(&id);
+        self.release(&mut state);
+    }
+
+    fn release(&self, state: &mut SharedBodyState) {
+        // Without readers, the buffer is kept for readers that might still
+        // join, until the upstream reader notices and cancels.
+        if let Some(slowest) = state.readers.values().min().copied() {
+            while state.first < slowest {
+                let chunk = state
+                    .chunks
+                    .pop_front()
+                    .expect("readers stay within the buffer");
+                state.buffered -= chunk.len();
+                state.first += 1;
+            }
+        }
+
+        self.consumed.send_replace(());
+    }
+}
+
+impl Reader {
+    /// Returns a new `Body` that streams all chunks from the beginning.
+    fn into_body(self) -> Body {
+        let progress = self.body.progress.subscribe();
+        let chunks = stream::unfold(
+            (self, progress, 0, false),
+            |(reader, mut progress, next, failed)| async move {
+                if failed {
+                    return None;
+                }
+
+                loop {
+                    {
+                        let state = reader.body.state();
+                        if let Some(chunk) = state.chunks.get(next - state.first) {
+                            let chunk = chunk.clone();
+                            drop(state);
+                            reader.body.advance(reader.id, next + 1);
+                            return Some((Ok(chunk), (reader, progress, next + 1, false)));
+                        }
+
+                        match &state.finished {
+                            Some(Ok(())) => return None,
+                            Some(Err(err)) => {
+                                let err = io::Error::other(err.to_owned());
+                                drop(state);
+                                return Some((Err(err), (reader, progress, next, true)));
+                            }
+                            None => {}
+                        }
+                    }
+
+                    // The sender lives as long as `reader.body`, so this can't
+                    // fail.
+                    let _ = progress.changed().await;
+                }
+            },
+        );
+
+        Body::from_stream(chunks)
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        self.body.leave(self.id);
+    }
+}
@@ -0,0 +1,71 @@
+//! Connector middleware used for upstream connections.
+
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use hyper::Uri;
+use tower_service::Service;
+
+use crate::body::BoxError;
+
+/// Error returned by `TimeoutConnector` if establishing the connection took
+/// too long.
+#[derive(Debug)]
+pub struct ConnectTimeoutElapsed;
+
+impl fmt::Display for ConnectTimeoutElapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("connecting to the upstream timed out")
+    }
+}
+
+impl std::error::Error for ConnectTimeoutElapsed {}
+
+/// Wraps a connector and limits the time it may take to establish a
+/// connection. For the HttpsConnector, this includes the TCP connection as
+/// well as the TLS handshake.
+#[derive(Clone, Debug)]
+pub struct TimeoutConnector<C> {
+    inner: C,
+    timeout: Duration,
+}
+
+impl<C> TimeoutConnector<C> {
+    /// Wraps the `inner` connector with the given timeout.
+    pub fn new(inner: C, timeout: Duration) -> Self {
+        Self { inner, timeout }
+    }
+}
+
+impl<C> Service<Uri> for TimeoutConnector<C>
+where
+    C: Service<Uri>,
+    C::Error: Into<BoxError>,
+    C::Future: Send + 'static,
+    C::Response: Send + 'static,
+{
+    type Response = C::Response;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let timeout = self.timeout;
+        let connecting = self.inner.call(dst);
+
+        Box::pin(async move {
+            match tokio::time::timeout(timeout, connecting).await {
+                Ok(res) => res.map_err(Into::into),
+                Err(_) => Err(Box::new(ConnectTimeoutElapsed) as BoxError),
+            }
+        })
+    }
+}
@@ -1,6 +1,6 @@
 //! Collection of Error types used by camo-rs
 
-use std::string::FromUtf8Error;
+use std::{net::IpAddr, path::PathBuf, string::FromUtf8Error, sync::Arc};
 
 use axum::{
     body::Body,
@@ -11,6 +11,12 @@ use hyper::{StatusCode, header};
 use thiserror::Error;
 use tracing::{info, warn};
 
+use crate::body::BoxError;
+
+/// The number of seconds clients are asked to wait before retrying a request
+/// that was rejected because camo-rs was overloaded.
+const OVERLOADED_RETRY_AFTER: u64 = 1;
+
 /// Error returned during parsing Authentication details (HMAC and Target
 /// provided via URL parameters).
 #[derive(Debug, Error)]
@@ -31,6 +37,10 @@ pub enum AuthParsingError {
     /// Returned if the Target URL cannot be encoded into a utf8 string.
     #[error("target url is not a utf8 string: {0}")]
     TargetNotUtf8(#[source] FromUtf8Error),
+
+    /// Returned if the image transformation parameters are invalid.
+    #[error("image transformation parameters are invalid: {0}")]
+    TransformInvalid(String),
 }
 
 /// Error returned during validating the URL-provided HMAC.
@@ -54,27 +64,100 @@ pub enum CamoError {
     #[error("authentication data was invalid: {0}")]
     AuthValidationError(#[source] AuthValidationError),
 
+    /// Returned to all clients of a coalesced request if the shared upstream
+    /// request failed.
+    #[error(transparent)]
+    Coalesced(Arc<CamoError>),
+
+    /// Returned to all clients of a coalesced request if the task running the
+    /// shared upstream request panicked, or was aborted.
+    #[error("shared upstream request was aborted")]
+    CoalescedFetchAborted,
+
+    /// Returned if the start of the upstream body doesn't match the declared
+    /// content-type. Contains the declared and the detected type.
+    #[error("upstream body does not match content-type {0}, looks like {1}")]
+    ContentTypeMismatch(String, String),
+
     /// Returned if the returned content-type is invalid.
     #[error("upstream content-type not accepted: {0}")]
     ContentTypeNotAccepted(String),
 
+    /// Returned if hotlink protection is enabled, and the request doesn't come
+    /// from an allowed site.
+    #[error("hotlinking from {0} is not allowed")]
+    HotlinkNotAllowed(String),
+
+    /// Returned if the header of an upstream image declares dimensions or a
+    /// number of frames over the limits.
+    #[error("upstream image exceeds the limits: {0}")]
+    ImageTooLarge(String),
+
     /// Returned if the upstream didn't send a content-type.
     #[error("upstream did not provide a content-type")]
     MissingContentType,
 
+    /// Returned if too many requests are in flight, and the request could not
+    /// be queued.
+    #[error("too many requests in flight, try again later")]
+    Overloaded,
+
     /// Returned if the Upstream Proxy failed.
     #[error("upstream proxy failed: {0}")]
     ProxyError(#[source] ProxyError),
 
+    /// Returned if the client sent too many requests, or received too much
+    /// data. Contains the number of seconds until the client may try again.
+    #[error("rate limit exceeded, try again in {0} seconds")]
+    RateLimited(u64),
+
+    /// Returned if an SVG could not be sanitized or rasterized.
+    #[error("upstream SVG could not be processed: {0}")]
+    SvgUnprocessable(String),
+
+    /// Returned if the requested image size exceeds the limits. Contains the
+    /// requested width and height.
+    #[error("requested image size {0}x{1} exceeds the limits")]
+    TransformTooLarge(u32, u32),
+
     /// Returned when the upstream returns an unexpected status code
     #[error("unexpected upstream status: {0}")]
     UnexpectedUpstreamStatus(u16),
 
+    /// Returned if the circuit breaker for the upstream host is open, because
+    /// too many requests to it failed recently.
+    #[error("upstream host {0} is failing, circuit breaker is open")]
+    UpstreamCircuitOpen(String),
+
+    /// Returned if the upstream sent a partial response without a valid
+    /// Content-Range header, or one that doesn't match the content-length.
+    #[error("upstream content-range header is missing or invalid")]
+    UpstreamContentRangeInvalid,
+
+    /// Returned if the upstream host has too many requests in flight, and no
+    /// slot became free in time.
+    #[error("upstream host {0} has too many concurrent requests")]
+    UpstreamHostBusy(String),
+
+    /// Returned if the upstream sent a partial response, but the request
+    /// didn't ask for a range.
+    #[error("upstream sent a partial response to a request without a range")]
+    UpstreamPartialContentUnrequested,
+
     /// Returned if the upstream returned a redirect, but we couldn't process
     /// the Location header
     #[error("upstream redirect location: header not processable")]
     UpstreamRedirectLocationUnprocessable,
 
+    /// Returned if reading the start of the upstream body failed.
+    #[error("upstream body could not be read: {0}")]
+    UpstreamBodyFailed(#[source] BoxError),
+
+    /// Returned if the upstream body has to be changed, but that's not
+    /// possible.
+    #[error("upstream body could not be processed: {0}")]
+    UpstreamBodyUnprocessable(String),
+
     /// Returned if the upstream content-length exceeds the limit.
     #[error("upstream content-length exceeds limit")]
     UpstreamResponseTooLong(usize),
@@ -85,15 +168,40 @@ impl CamoError {
         use CamoError::*;
 
         match self {
-            AuthParsingError(_) | AuthValidationError(_) => StatusCode::FORBIDDEN,
-            ContentTypeNotAccepted(_)
+            AuthParsingError(_) | AuthValidationError(_) | HotlinkNotAllowed(_) => {
+                StatusCode::FORBIDDEN
+            }
+            Coalesced(err) => err.status_code(),
+            CoalescedFetchAborted => StatusCode::INTERNAL_SERVER_ERROR,
+            ContentTypeMismatch(_, _)
+            | ContentTypeNotAccepted(_)
+            | ImageTooLarge(_)
             | MissingContentType
+            | SvgUnprocessable(_)
+            | UpstreamBodyUnprocessable(_)
+            | UpstreamContentRangeInvalid
+            | UpstreamPartialContentUnrequested
             | UpstreamRedirectLocationUnprocessable
             | UpstreamResponseTooLong(_) => StatusCode::UNPROCESSABLE_ENTITY,
-            ProxyError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            ProxyError(err) => err.status_code(),
+            RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            TransformTooLarge(_, _) => StatusCode::BAD_REQUEST,
             UnexpectedUpstreamStatus(status_code) => {
                 StatusCode::from_u16(*status_code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
             }
+            UpstreamBodyFailed(_) => StatusCode::BAD_GATEWAY,
+            UpstreamCircuitOpen(_) | UpstreamHostBusy(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl From<ProxyError> for CamoError {
+    fn from(err: ProxyError) -> Self {
+        match err {
+            ProxyError::CircuitOpen(host) => Self::UpstreamCircuitOpen(host),
+            ProxyError::HostConcurrencyExceeded(host) => Self::UpstreamHostBusy(host),
+            err => Self::ProxyError(err),
         }
     }
 }
@@ -106,7 +214,10 @@ impl IntoResponse for CamoError {
         // me get rid of the verbose logic inside the handler completely, so
         // let's keep it for now...
         match self {
-            AuthParsingError(_) | AuthValidationError(_) => {
+            AuthParsingError(_)
+            | AuthValidationError(_)
+            | HotlinkNotAllowed(_)
+            | RateLimited(_) => {
                 info!("{:?}", self);
             }
             _ => {
@@ -114,9 +225,19 @@ impl IntoResponse for CamoError {
             }
         }
 
-        Response::builder()
+        let mut builder = Response::builder()
             .status(self.status_code())
-            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8");
+        let retry_after = match self {
+            Overloaded => Some(OVERLOADED_RETRY_AFTER),
+            RateLimited(retry_after) => Some(retry_after),
+            _ => None,
+        };
+        if let Some(retry_after) = retry_after {
+            builder = builder.header(header::RETRY_AFTER, retry_after);
+        }
+
+        builder
             .body(Body::from(self.to_string()))
             .expect("Generating this error never fails")
             .into_response()
@@ -127,17 +248,92 @@ impl IntoResponse for CamoError {
 #[derive(Debug, Error)]
 #[non_exhaustive]
 pub enum ProxyError {
+    /// Returned if the circuit breaker for the upstream host is open.
+    #[error("circuit breaker for upstream host {0} is open")]
+    CircuitOpen(String),
+
+    /// Returned if the TCP connection and TLS handshake didn't finish before
+    /// the configurable timeout expired.
+    #[error("connecting to the upstream timed out")]
+    ConnectTimeout,
+
+    /// Returned if the upstream didn't send the response headers before the
+    /// configurable timeout expired.
+    #[error("upstream response headers timed out: {0}")]
+    HeaderTimeout(#[source] tokio::time::error::Elapsed),
+
+    /// Returned if the upstream host had too many requests in flight, and no
+    /// slot became free before the response headers timeout expired.
+    #[error("too many concurrent requests to upstream host {0}")]
+    HostConcurrencyExceeded(String),
+
+    /// Returned if the upstream didn't send any body data for longer than the
+    /// configurable idle timeout. This can only happen while streaming.
+    #[error("upstream body stalled for longer than the idle timeout")]
+    IdleTimeout,
+
+    /// Returned if the target is an IP address of a family that is not
+    /// allowed by the configuration.
+    #[error("upstream address {0} is not in an allowed IP family")]
+    IpFamilyNotPermitted(IpAddr),
+
     /// Returned if building the upstream request failed, usually due to invalid
     /// parameters like target URLs, headers, ...
     #[error("building the upstream request failed: {0}")]
     RequestBuildingFailed(#[source] hyper::http::Error),
 
+    /// Returned if the whole transfer, including the body, didn't finish
+    /// before the configurable timeout expired. This can only happen while
+    /// streaming.
+    #[error("upstream transfer took longer than the transfer timeout")]
+    TransferTimeout,
+
+    /// Returned if the upstream's throughput dropped below the configurable
+    /// minimum rate. This can only happen while streaming.
+    #[error("upstream transfer rate of {0} bytes/s is below the minimum rate")]
+    TransferTooSlow(u64),
+
     /// Returned if the request to the upstream failed.
     #[error("upstream error: {0}")]
     UpstreamError(#[source] hyper_util::client::legacy::Error),
+}
+
+impl ProxyError {
+    fn status_code(&self) -> StatusCode {
+        use ProxyError::*;
+
+        match self {
+            ConnectTimeout | HeaderTimeout(_) | IdleTimeout | TransferTimeout => {
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            CircuitOpen(_) | HostConcurrencyExceeded(_) => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Error returned if camo-rs cannot be set up with the provided configuration.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum SetupError {
+    /// Returned if the CA bundle could not be read or parsed.
+    #[error("CA bundle {0} could not be loaded: {1}")]
+    CaBundleUnreadable(PathBuf, #[source] rustls::pki_types::pem::Error),
+
+    /// Returned if the client certificate could not be read or parsed.
+    #[error("client certificate {0} could not be loaded: {1}")]
+    ClientCertUnreadable(PathBuf, #[source] rustls::pki_types::pem::Error),
+
+    /// Returned if the client certificate's private key could not be read or
+    /// parsed.
+    #[error("client key {0} could not be loaded: {1}")]
+    ClientKeyUnreadable(PathBuf, #[source] rustls::pki_types::pem::Error),
+
+    /// Returned if no trusted root certificate could be found.
+    #[error("no trusted root certificates are available")]
+    NoTlsRoots,
 
-    /// Returned if the connection didn't get established until the configurable
-    /// timeout expired.
-    #[error("upstream request timed out: {0}")]
-    UpstreamTimeout(#[source] tokio::time::error::Elapsed),
+    /// Returned if rustls refused the TLS configuration.
+    #[error("invalid TLS configuration: {0}")]
+    TlsConfigInvalid(#[source] rustls::Error),
 }
@@ -4,25 +4,58 @@ use axum::http::{HeaderMap, HeaderValue};
 use hyper::header::{self, HeaderName};
 use url::Url;
 
-/// These are the only headers that will be passed to the upstream. The main
-/// motivation is to avoid leaking potential secrets like the Cookie or
-/// Authorization headers, but it probably also helps to reduce client
-/// fingerprinting. Maybe. This isn't really a privacy-product anyway.
-pub const ALLOWED_REQUEST_HEADERS: [HeaderName; 8] = [
+use crate::{
+    Settings,
+    settings::{SecurityHeader, UpstreamHeader},
+};
+
+/// By default, these are the only headers that will be passed to the upstream.
+/// The main motivation is to avoid leaking potential secrets like the Cookie
+/// or Authorization headers, but it probably also helps to reduce client
+/// fingerprinting. Maybe. This isn't really a privacy-product anyway. The
+/// list can be changed with the `HeaderPolicy`.
+///
+/// `Range` is only passed along if it asks for a single range, see
+/// `is_single_byte_range` for details.
+pub const ALLOWED_REQUEST_HEADERS: [HeaderName; 10] = [
     header::ACCEPT,
     header::ACCEPT_ENCODING,
     header::ACCEPT_LANGUAGE,
     header::CACHE_CONTROL,
     header::IF_MODIFIED_SINCE,
     header::IF_NONE_MATCH,
+    header::IF_RANGE,
     header::PRAGMA,
+    header::RANGE,
     header::TE,
 ];
 
-/// These headers get set to the provided value in any HTTP response that gets
-/// proxied through Camo. The motivation here is to reduce the security risk
-/// of running a proxy like this. For example, the CSP header ensures that no
-/// JavaScript inside a SVG can do Evil Stuff(tm).
+/// By default, these are the only upstream response headers that will be
+/// passed to the client. Everything else, most importantly `Set-Cookie`, but
+/// also headers like `Strict-Transport-Security` or `Alt-Svc`, would apply to
+/// the Camo origin instead of the upstream, so it's dropped. The list can be
+/// extended with the `HeaderPolicy`.
+pub const ALLOWED_RESPONSE_HEADERS: [HeaderName; 13] = [
+    header::ACCEPT_RANGES,
+    header::AGE,
+    header::CACHE_CONTROL,
+    header::CONTENT_ENCODING,
+    header::CONTENT_LENGTH,
+    header::CONTENT_RANGE,
+    header::CONTENT_TYPE,
+    header::DATE,
+    header::ETAG,
+    header::EXPIRES,
+    header::LAST_MODIFIED,
+    header::LOCATION,
+    header::VARY,
+];
+
+/// By default, these headers get set to the provided value in any HTTP
+/// response that gets proxied through Camo, and in error responses. The
+/// motivation here is to reduce the security risk of running a proxy like
+/// this. For example, the CSP header ensures that no JavaScript inside a SVG
+/// can do Evil Stuff(tm). The list can be changed with the `HeaderPolicy`.
 pub const SECURE_RESPONSE_HEADERS: [(HeaderName, &str); 4] = [
     (
         header::CONTENT_SECURITY_POLICY,
@@ -33,40 +66,281 @@ pub const SECURE_RESPONSE_HEADERS: [(HeaderName, &str); 4] = [
     (header::X_XSS_PROTECTION, "1; mode=block"),
 ];
 
-/// Assigns allowlisted request headers from an original `HeaderMap` into a
-/// second map for use in the request to the upstream.
-///
-/// Check the definition of `const ALLOWED_REQUEST_HEADERS` for the copied
-/// header names. Existing values in the target `HeaderMap` for allow-listed
-/// headers will be overwriten, but other headers will be left untouched.
-pub fn assign_filtered_request_headers(from: &HeaderMap, to: &mut HeaderMap) {
-    for key in ALLOWED_REQUEST_HEADERS {
-        if let Some(value) = from.get(&key) {
-            if let Some(header) = to.get_mut(&key) {
-                *header = value.clone();
-            } else {
-                to.append(&key, value.clone());
+/// Decides which headers are sent to the upstream, and which headers are sent
+/// to the client. Built from the `Settings`, this combines the allowed client
+/// request headers with the static headers configured per upstream host, the
+/// allowed response headers, and the security headers.
+#[derive(Clone, Debug)]
+pub struct HeaderPolicy {
+    allowed_request_headers: Vec<HeaderName>,
+    allowed_response_headers: Vec<HeaderName>,
+    cors_allow_origins: Vec<String>,
+    cors_max_age: usize,
+    security_headers: Vec<SecurityHeader>,
+    upstream_headers: Vec<UpstreamHeader>,
+}
+
+impl Default for HeaderPolicy {
+    /// Passes the `ALLOWED_REQUEST_HEADERS` and `ALLOWED_RESPONSE_HEADERS`,
+    /// sets the `SECURE_RESPONSE_HEADERS`, doesn't add any headers to
+    /// upstream requests, and doesn't allow CORS.
+    fn default() -> Self {
+        Self {
+            allowed_request_headers: ALLOWED_REQUEST_HEADERS.to_vec(),
+            allowed_response_headers: ALLOWED_RESPONSE_HEADERS.to_vec(),
+            cors_allow_origins: vec![],
+            cors_max_age: 0,
+            security_headers: default_security_headers().collect(),
+            upstream_headers: vec![],
+        }
+    }
+}
+
+impl HeaderPolicy {
+    /// Creates a new policy based on the header-related settings.
+    pub fn new(settings: &Settings) -> Self {
+        let mut allowed_request_headers: Vec<HeaderName> = ALLOWED_REQUEST_HEADERS
+            .into_iter()
+            .filter(|name| !settings.strip_request_headers.contains(name))
+            .collect();
+        for name in &settings.pass_request_headers {
+            if !allowed_request_headers.contains(name) {
+                allowed_request_headers.push(name.clone());
+            }
+        }
+
+        let mut allowed_response_headers = ALLOWED_RESPONSE_HEADERS.to_vec();
+        for name in &settings.pass_response_headers {
+            if !allowed_response_headers.contains(name) {
+                allowed_response_headers.push(name.clone());
+            }
+        }
+
+        let mut security_headers: Vec<SecurityHeader> = default_security_headers()
+            .filter(|default| {
+                !settings.strip_security_headers.contains(&default.name)
+                    && !settings
+                        .security_headers
+                        .iter()
+                        .any(|header| header.name == default.name)
+            })
+            .collect();
+        security_headers.extend(settings.security_headers.iter().cloned());
+
+        Self {
+            allowed_request_headers,
+            allowed_response_headers,
+            cors_allow_origins: settings.cors_allow_origins.clone(),
+            cors_max_age: settings.cors_max_age,
+            security_headers,
+            upstream_headers: settings.upstream_headers.clone(),
+        }
+    }
+
+    /// Returns the names of all client request headers passed to the
+    /// upstream.
+    pub fn allowed_request_headers(&self) -> &[HeaderName] {
+        &self.allowed_request_headers
+    }
+
+    /// Assigns allowlisted request headers from an original `HeaderMap` into a
+    /// second map for use in the request to the upstream.
+    ///
+    /// Existing values in the target `HeaderMap` for allow-listed headers will
+    /// be overwriten, but other headers will be left untouched.
+    pub fn assign_filtered_request_headers(&self, from: &HeaderMap, to: &mut HeaderMap) {
+        for key in &self.allowed_request_headers {
+            if let Some(value) = from.get(key) {
+                if key == header::RANGE && !value.to_str().is_ok_and(is_single_byte_range) {
+                    continue;
+                }
+
+                if let Some(header) = to.get_mut(key) {
+                    *header = value.clone();
+                } else {
+                    to.append(key, value.clone());
+                }
             }
         }
     }
+
+    /// Removes all upstream response headers that are not allowlisted.
+    pub fn filter_response_headers(&self, headers: &mut HeaderMap) {
+        let disallowed: Vec<HeaderName> = headers
+            .keys()
+            .filter(|name| !self.allowed_response_headers.contains(name))
+            .cloned()
+            .collect();
+        for name in disallowed {
+            headers.remove(name);
+        }
+    }
+
+    /// Sets response headers to increase security a bit.
+    ///
+    /// If one of the headers is already set, this function will override the
+    /// values. All other existing headers will not be touched.
+    pub fn force_secure_response_headers(&self, headers: &mut HeaderMap) {
+        for SecurityHeader { name, value } in &self.security_headers {
+            headers.insert(name, value.clone());
+        }
+    }
+
+    /// Replaces all CORS headers from the upstream with the ones allowing the
+    /// request's `origin`, if it's one of the configured origins. Without a
+    /// matching origin, no CORS headers are set at all.
+    pub fn assign_cors_headers(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        let upstream_cors_headers: Vec<HeaderName> = headers
+            .keys()
+            .filter(|name| name.as_str().starts_with("access-control-"))
+            .cloned()
+            .collect();
+        for name in upstream_cors_headers {
+            headers.remove(name);
+        }
+
+        // With a list of origins, the response depends on the request's
+        // origin, so caches have to know about that.
+        if !self.cors_allow_origins.is_empty() && !self.allows_all_origins() {
+            headers.append(header::VARY, HeaderValue::from_static("origin"));
+        }
+
+        if let Some(allowed_origin) = self.allowed_origin(origin) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allowed_origin);
+            headers.insert(
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                HeaderValue::from_static("content-length, content-range"),
+            );
+        }
+    }
+
+    /// Sets the headers answering a CORS preflight request from `origin`. Only
+    /// `GET` and `HEAD` requests, and the request headers passed to the
+    /// upstream, are allowed.
+    pub fn assign_cors_preflight_headers(
+        &self,
+        origin: Option<&HeaderValue>,
+        headers: &mut HeaderMap,
+    ) {
+        self.assign_cors_headers(origin, headers);
+        if !headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN) {
+            return;
+        }
+
+        let allowed_headers = self
+            .allowed_request_headers
+            .iter()
+            .map(HeaderName::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_static("GET, HEAD"),
+        );
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            HeaderValue::from_str(&allowed_headers).expect("header names are valid values"),
+        );
+        headers.insert(header::ACCESS_CONTROL_MAX_AGE, self.cors_max_age.into());
+    }
+
+    fn allows_all_origins(&self) -> bool {
+        self.cors_allow_origins.iter().any(|origin| origin == "*")
+    }
+
+    /// Returns the value for `Access-Control-Allow-Origin`, if the request's
+    /// origin is allowed.
+    fn allowed_origin(&self, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+        if self.allows_all_origins() {
+            return Some(HeaderValue::from_static("*"));
+        }
+
+        let origin = origin?;
+        let origin_str = origin.to_str().ok()?;
+        self.cors_allow_origins
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(origin_str))
+            .then(|| origin.clone())
+    }
+
+    /// Sets the static headers configured for `host`, overriding headers
+    /// with the same name. Headers for a specific host take precedence over
+    /// headers for all hosts.
+    pub fn assign_upstream_headers(&self, host: &str, to: &mut HeaderMap) {
+        let for_all_hosts = self.upstream_headers.iter().filter(|h| h.host.is_none());
+        let for_this_host = self.upstream_headers.iter().filter(|h| {
+            h.host
+                .as_ref()
+                .is_some_and(|h| h.eq_ignore_ascii_case(host))
+        });
+
+        for upstream_header in for_all_hosts.chain(for_this_host) {
+            to.insert(&upstream_header.name, upstream_header.value.clone());
+        }
+    }
 }
 
-/// Sets response headers to increase security a bit.
+/// Returns the `SECURE_RESPONSE_HEADERS` as `SecurityHeader`s.
+fn default_security_headers() -> impl Iterator<Item = SecurityHeader> {
+    SECURE_RESPONSE_HEADERS
+        .into_iter()
+        .map(|(name, value)| SecurityHeader {
+            name,
+            value: HeaderValue::from_static(value),
+        })
+}
+
+/// Checks whether a `Range` request header asks for exactly one byte range.
 ///
-/// Check the definition of `const SECURE_RESPONSE_HEADERS` for details. If one
-/// of the headers is already set, this function will override the values. All
-/// other existing headers will not be touched.
-pub fn force_secure_response_headers(headers: &mut HeaderMap) {
-    for (key, value) in SECURE_RESPONSE_HEADERS {
-        let header_value = HeaderValue::from_static(value);
-        if let Some(header) = headers.get_mut(&key) {
-            *header = header_value;
-        } else {
-            headers.append(&key, header_value);
-        }
+/// Multiple ranges would be answered with a `multipart/byteranges` response,
+/// which neither passes the content-type checks nor is useful for media
+/// players. Dropping those headers makes the upstream send the full response
+/// instead, which is always allowed.
+pub fn is_single_byte_range(range: &str) -> bool {
+    range
+        .strip_prefix("bytes=")
+        .is_some_and(|spec| !spec.trim().is_empty() && !spec.contains(','))
+}
+
+/// A parsed `Content-Range` response header for a single byte range, like
+/// `bytes 0-1023/4096`. The complete length is `None` if the upstream doesn't
+/// know it, which is written as `*`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ContentRange {
+    pub first_byte: u64,
+    pub last_byte: u64,
+    pub complete_length: Option<u64>,
+}
+
+impl ContentRange {
+    /// Returns the number of bytes in this range.
+    pub fn range_length(&self) -> u64 {
+        self.last_byte - self.first_byte + 1
     }
 }
 
+/// Parses the `Content-Range` header of a `206 Partial Content` response.
+/// Returns `None` if the header is malformed, or if the range is invalid.
+pub fn parse_content_range(value: &str) -> Option<ContentRange> {
+    let (range, complete_length) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (first_byte, last_byte) = range.split_once('-')?;
+
+    let content_range = ContentRange {
+        first_byte: first_byte.parse().ok()?,
+        last_byte: last_byte.parse().ok()?,
+        complete_length: match complete_length {
+            "*" => None,
+            complete_length => Some(complete_length.parse().ok()?),
+        },
+    };
+
+    let is_valid = content_range.first_byte <= content_range.last_byte
+        && content_range
+            .complete_length
+            .is_none_or(|complete_length| content_range.last_byte < complete_length);
+    is_valid.then_some(content_range)
+}
+
 /// Tries to resolve a URL from a Location header and turns absolute redirect
 /// targets into absolute URLs.
 pub fn resolve_location_header(base: &str, location: &str) -> Result<String, url::ParseError> {
@@ -0,0 +1,81 @@
+//! Hotlink protection, based on the headers browsers send to tell where a
+//! request comes from.
+
+use hyper::{HeaderMap, header};
+use url::Url;
+
+use crate::{Settings, errors::CamoError};
+
+/// Checks whether a request comes from one of the allowed sites.
+#[derive(Clone, Debug)]
+pub struct HotlinkPolicy {
+    allowed_hosts: Vec<String>,
+    allow_empty_referer: bool,
+}
+
+impl HotlinkPolicy {
+    /// Creates a new policy based on the settings. Returns `None` if hotlink
+    /// protection is disabled, which is the case if no hosts are allowed.
+    pub fn new(settings: &Settings) -> Option<Self> {
+        if settings.hotlink_allow_hosts.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            allowed_hosts: settings
+                .hotlink_allow_hosts
+                .iter()
+                .map(|host| host.to_ascii_lowercase())
+                .collect(),
+            allow_empty_referer: settings.hotlink_allow_empty_referer,
+        })
+    }
+
+    /// Checks the request headers. The `Origin` header is preferred, as it
+    /// can't be stripped by a referrer policy, with a fallback to `Referer`.
+    /// If neither is there, requests the browser marks as coming from the
+    /// same site as camo-rs are allowed, requests it marks as cross-site are
+    /// rejected, and everything else depends on whether empty referers are
+    /// allowed.
+    pub fn check(&self, headers: &HeaderMap) -> Result<(), CamoError> {
+        let source = [header::ORIGIN, header::REFERER]
+            .iter()
+            .filter_map(|name| headers.get(name)?.to_str().ok())
+            .find(|value| !value.is_empty() && *value != "null");
+
+        if let Some(source) = source {
+            let url = Url::parse(source).ok();
+            let host = url.as_ref().and_then(Url::host_str);
+            return match host {
+                Some(host) if self.is_allowed_host(host) => Ok(()),
+                _ => Err(CamoError::HotlinkNotAllowed(source.to_owned())),
+            };
+        }
+
+        let fetch_site = headers
+            .get("sec-fetch-site")
+            .and_then(|value| value.to_str().ok());
+        match fetch_site {
+            Some("same-origin" | "same-site") => Ok(()),
+            Some("cross-site") => Err(CamoError::HotlinkNotAllowed(
+                "cross-site request without referer".to_owned(),
+            )),
+            _ if self.allow_empty_referer => Ok(()),
+            _ => Err(CamoError::HotlinkNotAllowed("no referer".to_owned())),
+        }
+    }
+
+    /// Checks a host against the allowlist. Entries starting with `*.` allow
+    /// all subdomains, but not the domain itself.
+    fn is_allowed_host(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        self.allowed_hosts
+            .iter()
+            .any(|allowed| match allowed.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .is_some_and(|subdomain| subdomain.ends_with('.')),
+                None => *allowed == host,
+            })
+    }
+}
@@ -0,0 +1,541 @@
+//! Reads the dimensions and frame counts of images from their headers, so
+//! images that would decode to huge bitmaps can be rejected before anything
+//! is passed to the client.
+
+use std::ops::Range;
+
+use crate::{Settings, errors::CamoError};
+
+/// The maximum number of bytes read to find an image's header. JPEGs can have
+/// large metadata segments before their dimensions.
+pub const MAX_HEADER_LENGTH: usize = 256 * 1024;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// The type of a box in an ISO media file, like AVIF, and the range of its
+/// contents.
+type IsoBox<'a> = (&'a [u8], Range<usize>);
+
+/// The dimensions of an image, as declared in its header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    /// The number of frames, if the header declares it. GIFs, animated
+    /// WebPs, and AVIF sequences don't, and neither do PNGs with a lot of
+    /// metadata before `acTL`.
+    pub frames: Option<u32>,
+}
+
+impl ImageInfo {
+    /// Returns the number of pixels of a single frame.
+    pub fn pixels(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+}
+
+/// The result of parsing the start of an image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Header {
+    /// The header was found.
+    Parsed(ImageInfo),
+    /// The header continues after the data. Contains the number of bytes
+    /// needed to read further.
+    Incomplete(usize),
+    /// The data is not a PNG, JPEG, GIF, WebP, or AVIF.
+    Unknown,
+    /// The data looks like an image, but the header is broken.
+    Malformed,
+}
+
+/// Parses the header of a PNG, JPEG, GIF, WebP, or AVIF image, detected by
+/// the first bytes of `data`.
+pub fn parse(data: &[u8]) -> Header {
+    let result = match data {
+        _ if data.starts_with(PNG_SIGNATURE) => parse_png(data),
+        [0xff, 0xd8, 0xff, ..] => parse_jpeg(data),
+        _ if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") => parse_gif(data),
+        _ if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") => parse_webp(data),
+        _ if data.get(4..8) == Some(b"ftyp") => parse_avif(data),
+        _ => return Header::Unknown,
+    };
+
+    match result {
+        Ok(info) => Header::Parsed(info),
+        Err(header) => header,
+    }
+}
+
+/// Reads the dimensions from `IHDR`, and the number of frames from `acTL`,
+/// which has to come before the first `IDAT`. If there are more than
+/// `MAX_HEADER_LENGTH` bytes of other chunks before that, the number of
+/// frames is unknown.
+fn parse_png(data: &[u8]) -> Result<ImageInfo, Header> {
+    if get(data, 12..16)? != b"IHDR" {
+        return Err(Header::Malformed);
+    }
+    let width = u32_be(get(data, 16..20)?);
+    let height = u32_be(get(data, 20..24)?);
+
+    // Signature, and `IHDR` with its length, type, data, and CRC.
+    let mut pos = PNG_SIGNATURE.len() + 25;
+    loop {
+        if pos + 12 > MAX_HEADER_LENGTH {
+            return Ok(ImageInfo {
+                width,
+                height,
+                frames: None,
+            });
+        }
+
+        let length = u32_be(get(data, pos..pos + 4)?) as usize;
+        match get(data, pos + 4..pos + 8)? {
+            b"acTL" => {
+                let frames = u32_be(get(data, pos + 8..pos + 12)?);
+                return Ok(ImageInfo {
+                    width,
+                    height,
+                    frames: Some(frames),
+                });
+            }
+            b"IDAT" | b"IEND" => {
+                return Ok(ImageInfo {
+                    width,
+                    height,
+                    frames: Some(1),
+                });
+            }
+            _ => {}
+        }
+        pos = pos
+            .checked_add(12)
+            .and_then(|pos| pos.checked_add(length))
+            .ok_or(Header::Malformed)?;
+    }
+}
+
+/// Reads the dimensions from the first start-of-frame segment.
+fn parse_jpeg(data: &[u8]) -> Result<ImageInfo, Header> {
+    let mut pos = 2;
+    loop {
+        // Markers can be padded with any number of 0xff bytes.
+        while get(data, pos + 1..pos + 2)? == [0xff] {
+            pos += 1;
+        }
+        let [0xff, marker] = *get(data, pos..pos + 2)? else {
+            return Err(Header::Malformed);
+        };
+
+        match marker {
+            0xd0..=0xd9 | 0x01 => {
+                pos += 2;
+                continue;
+            }
+            // Start of scan, before a frame was declared.
+            0xda => return Err(Header::Malformed),
+            // Start of frame, except for DHT, JPG, and DAC.
+            0xc0..=0xcf if !matches!(marker, 0xc4 | 0xc8 | 0xcc) => {
+                let height = u16_be(get(data, pos + 5..pos + 7)?);
+                let width = u16_be(get(data, pos + 7..pos + 9)?);
+                return Ok(ImageInfo {
+                    width: width as u32,
+                    height: height as u32,
+                    frames: Some(1),
+                });
+            }
+            _ => {}
+        }
+
+        pos += 2 + u16_be(get(data, pos + 2..pos + 4)?) as usize;
+    }
+}
+
+/// Reads the logical screen size. GIFs don't declare their frame count.
+fn parse_gif(data: &[u8]) -> Result<ImageInfo, Header> {
+    Ok(ImageInfo {
+        width: u16_le(get(data, 6..8)?) as u32,
+        height: u16_le(get(data, 8..10)?) as u32,
+        frames: None,
+    })
+}
+
+/// Reads the dimensions from the first chunk, which is either a lossy or
+/// lossless bitstream, or the extended header with the canvas size.
+fn parse_webp(data: &[u8]) -> Result<ImageInfo, Header> {
+    let (width, height, frames) = match get(data, 12..16)? {
+        b"VP8 " => {
+            if get(data, 23..26)? != [0x9d, 0x01, 0x2a] {
+                return Err(Header::Malformed);
+            }
+            let width = u16_le(get(data, 26..28)?) & 0x3fff;
+            let height = u16_le(get(data, 28..30)?) & 0x3fff;
+            (width as u32, height as u32, Some(1))
+        }
+        b"VP8L" => {
+            if get(data, 20..21)? != [0x2f] {
+                return Err(Header::Malformed);
+            }
+            let bits = u32::from_le_bytes(get(data, 21..25)?.try_into().unwrap());
+            ((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1, Some(1))
+        }
+        b"VP8X" => {
+            let is_animated = get(data, 20..21)?[0] & 0x02 != 0;
+            let width = u24_le(get(data, 24..27)?) + 1;
+            let height = u24_le(get(data, 27..30)?) + 1;
+            (width, height, (!is_animated).then_some(1))
+        }
+        _ => return Err(Header::Malformed),
+    };
+
+    Ok(ImageInfo {
+        width,
+        height,
+        frames,
+    })
+}
+
+/// Reads the dimensions from the `ispe` properties in the `meta` box. If
+/// there are multiple, like for thumbnails, the largest are used.
+fn parse_avif(data: &[u8]) -> Result<ImageInfo, Header> {
+    // The `ftyp` box comes first, and contains the major brand, a version,
+    // and the compatible brands.
+    let ftyp_end = u32_be(get(data, 0..4)?) as usize;
+    if ftyp_end < 16 {
+        return Err(Header::Unknown);
+    }
+    let brands = get(data, 8..ftyp_end)?;
+    let is_brand = |brand: &[u8]| {
+        brands[..4] == *brand
+            || brands[8..]
+                .chunks_exact(4)
+                .any(|compatible| compatible == brand)
+    };
+    let is_sequence = is_brand(b"avis");
+    if !is_sequence && !is_brand(b"avif") {
+        return Err(Header::Unknown);
+    }
+
+    // The contents of `meta` start with a version and flags.
+    let meta = find_box(data, ftyp_end..usize::MAX, b"meta")?.ok_or(Header::Malformed)?;
+    get(data, meta.clone())?;
+    let iprp = find_box(data, meta.start + 4..meta.end, b"iprp")?.ok_or(Header::Malformed)?;
+    let ipco = find_box(data, iprp, b"ipco")?.ok_or(Header::Malformed)?;
+
+    let mut size = None;
+    let mut pos = ipco.start;
+    while let Some((kind, contents)) = next_box(data, pos, ipco.end)? {
+        pos = contents.end;
+        if kind == b"ispe" {
+            let width = u32_be(get(data, contents.start + 4..contents.start + 8)?);
+            let height = u32_be(get(data, contents.start + 8..contents.start + 12)?);
+            let (max_width, max_height) = size.unwrap_or((0, 0));
+            size = Some((width.max(max_width), height.max(max_height)));
+        }
+    }
+
+    let (width, height) = size.ok_or(Header::Malformed)?;
+    Ok(ImageInfo {
+        width,
+        height,
+        frames: (!is_sequence).then_some(1),
+    })
+}
+
+/// Finds the first box of a type among the boxes in `range`, and returns the
+/// range of its contents. Top-level boxes are searched with an `end` of
+/// `usize::MAX`.
+fn find_box(
+    data: &[u8],
+    range: Range<usize>,
+    kind: &[u8; 4],
+) -> Result<Option<Range<usize>>, Header> {
+    let mut pos = range.start;
+    while let Some((found, contents)) = next_box(data, pos, range.end)? {
+        if found == kind {
+            return Ok(Some(contents));
+        }
+        pos = contents.end;
+    }
+
+    Ok(None)
+}
+
+/// Reads the header of the box at `pos`, and returns its type and the range
+/// of its contents. Returns `None` at the end of the parent box.
+fn next_box(data: &[u8], pos: usize, end: usize) -> Result<Option<IsoBox<'_>>, Header> {
+    if pos >= end {
+        return Ok(None);
+    }
+
+    let size = u32_be(get(data, pos..pos + 4)?) as usize;
+    let kind = get(data, pos + 4..pos + 8)?;
+    let (header, size) = match size {
+        1 => {
+            let size = u64::from_be_bytes(get(data, pos + 8..pos + 16)?.try_into().unwrap());
+            (16, usize::try_from(size).map_err(|_| Header::Malformed)?)
+        }
+        // The box extends to the end of the file.
+        0 => (8, end.saturating_sub(pos)),
+        size => (8, size),
+    };
+    if size < header {
+        return Err(Header::Malformed);
+    }
+
+    let box_end = pos.checked_add(size).ok_or(Header::Malformed)?;
+    if box_end > end {
+        return Err(Header::Malformed);
+    }
+    Ok(Some((kind, pos + header..box_end)))
+}
+
+/// Returns the bytes in `range`, or how many bytes are needed for them.
+fn get(data: &[u8], range: Range<usize>) -> Result<&[u8], Header> {
+    let end = range.end;
+    data.get(range).ok_or(Header::Incomplete(end))
+}
+
+fn u16_be(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+fn u16_le(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn u24_le(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0])
+}
+
+fn u32_be(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Limits for the dimensions and frame counts of images. 0 means unlimited.
+#[derive(Clone, Debug)]
+pub struct ImageLimits {
+    max_width: u32,
+    max_height: u32,
+    max_pixels: u64,
+    max_frames: u32,
+}
+
+impl ImageLimits {
+    /// Creates the limits from the settings. Returns `None` if none are set.
+    pub fn new(settings: &Settings) -> Option<Self> {
+        let limits = Self {
+            max_width: settings.image_max_width,
+            max_height: settings.image_max_height,
+            max_pixels: settings.image_max_pixels,
+            max_frames: settings.image_max_frames,
+        };
+
+        (limits.max_width > 0
+            || limits.max_height > 0
+            || limits.max_pixels > 0
+            || limits.max_frames > 0)
+            .then_some(limits)
+    }
+
+    /// Returns the maximum number of frames, or 0 if it's unlimited.
+    pub fn max_frames(&self) -> u32 {
+        self.max_frames
+    }
+
+    /// Rejects images that exceed any of the limits.
+    pub fn check(&self, info: &ImageInfo) -> Result<(), CamoError> {
+        let exceeds = |value: u64, limit: u64| limit > 0 && value > limit;
+
+        if exceeds(info.width as u64, self.max_width as u64)
+            || exceeds(info.height as u64, self.max_height as u64)
+            || exceeds(info.pixels(), self.max_pixels)
+        {
+            return Err(CamoError::ImageTooLarge(format!(
+                "{}x{} pixels",
+                info.width, info.height
+            )));
+        }
+
+        if let Some(frames) = info.frames
+            && exceeds(frames as u64, self.max_frames as u64)
+        {
+            return Err(CamoError::ImageTooLarge(format!("{frames} frames")));
+        }
+
+        Ok(())
+    }
+}
+
+/// Counts the frames of a complete image. For GIFs and animated WebPs, the
+/// frames are counted without decoding them. For other formats, this is what
+/// the header declares.
+pub fn count_frames(data: &[u8]) -> Option<u32> {
+    if let Some(mut counter) = FrameCounter::new(data) {
+        counter.feed(data);
+        return counter.finish();
+    }
+
+    match parse(data) {
+        Header::Parsed(info) => info.frames,
+        _ => None,
+    }
+}
+
+/// Counts the frames of a GIF or an animated WebP while it's streamed. These
+/// don't declare their number of frames in the header, so the blocks of a GIF
+/// are walked to count the image descriptors, and the `ANMF` chunks of a WebP
+/// are counted. Only the header of the current block is buffered, the image
+/// data is skipped.
+#[derive(Clone, Debug)]
+pub struct FrameCounter {
+    state: CounterState,
+    pending: Vec<u8>,
+    skip: usize,
+    frames: u32,
+}
+
+/// What a `FrameCounter` expects next.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CounterState {
+    /// The GIF header and logical screen descriptor.
+    GifHeader,
+    /// The introducer of the next block.
+    GifBlock,
+    /// The label of an extension.
+    GifLabel,
+    /// The rest of an image descriptor.
+    GifDescriptor,
+    /// The LZW minimum code size before the image data.
+    GifLzwCodeSize,
+    /// The size of the next data sub-block.
+    GifSubBlock,
+    /// The RIFF header.
+    WebpHeader,
+    /// The header of the next chunk.
+    WebpChunk,
+    /// The GIF trailer was found.
+    Done,
+    /// The data is malformed, so counting stopped.
+    Invalid,
+}
+
+impl CounterState {
+    /// The number of bytes needed to take the next step.
+    fn needed(self) -> usize {
+        match self {
+            Self::GifHeader => 13,
+            Self::GifDescriptor => 9,
+            Self::WebpHeader => 12,
+            Self::WebpChunk => 8,
+            Self::Done | Self::Invalid => 0,
+            _ => 1,
+        }
+    }
+}
+
+impl FrameCounter {
+    /// Creates a counter for the image that starts with `prefix`. Returns
+    /// `None` for formats other than GIF, and for WebPs that aren't animated.
+    pub fn new(prefix: &[u8]) -> Option<Self> {
+        let state = match prefix {
+            _ if prefix.starts_with(b"GIF87a") || prefix.starts_with(b"GIF89a") => {
+                CounterState::GifHeader
+            }
+            _ if prefix.starts_with(b"RIFF")
+                && matches!(parse(prefix), Header::Parsed(info) if info.frames.is_none()) =>
+            {
+                CounterState::WebpHeader
+            }
+            _ => return None,
+        };
+
+        Some(Self {
+            state,
+            pending: Vec::new(),
+            skip: 0,
+            frames: 0,
+        })
+    }
+
+    /// Feeds the next bytes of the image, and returns the number of frames
+    /// found so far.
+    pub fn feed(&mut self, mut data: &[u8]) -> u32 {
+        while !matches!(self.state, CounterState::Done | CounterState::Invalid) {
+            let skipped = self.skip.min(data.len());
+            self.skip -= skipped;
+            data = &data[skipped..];
+
+            let needed = self.state.needed() - self.pending.len();
+            let taken = needed.min(data.len());
+            self.pending.extend_from_slice(&data[..taken]);
+            data = &data[taken..];
+            if self.skip > 0 || taken < needed {
+                break;
+            }
+
+            let bytes = std::mem::take(&mut self.pending);
+            self.state = self.step(&bytes);
+        }
+
+        self.frames
+    }
+
+    /// Returns the number of frames if the image ended where expected, or
+    /// `None` if it's malformed or truncated.
+    pub fn finish(&self) -> Option<u32> {
+        let is_complete = match self.state {
+            CounterState::Done => true,
+            CounterState::WebpChunk => self.skip == 0 && self.pending.is_empty(),
+            _ => false,
+        };
+
+        is_complete.then_some(self.frames)
+    }
+
+    /// Handles the bytes the current state needed, and returns the next one.
+    fn step(&mut self, bytes: &[u8]) -> CounterState {
+        let color_table_size = |flags: u8| {
+            if flags & 0x80 != 0 {
+                3 << ((flags & 0x07) + 1)
+            } else {
+                0
+            }
+        };
+
+        match self.state {
+            CounterState::GifHeader => {
+                self.skip = color_table_size(bytes[10]);
+                CounterState::GifBlock
+            }
+            CounterState::GifBlock => match bytes[0] {
+                0x21 => CounterState::GifLabel,
+                0x2c => CounterState::GifDescriptor,
+                0x3b => CounterState::Done,
+                _ => CounterState::Invalid,
+            },
+            CounterState::GifLabel | CounterState::GifLzwCodeSize => CounterState::GifSubBlock,
+            CounterState::GifDescriptor => {
+                self.frames += 1;
+                self.skip = color_table_size(bytes[8]);
+                CounterState::GifLzwCodeSize
+            }
+            CounterState::GifSubBlock => match bytes[0] {
+                0 => CounterState::GifBlock,
+                size => {
+                    self.skip = size as usize;
+                    CounterState::GifSubBlock
+                }
+            },
+            CounterState::WebpHeader => CounterState::WebpChunk,
+            CounterState::WebpChunk => {
+                if &bytes[..4] == b"ANMF" {
+                    self.frames += 1;
+                }
+                let length = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+                self.skip = length + length % 2;
+                CounterState::WebpChunk
+            }
+            state @ (CounterState::Done | CounterState::Invalid) => state,
+        }
+    }
+}
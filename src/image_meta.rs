@@ -0,0 +1,87 @@
+//! Describes images for the meta endpoint, so front-ends can reserve space
+//! for an image before downloading it.
+
+use std::{collections::HashMap, io::Cursor};
+
+use image::{DynamicImage, ImageReader, Limits};
+use serde::Serialize;
+
+/// The maximum number of pixels of images that are decoded to find their
+/// dominant color, to render a preview, or to resize or transcode them.
+/// Larger images only get the details from their header, no preview, and are
+/// passed along unchanged.
+pub const MAX_DECODE_PIXELS: u64 = 16 * 1024 * 1024;
+
+/// The size of the thumbnail the dominant color is picked from.
+const THUMBNAIL_SIZE: u32 = 32;
+
+/// What the meta endpoint returns. Fields that aren't known are `null`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ImageMeta {
+    pub content_type: Option<String>,
+    pub byte_length: Option<u64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub frames: Option<u32>,
+    /// The most common color, as `#rrggbb`.
+    pub dominant_color: Option<String>,
+}
+
+/// Returns whether an image in this format can be decoded to find its
+/// dominant color.
+pub fn is_decodable(data: &[u8]) -> bool {
+    matches!(
+        image::guess_format(data),
+        Ok(image::ImageFormat::Gif
+            | image::ImageFormat::Jpeg
+            | image::ImageFormat::Png
+            | image::ImageFormat::WebP)
+    )
+}
+
+/// Returns decoder limits with a memory limit that fits `MAX_DECODE_PIXELS`.
+pub(crate) fn decode_limits() -> Limits {
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(MAX_DECODE_PIXELS * 8);
+    limits
+}
+
+/// Decodes an image, with a memory limit that fits `MAX_DECODE_PIXELS`.
+pub(crate) fn decode(data: &[u8]) -> Option<DynamicImage> {
+    let mut reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?;
+    reader.limits(decode_limits());
+    reader.decode().ok()
+}
+
+/// Finds the most common color of an image, ignoring transparent pixels. The
+/// image is shrunk to a thumbnail first, and colors are grouped with 4 bits
+/// per channel. Returns the average of the most common group.
+pub fn dominant_color(data: &[u8]) -> Option<String> {
+    let thumbnail = decode(data)?
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .to_rgba8();
+
+    let mut groups: HashMap<[u8; 3], (u32, [u32; 3])> = HashMap::new();
+    for pixel in thumbnail.pixels() {
+        let [r, g, b, a] = pixel.0;
+        if a < 128 {
+            continue;
+        }
+
+        let (count, sums) = groups.entry([r >> 4, g >> 4, b >> 4]).or_default();
+        *count += 1;
+        for (sum, value) in sums.iter_mut().zip([r, g, b]) {
+            *sum += value as u32;
+        }
+    }
+
+    // Ties are broken by the group, so the result doesn't depend on the
+    // iteration order.
+    let (_, (count, sums)) = groups
+        .into_iter()
+        .max_by_key(|(group, (count, _))| (*count, *group))?;
+    let [r, g, b] = sums.map(|sum| sum / count);
+    Some(format!("#{r:02x}{g:02x}{b:02x}"))
+}
@@ -0,0 +1,327 @@
+//! Resizes and re-encodes raster images, as requested by signed parameters in
+//! the query string of a Camo URL, like `?w=64&h=64&fit=cover&q=80`, and
+//! transcodes them to WebP for clients that accept it.
+
+use std::io::Cursor;
+
+use hyper::{HeaderMap, header};
+use image::{
+    AnimationDecoder, DynamicImage, ImageFormat, ImageReader,
+    codecs::{
+        gif::GifDecoder, jpeg::JpegEncoder, png::PngDecoder, webp::WebPDecoder, webp::WebPEncoder,
+    },
+    imageops::FilterType,
+};
+
+use crate::{
+    errors::{AuthParsingError, CamoError},
+    image_header::{self, Header},
+    image_meta,
+};
+
+/// The JPEG quality used if the parameters don't specify one.
+const DEFAULT_QUALITY: u8 = 80;
+
+/// Specifies how an image is fitted into the requested width and height.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Fit {
+    /// Scales the image to fit inside the box, keeping its aspect ratio.
+    #[default]
+    Contain,
+    /// Scales the image to cover the box, keeping its aspect ratio, and crops
+    /// whatever is outside of it.
+    Cover,
+    /// Stretches the image to the exact size of the box.
+    Fill,
+}
+
+impl Fit {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Contain => "contain",
+            Self::Cover => "cover",
+            Self::Fill => "fill",
+        }
+    }
+}
+
+/// The parameters for resizing an image. They are part of the signed data,
+/// so clients can't request sizes the application didn't ask for.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImageTransform {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+    pub quality: Option<u8>,
+}
+
+impl ImageTransform {
+    /// Parses the parameters from a query string. Unknown, duplicated, and
+    /// out-of-range parameters are rejected.
+    pub fn from_query(query: &str) -> Result<Self, AuthParsingError> {
+        let mut transform = Self::default();
+        let mut seen = Vec::new();
+
+        for pair in query.split('&') {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            if seen.contains(&name) {
+                return Err(invalid(&format!("{name} is set more than once")));
+            }
+            seen.push(name);
+
+            match name {
+                "w" => transform.width = Some(parse_dimension(name, value)?),
+                "h" => transform.height = Some(parse_dimension(name, value)?),
+                "fit" => {
+                    transform.fit = match value {
+                        "contain" => Fit::Contain,
+                        "cover" => Fit::Cover,
+                        "fill" => Fit::Fill,
+                        _ => return Err(invalid(&format!("fit {value} is unknown"))),
+                    }
+                }
+                "q" => {
+                    transform.quality = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|quality| (1..=100).contains(quality))
+                            .ok_or_else(|| invalid("q must be between 1 and 100"))?,
+                    )
+                }
+                _ => return Err(invalid(&format!("{name} is unknown"))),
+            }
+        }
+
+        Ok(transform)
+    }
+
+    /// Returns the parameters as a query string, in a fixed order and without
+    /// default values. This is the form that gets signed.
+    pub fn to_query(&self) -> String {
+        let mut pairs = Vec::new();
+        if let Some(width) = self.width {
+            pairs.push(format!("w={width}"));
+        }
+        if let Some(height) = self.height {
+            pairs.push(format!("h={height}"));
+        }
+        if self.fit != Fit::default() {
+            pairs.push(format!("fit={}", self.fit.as_str()));
+        }
+        if let Some(quality) = self.quality {
+            pairs.push(format!("q={quality}"));
+        }
+
+        pairs.join("&")
+    }
+
+    /// Resizes and re-encodes an image. JPEGs, PNGs, and WebPs keep their
+    /// format, GIFs are turned into PNGs. Returns `None` for other formats,
+    /// for images that are too large to decode, and for animated images,
+    /// which are all passed along unchanged. Images are never enlarged.
+    pub fn apply(&self, data: &[u8]) -> Result<Option<(Vec<u8>, &'static str)>, CamoError> {
+        let Some((image, format)) = decode(data)? else {
+            return Ok(None);
+        };
+        let image = self.resize(image);
+
+        let mut output = Cursor::new(Vec::new());
+        let content_type = match format {
+            ImageFormat::Jpeg => {
+                let encoder = JpegEncoder::new_with_quality(
+                    &mut output,
+                    self.quality.unwrap_or(DEFAULT_QUALITY),
+                );
+                DynamicImage::ImageRgb8(image.to_rgb8())
+                    .write_with_encoder(encoder)
+                    .map_err(unprocessable)?;
+                "image/jpeg"
+            }
+            ImageFormat::WebP => {
+                encode_webp(&image, &mut output)?;
+                "image/webp"
+            }
+            _ => {
+                image
+                    .write_to(&mut output, ImageFormat::Png)
+                    .map_err(unprocessable)?;
+                "image/png"
+            }
+        };
+
+        Ok(Some((output.into_inner(), content_type)))
+    }
+
+    /// Resizes an image into the requested box. If only one dimension is
+    /// given, the other one follows the image's aspect ratio.
+    fn resize(&self, image: DynamicImage) -> DynamicImage {
+        let (width, height) = (image.width(), image.height());
+        let (box_width, box_height) = match (self.width, self.height) {
+            (Some(box_width), Some(box_height)) => (box_width, box_height),
+            (Some(box_width), None) => (box_width, scale(height, box_width, width)),
+            (None, Some(box_height)) => (scale(width, box_height, height), box_height),
+            (None, None) => return image,
+        };
+
+        if self.fit == Fit::Contain {
+            return image.resize(
+                box_width.min(width),
+                box_height.min(height),
+                FilterType::CatmullRom,
+            );
+        }
+
+        // The box is shrunk to fit into the image, keeping its aspect ratio,
+        // so nothing is enlarged.
+        let factor = (width as f64 / box_width as f64)
+            .min(height as f64 / box_height as f64)
+            .min(1.0);
+        let box_width = ((box_width as f64 * factor).round() as u32).max(1);
+        let box_height = ((box_height as f64 * factor).round() as u32).max(1);
+
+        match self.fit {
+            Fit::Cover => image.resize_to_fill(box_width, box_height, FilterType::CatmullRom),
+            _ => image.resize_exact(box_width, box_height, FilterType::CatmullRom),
+        }
+    }
+}
+
+/// Returns whether a content-type is one of the formats that can be resized.
+pub fn is_supported(content_type: &str) -> bool {
+    matches!(
+        crate::sniff::essence(content_type).as_str(),
+        "image/gif" | "image/jpeg" | "image/jpg" | "image/pjpeg" | "image/png" | "image/webp"
+    )
+}
+
+/// Returns whether a content-type is one of the formats that are transcoded
+/// to WebP. The WebP encoder is lossless, so lossy JPEGs would practically
+/// never get smaller, and WebPs are already WebPs.
+pub fn is_transcodable(content_type: &str) -> bool {
+    matches!(
+        crate::sniff::essence(content_type).as_str(),
+        "image/gif" | "image/png"
+    )
+}
+
+/// Returns whether the `Accept` header of a request explicitly lists WebP.
+/// Wildcards don't count, as clients send them for formats they can't
+/// display, too.
+pub fn accepts_webp(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|accept| accept.to_str().ok())
+        .flat_map(|accept| accept.split(','))
+        .any(|range| {
+            let mut params = range.split(';').map(str::trim);
+            params
+                .next()
+                .is_some_and(|mime| mime.eq_ignore_ascii_case("image/webp"))
+                && params.all(|param| {
+                    !param
+                        .strip_prefix("q=")
+                        .and_then(|quality| quality.parse::<f32>().ok())
+                        .is_some_and(|quality| quality <= 0.0)
+                })
+        })
+}
+
+/// Re-encodes a PNG or GIF as a lossless WebP. Returns `None` for other
+/// formats, and if the image can't be decoded, is animated, or wouldn't get
+/// smaller.
+pub fn transcode_to_webp(data: &[u8]) -> Option<Vec<u8>> {
+    if !matches!(
+        image::guess_format(data),
+        Ok(ImageFormat::Gif | ImageFormat::Png)
+    ) {
+        return None;
+    }
+
+    let (image, _) = decode(data).ok()??;
+
+    let mut output = Cursor::new(Vec::new());
+    encode_webp(&image, &mut output).ok()?;
+    let output = output.into_inner();
+    (output.len() < data.len()).then_some(output)
+}
+
+/// Decodes a JPEG, PNG, GIF, or WebP image. Returns `None` for other formats,
+/// for images with more than `MAX_DECODE_PIXELS` pixels, and for animated
+/// images.
+fn decode(data: &[u8]) -> Result<Option<(DynamicImage, ImageFormat)>, CamoError> {
+    let Ok(format) = image::guess_format(data) else {
+        return Ok(None);
+    };
+    if !matches!(
+        format,
+        ImageFormat::Gif | ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP
+    ) {
+        return Ok(None);
+    }
+
+    // The size is checked before anything is decoded, as small files can
+    // declare huge images.
+    match image_header::parse(data) {
+        Header::Parsed(info) if info.pixels() <= image_meta::MAX_DECODE_PIXELS => {}
+        _ => return Ok(None),
+    }
+    if is_animated(format, data)? {
+        return Ok(None);
+    }
+
+    let mut reader = ImageReader::with_format(Cursor::new(data), format);
+    reader.limits(image_meta::decode_limits());
+    let image = reader.decode().map_err(unprocessable)?;
+    Ok(Some((image, format)))
+}
+
+/// The WebP encoder only supports lossless compression, and needs 8-bit RGBA.
+fn encode_webp(image: &DynamicImage, output: &mut Cursor<Vec<u8>>) -> Result<(), CamoError> {
+    let encoder = WebPEncoder::new_lossless(output);
+    DynamicImage::ImageRgba8(image.to_rgba8())
+        .write_with_encoder(encoder)
+        .map_err(unprocessable)
+}
+
+/// Animated images would lose all frames but the first, so they are left
+/// alone.
+fn is_animated(format: ImageFormat, data: &[u8]) -> Result<bool, CamoError> {
+    let animated = match format {
+        ImageFormat::Gif => {
+            let decoder = GifDecoder::new(Cursor::new(data)).map_err(unprocessable)?;
+            decoder.into_frames().take(2).count() > 1
+        }
+        ImageFormat::Png => PngDecoder::new(Cursor::new(data))
+            .and_then(|decoder| decoder.is_apng())
+            .map_err(unprocessable)?,
+        ImageFormat::WebP => WebPDecoder::new(Cursor::new(data))
+            .map_err(unprocessable)?
+            .has_animation(),
+        _ => false,
+    };
+
+    Ok(animated)
+}
+
+/// Scales `value` by `numerator / denominator`, with a minimum of 1.
+fn scale(value: u32, numerator: u32, denominator: u32) -> u32 {
+    (value as u64 * numerator as u64 / denominator.max(1) as u64).clamp(1, u32::MAX as u64) as u32
+}
+
+fn parse_dimension(name: &str, value: &str) -> Result<u32, AuthParsingError> {
+    value
+        .parse()
+        .ok()
+        .filter(|dimension| *dimension > 0)
+        .ok_or_else(|| invalid(&format!("{name} must be a positive number")))
+}
+
+fn invalid(reason: &str) -> AuthParsingError {
+    AuthParsingError::TransformInvalid(reason.to_owned())
+}
+
+fn unprocessable(err: image::ImageError) -> CamoError {
+    CamoError::UpstreamBodyUnprocessable(err.to_string())
+}
@@ -1,9 +1,28 @@
 pub mod authenticated_target;
+pub mod body;
+pub mod coalesce;
+pub mod connector;
 pub mod errors;
 pub mod header_wrangler;
+pub mod hotlink;
+pub mod image_header;
+pub mod image_meta;
+pub mod image_transform;
+pub mod load_shed;
+pub mod metadata;
+pub mod metrics;
+pub mod mime_policy;
+pub mod preview;
 pub mod proxy;
+pub mod rate_limit;
+pub mod resolver;
+pub mod retry;
 pub mod server;
 pub mod settings;
+pub mod sniff;
+pub mod svg;
+pub mod tls;
+pub mod upstream_health;
 
 pub use authenticated_target::AuthenticatedTarget;
 pub use proxy::Proxy;
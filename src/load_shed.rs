@@ -0,0 +1,156 @@
+//! Limits the number of proxy requests handled at the same time. Requests
+//! beyond that limit wait in a bounded queue, and are rejected once the queue
+//! is full, so camo-rs pushes back before it runs out of memory or file
+//! descriptors.
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    Settings,
+    body::{BodyObserver, ObservedBody},
+    errors::CamoError,
+    header_wrangler::HeaderPolicy,
+    metrics::Metrics,
+};
+
+const IN_FLIGHT_METRIC: &str = "camo_requests_in_flight";
+const IN_FLIGHT_HELP: &str = "Number of proxy requests currently being handled.";
+const QUEUED_METRIC: &str = "camo_requests_queued";
+const QUEUED_HELP: &str = "Number of proxy requests waiting for a free slot.";
+const SHED_METRIC: &str = "camo_requests_shed_total";
+const SHED_HELP: &str = "Number of proxy requests rejected because camo-rs was overloaded.";
+
+/// Hands out slots for in-flight requests.
+#[derive(Clone)]
+pub struct LoadShedder {
+    slots: Arc<Semaphore>,
+    in_flight: Arc<AtomicUsize>,
+    queued: Arc<AtomicUsize>,
+    queue_limit: usize,
+    queue_timeout: Duration,
+    header_policy: HeaderPolicy,
+    metrics: Metrics,
+}
+
+/// Holds a slot until the response body is done.
+struct InFlightGuard {
+    shedder: LoadShedder,
+    _permit: OwnedSemaphorePermit,
+}
+
+/// Holds a spot in the queue while waiting for a slot.
+struct QueueGuard<'a> {
+    shedder: &'a LoadShedder,
+}
+
+impl LoadShedder {
+    /// Creates a new instance based on the settings. The in-flight limit has
+    /// to be larger than `0`. The `header_policy` provides the security
+    /// headers for rejected requests.
+    pub fn new(settings: &Settings, header_policy: HeaderPolicy, metrics: Metrics) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(settings.in_flight_limit)),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            queued: Arc::new(AtomicUsize::new(0)),
+            queue_limit: settings.queue_limit,
+            queue_timeout: Duration::from_secs(settings.queue_timeout as u64),
+            header_policy,
+            metrics,
+        }
+    }
+
+    /// Waits for a free slot, if there is space in the queue. Fails with
+    /// `CamoError::Overloaded` if the queue is full, or if no slot became free
+    /// before the queue timeout.
+    async fn acquire(&self) -> Result<InFlightGuard, CamoError> {
+        let permit = match self.slots.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => self.wait_for_slot().await.inspect_err(|_| {
+                self.metrics.increment(SHED_METRIC, SHED_HELP, &[]);
+            })?,
+        };
+
+        let in_flight = self.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+        self.metrics
+            .set(IN_FLIGHT_METRIC, IN_FLIGHT_HELP, &[], in_flight as i64);
+
+        Ok(InFlightGuard {
+            shedder: self.clone(),
+            _permit: permit,
+        })
+    }
+
+    async fn wait_for_slot(&self) -> Result<OwnedSemaphorePermit, CamoError> {
+        let queued = self
+            .queued
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+                (queued < self.queue_limit).then_some(queued + 1)
+            })
+            .map_err(|_| CamoError::Overloaded)?;
+        self.metrics
+            .set(QUEUED_METRIC, QUEUED_HELP, &[], (queued + 1) as i64);
+        let _queue_guard = QueueGuard { shedder: self };
+
+        tokio::time::timeout(self.queue_timeout, self.slots.clone().acquire_owned())
+            .await
+            .map_err(|_| CamoError::Overloaded)
+            .map(|permit| permit.expect("semaphore is never closed"))
+    }
+}
+
+impl BodyObserver for InFlightGuard {}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let in_flight = self.shedder.in_flight.fetch_sub(1, Ordering::Relaxed) - 1;
+        self.shedder
+            .metrics
+            .set(IN_FLIGHT_METRIC, IN_FLIGHT_HELP, &[], in_flight as i64);
+    }
+}
+
+impl Drop for QueueGuard<'_> {
+    fn drop(&mut self) {
+        let queued = self.shedder.queued.fetch_sub(1, Ordering::Relaxed) - 1;
+        self.shedder
+            .metrics
+            .set(QUEUED_METRIC, QUEUED_HELP, &[], queued as i64);
+    }
+}
+
+/// Middleware that only lets requests through if there is a free slot. The
+/// slot is held until the response body is fully sent.
+pub async fn limit_in_flight(
+    State(shedder): State<LoadShedder>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let guard = match shedder.acquire().await {
+        Ok(guard) => guard,
+        Err(err) => {
+            let mut res = err.into_response();
+            shedder
+                .header_policy
+                .force_secure_response_headers(res.headers_mut());
+            return res;
+        }
+    };
+
+    next.run(req)
+        .await
+        .map(|body| Body::new(ObservedBody::new(body, guard)))
+}
@@ -0,0 +1,228 @@
+//! Removes metadata like EXIF, XMP, and IPTC from images, without touching
+//! the pixel data. Photos often contain GPS coordinates and other details the
+//! people posting them don't know about.
+
+use crate::errors::CamoError;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// PNG chunks that contain metadata. XMP is stored in `iTXt` chunks.
+const PNG_METADATA_CHUNKS: &[&[u8; 4]] = &[b"eXIf", b"iTXt", b"tEXt", b"zTXt", b"tIME"];
+
+/// WebP chunks that contain metadata.
+const WEBP_METADATA_CHUNKS: &[&[u8; 4]] = &[b"EXIF", b"XMP "];
+
+/// The flags in the `VP8X` chunk that announce EXIF and XMP chunks.
+const WEBP_METADATA_FLAGS: u8 = 0x08 | 0x04;
+
+/// The EXIF tag for the orientation of the image.
+const EXIF_ORIENTATION_TAG: u16 = 0x0112;
+
+/// JPEG markers without a length.
+const JPEG_STANDALONE_MARKERS: std::ops::RangeInclusive<u8> = 0xd0..=0xd9;
+
+/// The image formats metadata can be removed from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    WebP,
+}
+
+impl ImageFormat {
+    /// Detects the format by the image's first bytes.
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        match data {
+            [0xff, 0xd8, 0xff, ..] => Some(Self::Jpeg),
+            _ if data.starts_with(PNG_SIGNATURE) => Some(Self::Png),
+            _ if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") => Some(Self::WebP),
+            _ => None,
+        }
+    }
+
+    /// Returns the format for a content-type, if metadata can be removed from
+    /// that type.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        match crate::sniff::essence(content_type).as_str() {
+            "image/jpeg" | "image/jpg" | "image/pjpeg" => Some(Self::Jpeg),
+            "image/png" | "image/apng" => Some(Self::Png),
+            "image/webp" => Some(Self::WebP),
+            _ => None,
+        }
+    }
+}
+
+/// Removes metadata from an image. The format is detected from the data, and
+/// data in other formats is returned unchanged. Fails if the image is
+/// malformed, as it's not possible to tell what it contains then.
+pub fn strip(data: &[u8]) -> Result<Vec<u8>, CamoError> {
+    match ImageFormat::detect(data) {
+        Some(ImageFormat::Jpeg) => strip_jpeg(data),
+        Some(ImageFormat::Png) => strip_png(data),
+        Some(ImageFormat::WebP) => strip_webp(data),
+        None => Some(data.to_vec()),
+    }
+    .ok_or_else(|| CamoError::UpstreamBodyUnprocessable("image is malformed".to_owned()))
+}
+
+/// Removes the `APP1` segments, which contain EXIF and XMP, the `APP13`
+/// segments, which contain IPTC, and comments. Everything from the start of
+/// the scan is copied unchanged. If the EXIF data rotates or flips the image,
+/// it's replaced with a segment that only contains the orientation.
+fn strip_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(data.len());
+    output.extend_from_slice(&data[..2]);
+
+    let mut pos = 2;
+    loop {
+        // Markers can be padded with any number of 0xff bytes.
+        while data.get(pos + 1) == Some(&0xff) {
+            pos += 1;
+        }
+        let [0xff, marker] = *data.get(pos..pos + 2)? else {
+            return None;
+        };
+
+        if JPEG_STANDALONE_MARKERS.contains(&marker) || marker == 0x01 {
+            output.extend_from_slice(&data[pos..pos + 2]);
+            pos += 2;
+            if marker == 0xd9 {
+                return Some(output);
+            }
+            continue;
+        }
+
+        let length = u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]) as usize;
+        let end = pos + 2 + length;
+        let segment = data.get(pos..end)?;
+
+        // Start of scan: the rest is entropy-coded data.
+        if marker == 0xda {
+            output.extend_from_slice(&data[pos..]);
+            return Some(output);
+        }
+
+        if !matches!(marker, 0xe1 | 0xed | 0xfe) {
+            output.extend_from_slice(segment);
+        } else if marker == 0xe1
+            && let Some(orientation) = segment.get(4..).and_then(exif_orientation)
+        {
+            output.extend_from_slice(&jpeg_orientation_segment(orientation));
+        }
+        pos = end;
+    }
+}
+
+/// Reads the orientation from the contents of an EXIF `APP1` segment. It's
+/// stored in the first IFD of the TIFF structure after the `Exif` header.
+/// Returns `None` for the default orientation, or if there is none.
+fn exif_orientation(exif: &[u8]) -> Option<u16> {
+    let tiff = exif.strip_prefix(b"Exif\0\0")?;
+    let is_little_endian = match tiff.get(0..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let u16_at = |pos: usize| {
+        let bytes = tiff.get(pos..pos.checked_add(2)?)?.try_into().ok()?;
+        Some(match is_little_endian {
+            true => u16::from_le_bytes(bytes),
+            false => u16::from_be_bytes(bytes),
+        })
+    };
+    let u32_at = |pos: usize| {
+        let bytes = tiff.get(pos..pos.checked_add(4)?)?.try_into().ok()?;
+        Some(match is_little_endian {
+            true => u32::from_le_bytes(bytes),
+            false => u32::from_be_bytes(bytes),
+        })
+    };
+
+    // Each entry has a tag, a type, a count, and the value, which is stored
+    // in place if it fits into four bytes.
+    let ifd = u32_at(4)? as usize;
+    let entries = u16_at(ifd)? as usize;
+    let orientation = (0..entries).find_map(|index| {
+        let entry = ifd.checked_add(2 + index * 12)?;
+        let is_orientation = u16_at(entry)? == EXIF_ORIENTATION_TAG && u16_at(entry + 2)? == 3;
+        is_orientation.then(|| u16_at(entry + 8)).flatten()
+    })?;
+
+    (2..=8).contains(&orientation).then_some(orientation)
+}
+
+/// Builds an EXIF `APP1` segment that only contains the orientation.
+fn jpeg_orientation_segment(orientation: u16) -> Vec<u8> {
+    [
+        &[0xff, 0xe1, 0, 34][..],
+        b"Exif\0\0",
+        // A big-endian TIFF header, with the first IFD right after it.
+        b"MM\0\x2a\0\0\0\x08",
+        // A single entry with one SHORT, and no next IFD.
+        b"\0\x01",
+        &EXIF_ORIENTATION_TAG.to_be_bytes(),
+        b"\0\x03\0\0\0\x01",
+        &orientation.to_be_bytes(),
+        b"\0\0\0\0\0\0",
+    ]
+    .concat()
+}
+
+/// Removes the `eXIf` chunk, text chunks, which contain XMP and other
+/// metadata, and the modification time. Anything after `IEND` is dropped.
+fn strip_png(data: &[u8]) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(data.len());
+    output.extend_from_slice(PNG_SIGNATURE);
+
+    let mut pos = PNG_SIGNATURE.len();
+    loop {
+        let length = u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let chunk_type: &[u8; 4] = data.get(pos + 4..pos + 8)?.try_into().ok()?;
+        // Length, type, data, and CRC.
+        let end = pos.checked_add(12)?.checked_add(length)?;
+        let chunk = data.get(pos..end)?;
+
+        if !PNG_METADATA_CHUNKS.contains(&chunk_type) {
+            output.extend_from_slice(chunk);
+        }
+        if chunk_type == b"IEND" {
+            return Some(output);
+        }
+        pos = end;
+    }
+}
+
+/// Removes the `EXIF` and `XMP ` chunks, and clears the flags announcing them
+/// in the `VP8X` chunk.
+fn strip_webp(data: &[u8]) -> Option<Vec<u8>> {
+    let riff_length = u32::from_le_bytes(data.get(4..8)?.try_into().ok()?) as usize;
+    let chunks = data.get(12..riff_length.checked_add(8)?)?;
+
+    let mut output = Vec::with_capacity(data.len());
+    output.extend_from_slice(b"RIFF\0\0\0\0WEBP");
+
+    let mut pos = 0;
+    while pos < chunks.len() {
+        let chunk_type: &[u8; 4] = chunks.get(pos..pos + 4)?.try_into().ok()?;
+        let length = u32::from_le_bytes(chunks.get(pos + 4..pos + 8)?.try_into().ok()?) as usize;
+        // Type, length, data, and padding to an even length.
+        let end = pos
+            .checked_add(8)?
+            .checked_add(length)?
+            .checked_add(length % 2)?;
+        let chunk = chunks.get(pos..end.min(chunks.len()))?;
+
+        if !WEBP_METADATA_CHUNKS.contains(&chunk_type) {
+            let start = output.len();
+            output.extend_from_slice(chunk);
+            if chunk_type == b"VP8X" {
+                *output.get_mut(start + 8)? &= !WEBP_METADATA_FLAGS;
+            }
+        }
+        pos = end;
+    }
+
+    let riff_length = u32::try_from(output.len() - 8).ok()?;
+    output[4..8].copy_from_slice(&riff_length.to_le_bytes());
+    Some(output)
+}
@@ -0,0 +1,101 @@
+//! A tiny metrics registry, rendered in the Prometheus text format on the
+//! `/__metrics__` endpoint.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+/// The kind of a metric, as announced in the `# TYPE` line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+/// All series of one metric name.
+struct MetricFamily {
+    kind: MetricKind,
+    help: &'static str,
+    series: BTreeMap<String, i64>,
+}
+
+/// Shared handle to the metrics registry. Cloning is cheap, and all clones
+/// write into the same registry.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    families: Arc<Mutex<BTreeMap<&'static str, MetricFamily>>>,
+}
+
+impl Metrics {
+    /// Increments a counter by one.
+    pub fn increment(&self, name: &'static str, help: &'static str, labels: &[(&str, &str)]) {
+        self.update(name, help, MetricKind::Counter, labels, |value| *value += 1);
+    }
+
+    /// Sets a gauge to the given value.
+    pub fn set(&self, name: &'static str, help: &'static str, labels: &[(&str, &str)], to: i64) {
+        self.update(name, help, MetricKind::Gauge, labels, |value| *value = to);
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let families = self.families.lock().expect("lock is never poisoned");
+        let mut out = String::new();
+
+        for (name, family) in families.iter() {
+            let kind = match family.kind {
+                MetricKind::Counter => "counter",
+                MetricKind::Gauge => "gauge",
+            };
+            let _ = writeln!(out, "# HELP {name} {}", family.help);
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            for (labels, value) in &family.series {
+                let _ = writeln!(out, "{name}{labels} {value}");
+            }
+        }
+
+        out
+    }
+
+    fn update(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        kind: MetricKind,
+        labels: &[(&str, &str)],
+        f: impl FnOnce(&mut i64),
+    ) {
+        let mut families = self.families.lock().expect("lock is never poisoned");
+        let family = families.entry(name).or_insert_with(|| MetricFamily {
+            kind,
+            help,
+            series: BTreeMap::new(),
+        });
+        debug_assert_eq!(family.kind, kind, "metric {name} used with different kinds");
+
+        f(family.series.entry(render_labels(labels)).or_default());
+    }
+}
+
+/// Renders a label set, like `{host="example.com"}`. Returns an empty string
+/// if there are no labels.
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{key}=\"{value}\"")
+        })
+        .collect();
+
+    format!("{{{}}}", labels.join(","))
+}
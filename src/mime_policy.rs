@@ -0,0 +1,102 @@
+//! Decides which content-types camo-rs passes through, based on lists of
+//! allowed and denied MIME type patterns.
+
+use crate::{
+    Settings,
+    errors::CamoError,
+    settings::{MimePattern, SvgHandling},
+};
+
+/// Checks content-types against the allowed and denied patterns. The most
+/// specific matching pattern decides, and if an allowed and a denied pattern
+/// are equally specific, the content-type is denied.
+#[derive(Clone, Debug)]
+pub struct MimePolicy {
+    allowed: Vec<MimePattern>,
+    denied: Vec<MimePattern>,
+    allow_missing: bool,
+}
+
+impl MimePolicy {
+    /// Creates a new policy based on the settings. The `--allow-audio`,
+    /// `--allow-image`, and `--allow-video` flags add the `audio/*`, `image/*`,
+    /// and `video/*` patterns, and `--allow-all-types` adds `*/*`, and also
+    /// allows responses without a content-type. Blocking SVGs denies
+    /// `image/svg+xml`.
+    pub fn new(settings: &Settings) -> Self {
+        let flags = [
+            (settings.allow_audio, "audio"),
+            (settings.allow_image, "image"),
+            (settings.allow_video, "video"),
+        ];
+        let mut allowed: Vec<MimePattern> = flags
+            .into_iter()
+            .filter(|(enabled, _)| *enabled)
+            .map(|(_, type_)| MimePattern {
+                type_: Some(type_.to_owned()),
+                subtype: None,
+            })
+            .collect();
+        if settings.allow_all_types {
+            allowed.push(MimePattern {
+                type_: None,
+                subtype: None,
+            });
+        }
+        allowed.extend(settings.allow_types.iter().cloned());
+
+        let mut denied = settings.deny_types.clone();
+        if settings.svg_handling == SvgHandling::Block {
+            denied.push(MimePattern {
+                type_: Some("image".to_owned()),
+                subtype: Some("svg+xml".to_owned()),
+            });
+        }
+
+        Self {
+            allowed,
+            denied,
+            allow_missing: settings.allow_all_types,
+        }
+    }
+
+    /// Returns whether any content-type can pass the policy at all.
+    pub fn allows_anything(&self) -> bool {
+        self.allow_missing || !self.allowed.is_empty()
+    }
+
+    /// Checks the value of a `Content-Type` header. Parameters and the case of
+    /// the type are ignored.
+    pub fn check(&self, content_type: Option<&str>) -> Result<(), CamoError> {
+        let Some(content_type) = content_type else {
+            return match self.allow_missing {
+                true => Ok(()),
+                false => Err(CamoError::MissingContentType),
+            };
+        };
+
+        let Ok(mime) = content_type.parse::<mime::Mime>() else {
+            return match self.allow_missing && self.denied.is_empty() {
+                true => Ok(()),
+                false => Err(CamoError::ContentTypeNotAccepted(content_type.to_owned())),
+            };
+        };
+
+        // The essence includes suffixes like `+xml`, which `subtype()` drops.
+        let essence = mime.essence_str().to_ascii_lowercase();
+        let (type_, subtype) = essence.split_once('/').unwrap_or((&essence, ""));
+        let best_match = |patterns: &[MimePattern]| {
+            patterns
+                .iter()
+                .filter(|pattern| pattern.matches(type_, subtype))
+                .map(MimePattern::specificity)
+                .max()
+        };
+
+        match (best_match(&self.allowed), best_match(&self.denied)) {
+            (Some(allowed), Some(denied)) if allowed > denied => Ok(()),
+            (Some(_), None) => Ok(()),
+            _ => Err(CamoError::ContentTypeNotAccepted(content_type.to_owned())),
+        }
+    }
+}
@@ -0,0 +1,43 @@
+//! Renders tiny, blurred previews of images, which can be shown while the
+//! image itself is still loading.
+
+use std::io::Cursor;
+
+use image::{Rgb, RgbImage, codecs::jpeg::JpegEncoder, imageops};
+
+use crate::image_meta;
+
+/// The maximum width and height of a preview.
+pub const PREVIEW_SIZE: u32 = 32;
+
+/// How much the preview is blurred.
+const BLUR_SIGMA: f32 = 1.5;
+
+/// The JPEG quality of previews. Details are blurred away anyway.
+const QUALITY: u8 = 60;
+
+/// Renders a preview of an image as a JPEG, keeping its aspect ratio.
+/// Transparent parts are put on a white background. Returns `None` if the
+/// image can't be decoded.
+pub fn render(data: &[u8]) -> Option<Vec<u8>> {
+    let image = image_meta::decode(data)?;
+    // `thumbnail` would enlarge images smaller than a preview.
+    let thumbnail = if image.width() > PREVIEW_SIZE || image.height() > PREVIEW_SIZE {
+        image.thumbnail(PREVIEW_SIZE, PREVIEW_SIZE).to_rgba8()
+    } else {
+        image.to_rgba8()
+    };
+
+    let flattened = RgbImage::from_fn(thumbnail.width(), thumbnail.height(), |x, y| {
+        let [r, g, b, a] = thumbnail.get_pixel(x, y).0;
+        let blend = |value: u8| ((value as u32 * a as u32 + 255 * (255 - a as u32)) / 255) as u8;
+        Rgb([blend(r), blend(g), blend(b)])
+    });
+    let blurred = imageops::blur(&flattened, BLUR_SIGMA);
+
+    let mut output = Cursor::new(Vec::new());
+    blurred
+        .write_with_encoder(JpegEncoder::new_with_quality(&mut output, QUALITY))
+        .ok()?;
+    Some(output.into_inner())
+}
@@ -1,54 +1,217 @@
 //! Hyper-based HTTP proxy to connect to the upstream
 
-use std::time::Duration;
+use std::{error::Error, net::IpAddr, time::Duration};
 
-use axum::{http::HeaderValue, response::IntoResponse};
+use axum::{body::Body, http::HeaderValue, response::IntoResponse};
 use http_body_util::Empty;
-use hyper::{HeaderMap, Method, Request, Response, body::Bytes, header};
-use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+use hyper::{
+    HeaderMap, Method, Request, Response,
+    body::{Bytes, Incoming},
+    header,
+};
+use hyper_rustls::HttpsConnector;
+use hyper_util::{
+    client::legacy::{Client, connect::HttpConnector},
+    rt::TokioExecutor,
+};
+use tokio::time::Instant;
+use tracing::warn;
 
-use crate::{errors::ProxyError, header_wrangler};
+use crate::{
+    Settings,
+    body::{MinRateBody, ObservedBody, TimedBody},
+    connector::{ConnectTimeoutElapsed, TimeoutConnector},
+    errors::{ProxyError, SetupError},
+    header_wrangler::HeaderPolicy,
+    metrics::Metrics,
+    resolver::FamilyResolver,
+    retry::RetryPolicy,
+    settings::IpFamily,
+    tls,
+    upstream_health::HostRegistry,
+};
+
+type HttpClient =
+    Client<TimeoutConnector<HttpsConnector<HttpConnector<FamilyResolver>>>, Empty<Bytes>>;
 
 /// The thing that actually does the requests to the upstream!
 #[derive(Clone)]
 pub struct Proxy {
+    http_client: HttpClient,
+    client_cert: Option<(Vec<String>, HttpClient)>,
+    ip_family: IpFamily,
+    header_policy: HeaderPolicy,
     via_header: String,
-    upstream_timeout: usize,
+    header_timeout: Duration,
+    idle_timeout: Duration,
+    transfer_timeout: Duration,
+    min_rate: u64,
+    min_rate_window: Duration,
+    retry_policy: RetryPolicy,
+    hosts: HostRegistry,
 }
 
 impl Proxy {
-    /// Creates a new Proxy instance with a set Via/User-Agent value and
-    /// a timeout.
+    /// Creates a new Proxy instance based on the provided `Settings`.
     ///
     /// This will internally also create the hyper HttpsConnector and hyper
-    /// Client, which will be used throughout the life of this Proxy.
-    pub fn new(via_header: &str, upstream_timeout: usize) -> Self {
-        Self {
-            via_header: via_header.to_owned(),
-            upstream_timeout,
+    /// Client, which will be used throughout the life of this Proxy. Circuit
+    /// breaker changes are reported to `metrics`. Fails if the TLS
+    /// configuration is unusable.
+    pub fn new(settings: &Settings, metrics: Metrics) -> Result<Self, SetupError> {
+        // Without a host list, the default Client presents the certificate to
+        // all upstreams, so no dedicated Client is needed.
+        let cert_hosts = &settings.upstream_client_cert_hosts;
+        let client_cert = if settings.upstream_client_cert.is_some() && !cert_hosts.is_empty() {
+            Some((cert_hosts.clone(), Self::build_client(settings, true)?))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            http_client: Self::build_client(settings, cert_hosts.is_empty())?,
+            client_cert,
+            ip_family: settings.upstream_ip_family,
+            header_policy: HeaderPolicy::new(settings),
+            via_header: settings.header_via.to_owned(),
+            header_timeout: Duration::from_secs(settings.upstream_timeout as u64),
+            idle_timeout: Duration::from_secs(settings.upstream_idle_timeout as u64),
+            transfer_timeout: Duration::from_secs(settings.upstream_transfer_timeout as u64),
+            min_rate: settings.upstream_min_rate,
+            min_rate_window: Duration::from_secs(settings.upstream_min_rate_window as u64),
+            retry_policy: RetryPolicy::new(settings),
+            hosts: HostRegistry::new(settings, metrics),
+        })
+    }
+
+    /// Builds a hyper Client, optionally with the client certificate.
+    fn build_client(settings: &Settings, with_client_cert: bool) -> Result<HttpClient, SetupError> {
+        let mut http =
+            HttpConnector::new_with_resolver(FamilyResolver::new(settings.upstream_ip_family));
+        http.enforce_http(false);
+        match (settings.upstream_bind_ipv4, settings.upstream_bind_ipv6) {
+            (Some(ipv4), Some(ipv6)) => http.set_local_addresses(ipv4, ipv6),
+            (Some(ipv4), None) => http.set_local_address(Some(IpAddr::V4(ipv4))),
+            (None, Some(ipv6)) => http.set_local_address(Some(IpAddr::V6(ipv6))),
+            (None, None) => {}
+        }
+
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_tls_config(tls::build_client_config(settings, with_client_cert)?)
+            .https_or_http()
+            .enable_http1()
+            .enable_http2()
+            .wrap_connector(http);
+        let connect_timeout = Duration::from_secs(settings.upstream_connect_timeout as u64);
+
+        Ok(Client::builder(TokioExecutor::new())
+            .build(TimeoutConnector::new(https, connect_timeout)))
+    }
+
+    /// Returns the Client to use for a given upstream host. Hosts that should
+    /// receive the client certificate get a dedicated Client.
+    fn client_for(&self, host: Option<&str>) -> &HttpClient {
+        match (&self.client_cert, host) {
+            (Some((hosts, client)), Some(host))
+                if hosts.iter().any(|h| h.eq_ignore_ascii_case(host)) =>
+            {
+                client
+            }
+            _ => &self.http_client,
         }
     }
 
     /// Runs a request to the upstream, and returns the Response to be used
     /// further down the app, for example for body streaming.
     ///
-    /// This function will make sure that request headers are filtered, and it
-    /// will ensure the response headers have the security header set.
+    /// This function will make sure that request and response headers are
+    /// filtered, and it will ensure the response headers have the security
+    /// header set. Failed requests are retried according to the `RetryPolicy`,
+    /// but only until the transfer timeout is used up. As this happens before
+    /// the response is returned, no body data has been sent to the client at
+    /// that point.
+    ///
+    /// Every attempt has to pass the upstream host's concurrency limit and
+    /// circuit breaker first. Errors and 5xx responses count as failures for
+    /// the circuit breaker, and so do errors while streaming the body.
     pub async fn run_request(
         &self,
         method: &Method,
         headers: &HeaderMap,
         target: &str,
-    ) -> Result<Response<axum::body::Body>, ProxyError> {
-        let https = hyper_rustls::HttpsConnectorBuilder::new()
-            .with_native_roots()
-            .expect("native roots to be there")
-            .https_or_http()
-            .enable_http1()
-            .enable_http2()
-            .build();
-        let http_client = Client::builder(TokioExecutor::new()).build::<_, Empty<Bytes>>(https);
+    ) -> Result<Response<Body>, ProxyError> {
+        let deadline = Instant::now() + self.transfer_timeout;
+
+        let mut attempt = 0;
+        let (mut res, lease) = loop {
+            let req = self.build_request(method, headers, target)?;
+            let host = req.uri().host().unwrap_or_default().to_ascii_lowercase();
+            let lease_deadline = deadline.min(Instant::now() + self.header_timeout);
+            let lease = self.hosts.acquire(&host, lease_deadline).await?;
 
+            let res = self.send_request(req, deadline).await;
+            match &res {
+                Ok(res) if !res.status().is_server_error() => lease.record_success(),
+                _ => lease.record_failure(),
+            }
+
+            let is_retryable = match &res {
+                Ok(res) => self.retry_policy.is_retryable_status(res.status()),
+                Err(err) => self.retry_policy.is_retryable_error(err),
+            };
+            if !is_retryable || !self.retry_policy.allows_retry(method, attempt) {
+                break (res?, lease);
+            }
+
+            let backoff = self.retry_policy.backoff(attempt);
+            if Instant::now() + backoff >= deadline {
+                break (res?, lease);
+            }
+
+            match &res {
+                Ok(res) => warn!("retrying upstream request after status {}", res.status()),
+                Err(err) => warn!("retrying upstream request after {:?}", err),
+            }
+            // Release the failed response's connection and the concurrency
+            // slot before waiting.
+            drop(res);
+            drop(lease);
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        };
+
+        self.header_policy
+            .filter_response_headers(res.headers_mut());
+        self.header_policy
+            .force_secure_response_headers(res.headers_mut());
+        res.headers_mut().append(
+            "x-camo-original-url",
+            HeaderValue::from_str(target).expect("target is always a valid URL at this point"),
+        );
+
+        Ok(res
+            .map(|body| {
+                let body = TimedBody::new(body, self.idle_timeout, deadline);
+                let body = if self.min_rate > 0 {
+                    Body::new(MinRateBody::new(body, self.min_rate, self.min_rate_window))
+                } else {
+                    Body::new(body)
+                };
+
+                // The lease keeps the concurrency slot until the body is done.
+                Body::new(ObservedBody::new(body, lease))
+            })
+            .into_response())
+    }
+
+    /// Builds the request to the upstream, with filtered request headers and
+    /// the static headers configured for the upstream host.
+    fn build_request(
+        &self,
+        method: &Method,
+        headers: &HeaderMap,
+        target: &str,
+    ) -> Result<Request<Empty<Bytes>>, ProxyError> {
         let mut req = Request::builder()
             .method(method)
             .uri(target)
@@ -57,23 +220,57 @@ impl Proxy {
             .body(Empty::new())
             .map_err(ProxyError::RequestBuildingFailed)?;
 
-        header_wrangler::assign_filtered_request_headers(headers, req.headers_mut());
+        // IP literals never hit the resolver, so the IP family restriction has
+        // to be checked here as well.
+        if let Some(host) = req.uri().host()
+            && let Ok(addr) = host.trim_matches(['[', ']']).parse::<IpAddr>()
+            && !self.ip_family.permits(&addr)
+        {
+            return Err(ProxyError::IpFamilyNotPermitted(addr));
+        }
+
+        self.header_policy
+            .assign_filtered_request_headers(headers, req.headers_mut());
+        if let Some(host) = req.uri().host().map(str::to_owned) {
+            self.header_policy
+                .assign_upstream_headers(&host, req.headers_mut());
+        }
 
-        let request_future = http_client.request(req);
-        let mut res = tokio::time::timeout(
-            Duration::from_secs(self.upstream_timeout as u64),
-            request_future,
-        )
-        .await
-        .map_err(ProxyError::UpstreamTimeout)?
-        .map_err(ProxyError::UpstreamError)?;
+        Ok(req)
+    }
 
-        header_wrangler::force_secure_response_headers(res.headers_mut());
-        res.headers_mut().append(
-            "x-camo-original-url",
-            HeaderValue::from_str(target).expect("target is always a valid URL at this point"),
-        );
+    /// Sends a single request to the upstream, and waits for the response
+    /// headers, but never past the `deadline`.
+    async fn send_request(
+        &self,
+        req: Request<Empty<Bytes>>,
+        deadline: Instant,
+    ) -> Result<Response<Incoming>, ProxyError> {
+        let header_deadline = deadline.min(Instant::now() + self.header_timeout);
+        let request_future = self.client_for(req.uri().host()).request(req);
+
+        tokio::time::timeout_at(header_deadline, request_future)
+            .await
+            .map_err(ProxyError::HeaderTimeout)?
+            .map_err(|err| {
+                if is_connect_timeout(&err) {
+                    ProxyError::ConnectTimeout
+                } else {
+                    ProxyError::UpstreamError(err)
+                }
+            })
+    }
+}
 
-        Ok(res.into_response())
+/// Checks whether a hyper client error was caused by the `TimeoutConnector`.
+fn is_connect_timeout(err: &hyper_util::client::legacy::Error) -> bool {
+    let mut source = err.source();
+    while let Some(err) = source {
+        if err.is::<ConnectTimeoutElapsed>() {
+            return true;
+        }
+        source = err.source();
     }
+
+    false
 }
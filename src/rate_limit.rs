@@ -0,0 +1,347 @@
+//! Per-client rate limiting, with token buckets for the number of requests
+//! and for the number of bytes sent to each client.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hyper::{HeaderMap, header};
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::{
+    Settings,
+    body::{BodyObserver, ObservedBody},
+    errors::CamoError,
+    header_wrangler::HeaderPolicy,
+    metrics::Metrics,
+};
+
+const RATE_LIMITED_METRIC: &str = "camo_requests_rate_limited_total";
+const RATE_LIMITED_HELP: &str =
+    "Number of proxy requests rejected because a client exceeded its rate limit.";
+
+/// The maximum number of tracked clients. Once reached, clients with full
+/// buckets are forgotten, and if that doesn't free up any space, new clients
+/// are rejected until it does.
+const MAX_CLIENTS: usize = 100_000;
+
+/// How often the tracked clients are searched for idle ones at most, so a
+/// flood of new clients doesn't cause a full search for every request.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A token bucket that refills continuously. The byte bucket can go into
+/// debt, as the size of a response is only known while it's streamed.
+#[derive(Clone, Copy, Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// The buckets of a single client.
+struct ClientBuckets {
+    requests: TokenBucket,
+    bytes: TokenBucket,
+}
+
+/// The buckets of all tracked clients.
+#[derive(Default)]
+struct Clients {
+    buckets: HashMap<IpAddr, ClientBuckets>,
+    last_sweep: Option<Instant>,
+}
+
+/// Keeps track of all clients.
+#[derive(Clone)]
+pub struct RateLimiter {
+    clients: Arc<Mutex<Clients>>,
+    requests_per_second: u64,
+    bytes_per_second: u64,
+    burst: u64,
+    trusted_proxy_hops: usize,
+    /// Whether a missing connection address was logged already.
+    warned_missing_peer: Arc<AtomicBool>,
+    header_policy: HeaderPolicy,
+    metrics: Metrics,
+}
+
+/// Counts the bytes sent to a client.
+struct ByteCounter {
+    limiter: RateLimiter,
+    client: IpAddr,
+}
+
+impl TokenBucket {
+    fn full(capacity: f64) -> Self {
+        Self {
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, rate: u64, capacity: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(capacity);
+        self.updated = now;
+    }
+
+    /// Returns the number of seconds until at least `tokens` tokens are in
+    /// the bucket, rounded up.
+    fn seconds_until(&self, tokens: f64, rate: u64) -> u64 {
+        ((tokens - self.tokens) / rate as f64).ceil().max(1.0) as u64
+    }
+}
+
+impl RateLimiter {
+    /// Creates a new limiter based on the settings. Returns `None` if neither
+    /// requests nor bytes are limited. The `header_policy` provides the
+    /// security headers for rejected requests.
+    pub fn new(settings: &Settings, header_policy: HeaderPolicy, metrics: Metrics) -> Option<Self> {
+        if settings.rate_limit_requests == 0 && settings.rate_limit_bytes == 0 {
+            return None;
+        }
+
+        Some(Self {
+            clients: Arc::new(Mutex::new(Clients::default())),
+            requests_per_second: settings.rate_limit_requests,
+            bytes_per_second: settings.rate_limit_bytes,
+            burst: settings.rate_limit_burst.max(1),
+            trusted_proxy_hops: settings.trusted_proxy_hops,
+            warned_missing_peer: Arc::new(AtomicBool::new(false)),
+            header_policy,
+            metrics,
+        })
+    }
+
+    fn request_capacity(&self) -> f64 {
+        (self.requests_per_second * self.burst) as f64
+    }
+
+    fn byte_capacity(&self) -> f64 {
+        (self.bytes_per_second * self.burst) as f64
+    }
+
+    /// Takes a request token from the client's bucket. Fails if there is no
+    /// token left, if the client received more bytes than allowed, or if too
+    /// many clients are tracked already.
+    fn check(&self, client: IpAddr) -> Result<(), CamoError> {
+        let mut clients = self.clients.lock().expect("lock is never poisoned");
+        if clients.buckets.len() >= MAX_CLIENTS && !clients.buckets.contains_key(&client) {
+            let is_sweep_due = clients
+                .last_sweep
+                .is_none_or(|last_sweep| last_sweep.elapsed() >= SWEEP_INTERVAL);
+            if is_sweep_due {
+                clients.last_sweep = Some(Instant::now());
+                clients.buckets.retain(|_, buckets| !self.is_idle(buckets));
+            }
+
+            if clients.buckets.len() >= MAX_CLIENTS {
+                return Err(CamoError::RateLimited(SWEEP_INTERVAL.as_secs()));
+            }
+        }
+
+        let buckets = clients
+            .buckets
+            .entry(client)
+            .or_insert_with(|| ClientBuckets {
+                requests: TokenBucket::full(self.request_capacity()),
+                bytes: TokenBucket::full(self.byte_capacity()),
+            });
+
+        if self.bytes_per_second > 0 {
+            buckets
+                .bytes
+                .refill(self.bytes_per_second, self.byte_capacity());
+            if buckets.bytes.tokens < 0.0 {
+                let retry_after = buckets.bytes.seconds_until(0.0, self.bytes_per_second);
+                return Err(CamoError::RateLimited(retry_after));
+            }
+        }
+
+        if self.requests_per_second > 0 {
+            buckets
+                .requests
+                .refill(self.requests_per_second, self.request_capacity());
+            if buckets.requests.tokens < 1.0 {
+                let retry_after = buckets
+                    .requests
+                    .seconds_until(1.0, self.requests_per_second);
+                return Err(CamoError::RateLimited(retry_after));
+            }
+            buckets.requests.tokens -= 1.0;
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether both buckets are full, so there is nothing worth
+    /// remembering about the client.
+    fn is_idle(&self, buckets: &ClientBuckets) -> bool {
+        let mut buckets = ClientBuckets {
+            requests: buckets.requests,
+            bytes: buckets.bytes,
+        };
+        buckets
+            .requests
+            .refill(self.requests_per_second, self.request_capacity());
+        buckets
+            .bytes
+            .refill(self.bytes_per_second, self.byte_capacity());
+
+        buckets.requests.tokens >= self.request_capacity()
+            && buckets.bytes.tokens >= self.byte_capacity()
+    }
+
+    /// Finds the client's IP address. Without trusted proxies, this is the
+    /// address of the connection. Otherwise, it's the address the outermost
+    /// trusted proxy has added to the `Forwarded` header, or, if that's not
+    /// there, to the `X-Forwarded-For` header.
+    pub fn client_ip(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> IpAddr {
+        let peer = peer.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        if self.trusted_proxy_hops == 0 {
+            return peer;
+        }
+
+        let forwarded_for = forwarded_for(headers);
+        let hops = if forwarded_for.is_empty() {
+            x_forwarded_for(headers)
+        } else {
+            forwarded_for
+        };
+
+        // Every trusted proxy appended the address it received the request
+        // from, so the client is the last hop the trusted proxies added.
+        // Anything before that is under the client's control.
+        let client = hops
+            .len()
+            .checked_sub(self.trusted_proxy_hops)
+            .and_then(|index| hops.get(index))
+            .or(hops.first());
+        client.and_then(|client| parse_ip(client)).unwrap_or(peer)
+    }
+}
+
+impl BodyObserver for ByteCounter {
+    fn on_data(&mut self, len: usize) {
+        let limiter = &self.limiter;
+        let mut clients = limiter.clients.lock().expect("lock is never poisoned");
+        if let Some(buckets) = clients.buckets.get_mut(&self.client) {
+            buckets
+                .bytes
+                .refill(limiter.bytes_per_second, limiter.byte_capacity());
+            buckets.bytes.tokens -= len as f64;
+        }
+    }
+}
+
+/// Returns the address the client's limits are tracked under. IPv6 clients
+/// usually get a whole /64 network, so they are tracked by that.
+pub fn client_key(client: IpAddr) -> IpAddr {
+    match client {
+        IpAddr::V4(_) => client,
+        IpAddr::V6(addr) => match addr.to_ipv4_mapped() {
+            Some(addr) => IpAddr::V4(addr),
+            None => IpAddr::V6(Ipv6Addr::from_bits(addr.to_bits() & u128::MAX << 64)),
+        },
+    }
+}
+
+/// Returns all `for=` values of the `Forwarded` headers, in order.
+fn forwarded_for(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(header::FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for")
+                    .then(|| value.trim_matches('"').to_owned())
+            })
+        })
+        .collect()
+}
+
+/// Returns all values of the `X-Forwarded-For` headers, in order.
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|hop| hop.trim().to_owned())
+        .collect()
+}
+
+/// Parses an IP address, with or without a port, and with or without brackets
+/// around IPv6 addresses.
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    value
+        .parse::<SocketAddr>()
+        .map(|addr| addr.ip())
+        .or_else(|_| value.trim_matches(['[', ']']).parse())
+        .ok()
+}
+
+/// Middleware that rejects requests from clients that exceeded their rate
+/// limit. If bytes are limited, the response body is counted against the
+/// client's limit while it's streamed.
+///
+/// The connection's address comes from axum's `ConnectInfo`. Without it, and
+/// without trusted proxies, all clients share a single limit, which is logged
+/// once.
+pub async fn limit_rate(State(limiter): State<RateLimiter>, req: Request, next: Next) -> Response {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    if peer.is_none()
+        && limiter.trusted_proxy_hops == 0
+        && !limiter.warned_missing_peer.swap(true, Ordering::Relaxed)
+    {
+        warn!(
+            "connection address is unknown, so all clients share one rate limit; serve the router with `into_make_service_with_connect_info::<SocketAddr>()`"
+        );
+    }
+    let client = client_key(limiter.client_ip(req.headers(), peer));
+
+    if let Err(err) = limiter.check(client) {
+        limiter
+            .metrics
+            .increment(RATE_LIMITED_METRIC, RATE_LIMITED_HELP, &[]);
+        let mut res = err.into_response();
+        limiter
+            .header_policy
+            .force_secure_response_headers(res.headers_mut());
+        return res;
+    }
+
+    let res = next.run(req).await;
+    if limiter.bytes_per_second == 0 {
+        return res;
+    }
+
+    res.map(|body| {
+        Body::new(ObservedBody::new(
+            body,
+            ByteCounter {
+                limiter: limiter.clone(),
+                client,
+            },
+        ))
+    })
+}
@@ -0,0 +1,77 @@
+//! DNS resolver used for upstream connections, which filters and sorts the
+//! resolved addresses according to the configured IP family.
+
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use hyper_util::client::legacy::connect::dns::{GaiResolver, Name};
+use tower_service::Service;
+
+use crate::settings::IpFamily;
+
+/// Wraps hyper's `GaiResolver`, but only returns addresses of the allowed IP
+/// families, in the preferred order.
+#[derive(Clone, Debug)]
+pub struct FamilyResolver {
+    inner: GaiResolver,
+    family: IpFamily,
+}
+
+impl FamilyResolver {
+    /// Creates a new resolver that only resolves to addresses permitted by
+    /// the given `IpFamily`.
+    pub fn new(family: IpFamily) -> Self {
+        Self {
+            inner: GaiResolver::new(),
+            family,
+        }
+    }
+
+    /// Filters and sorts a list of resolved addresses. Sorting is stable, so
+    /// the resolver's order is kept within each family.
+    fn apply_family(&self, addrs: impl Iterator<Item = SocketAddr>) -> Vec<SocketAddr> {
+        let mut addrs: Vec<SocketAddr> = addrs
+            .filter(|addr| self.family.permits(&addr.ip()))
+            .collect();
+
+        match self.family {
+            IpFamily::PreferIpv4 => addrs.sort_by_key(|addr| addr.is_ipv6()),
+            IpFamily::PreferIpv6 => addrs.sort_by_key(|addr| addr.is_ipv4()),
+            IpFamily::Any | IpFamily::Ipv4 | IpFamily::Ipv6 => {}
+        }
+
+        addrs
+    }
+}
+
+impl Service<Name> for FamilyResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolver = self.clone();
+        let lookup = self.inner.call(name.clone());
+
+        Box::pin(async move {
+            let addrs = resolver.apply_family(lookup.await?);
+            if addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("{name} has no address in the allowed IP families"),
+                ));
+            }
+
+            Ok(addrs.into_iter())
+        })
+    }
+}
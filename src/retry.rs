@@ -0,0 +1,95 @@
+//! Decides whether, and when, failed upstream requests are retried.
+
+use std::{error::Error, io, time::Duration};
+
+use hyper::{Method, StatusCode};
+
+use crate::{Settings, errors::ProxyError, settings::RetryOn};
+
+/// The retry policy for upstream requests, built from the `Settings`.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    retries: usize,
+    backoff: Duration,
+    max_backoff: Duration,
+    retry_on: Vec<RetryOn>,
+    statuses: Vec<u16>,
+}
+
+impl RetryPolicy {
+    /// Creates a new policy based on the retry-related settings.
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            retries: settings.upstream_retries,
+            backoff: Duration::from_millis(settings.upstream_retry_backoff),
+            max_backoff: Duration::from_millis(settings.upstream_retry_max_backoff),
+            retry_on: settings.upstream_retry_on.clone(),
+            statuses: settings.upstream_retry_statuses.clone(),
+        }
+    }
+
+    /// Returns whether a request with `method` can be retried after `attempt`
+    /// retries already happened. Only idempotent requests without a body
+    /// are ever retried.
+    pub fn allows_retry(&self, method: &Method, attempt: usize) -> bool {
+        (method == Method::GET || method == Method::HEAD) && attempt < self.retries
+    }
+
+    /// Returns whether an upstream response with this status is worth
+    /// retrying.
+    pub fn is_retryable_status(&self, status: StatusCode) -> bool {
+        self.statuses.contains(&status.as_u16())
+    }
+
+    /// Returns whether a failed request is worth retrying.
+    pub fn is_retryable_error(&self, err: &ProxyError) -> bool {
+        let class = match err {
+            ProxyError::ConnectTimeout | ProxyError::HeaderTimeout(_) => RetryOn::Timeout,
+            ProxyError::UpstreamError(err) if err.is_connect() => RetryOn::Connect,
+            ProxyError::UpstreamError(err) if is_connection_reset(err) => RetryOn::Reset,
+            _ => return false,
+        };
+
+        self.retry_on.contains(&class)
+    }
+
+    /// Returns the time to wait before the next retry. This is an exponential
+    /// backoff with full jitter, so concurrent requests don't retry in sync.
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let exponential = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(attempt as u32))
+            .min(self.max_backoff);
+
+        exponential.mul_f64(rand::random_range(0.0..=1.0))
+    }
+}
+
+/// Checks whether a hyper client error was caused by the upstream closing or
+/// resetting the connection.
+fn is_connection_reset(err: &hyper_util::client::legacy::Error) -> bool {
+    let mut source = err.source();
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<hyper::Error>()
+            && (err.is_incomplete_message() || err.is_closed() || err.is_canceled())
+        {
+            return true;
+        }
+
+        if let Some(err) = err.downcast_ref::<io::Error>()
+            && matches!(
+                err.kind(),
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof
+            )
+        {
+            return true;
+        }
+
+        source = err.source();
+    }
+
+    false
+}
@@ -4,59 +4,216 @@ use std::str::FromStr;
 
 use axum::{
     Router,
-    body::Body,
-    extract::{Path, State},
-    http::HeaderValue,
+    body::{Body, Bytes},
+    extract::{Path, RawQuery, State},
+    http::{HeaderValue, response::Parts},
+    middleware,
     response::{IntoResponse, Response},
     routing::get,
 };
 use hyper::{
-    HeaderMap, Method,
+    HeaderMap, Method, StatusCode,
     header::{self, HeaderName},
 };
 use tracing::{Span, instrument};
 
 use crate::{
-    AuthenticatedTarget, Proxy, Settings, errors::CamoError,
-    header_wrangler::resolve_location_header,
+    AuthenticatedTarget, Proxy, Settings,
+    body::{FrameLimitBody, read_prefix},
+    coalesce::Coalescer,
+    errors::{CamoError, SetupError},
+    header_wrangler::{HeaderPolicy, parse_content_range, resolve_location_header},
+    hotlink::HotlinkPolicy,
+    image_header::{self, FrameCounter, Header, ImageLimits},
+    image_meta::{self, ImageMeta},
+    image_transform::{self, ImageTransform},
+    load_shed::{self, LoadShedder},
+    metadata::{self, ImageFormat},
+    metrics::Metrics,
+    mime_policy::MimePolicy,
+    preview,
+    rate_limit::{self, RateLimiter},
+    settings::{ContentSniffing, SvgHandling},
+    sniff::{self, Verdict},
+    svg,
 };
 
+/// The endpoints that share the validation and fetching of the target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Endpoint {
+    /// Proxies the target.
+    Proxy,
+    /// Describes the image at the target as JSON.
+    Meta,
+    /// Renders a tiny, blurred preview of the image at the target.
+    Preview,
+}
+
+impl Endpoint {
+    /// Returns what's appended to the Camo URL for this endpoint.
+    fn path_suffix(self) -> &'static str {
+        match self {
+            Self::Proxy => "",
+            Self::Meta => "/meta",
+            Self::Preview => "/preview",
+        }
+    }
+}
+
+/// The `cache-control` header for previews, if the upstream didn't send one.
+const PREVIEW_CACHE_CONTROL: &str = "public, max-age=86400";
+
 #[derive(Clone)]
 pub struct AppState {
     settings: Settings,
     proxy: Proxy,
+    coalescer: Option<Coalescer>,
+    header_policy: HeaderPolicy,
+    hotlink_policy: Option<HotlinkPolicy>,
+    image_limits: Option<ImageLimits>,
+    mime_policy: MimePolicy,
+    metrics: Metrics,
 }
 
 /// Builds the router. This doesn't plug this into a server, so you need to
-/// do that yourself.
-pub fn build(settings: Settings) -> Router {
-    let proxy = Proxy::new(&settings.header_via, settings.upstream_timeout);
-    let state = AppState { settings, proxy };
-
-    Router::new()
-        .route(
-            "/{digest}/{target}",
-            get(proxy_handler)
-                .head(proxy_handler)
-                .options(proxy_handler),
-        )
+/// do that yourself. Fails if the settings can't be used to set up camo-rs.
+///
+/// Rate limiting needs the address of each connection, so serve the router
+/// with `into_make_service_with_connect_info::<SocketAddr>()`. Otherwise, all
+/// clients share a single limit, unless `trusted_proxy_hops` is set.
+pub fn build(settings: Settings) -> Result<Router, SetupError> {
+    let metrics = Metrics::default();
+    let proxy = Proxy::new(&settings, metrics.clone())?;
+    let header_policy = HeaderPolicy::new(&settings);
+    let hotlink_policy = HotlinkPolicy::new(&settings);
+    let image_limits = ImageLimits::new(&settings);
+    let mime_policy = MimePolicy::new(&settings);
+    let coalescer = settings
+        .coalesce_requests
+        .then(|| Coalescer::new(header_policy.clone()));
+
+    let mut router = Router::new().route(
+        "/{digest}/{target}",
+        get(proxy_handler)
+            .head(proxy_handler)
+            .options(options_handler),
+    );
+    if settings.meta_endpoint {
+        router = router.route("/{digest}/{target}/meta", get(meta_handler));
+    }
+    if settings.preview_endpoint {
+        router = router.route("/{digest}/{target}/preview", get(preview_handler));
+    }
+
+    // The limit only applies to the routes above, so the heartbeat and
+    // other internal endpoints keep answering while camo-rs is overloaded.
+    if settings.in_flight_limit > 0 {
+        let shedder = LoadShedder::new(&settings, header_policy.clone(), metrics.clone());
+        router = router.route_layer(middleware::from_fn_with_state(
+            shedder,
+            load_shed::limit_in_flight,
+        ));
+    }
+
+    // Added last, so it runs first, and rate limited clients don't take up
+    // slots or queue spots.
+    if let Some(limiter) = RateLimiter::new(&settings, header_policy.clone(), metrics.clone()) {
+        router = router.route_layer(middleware::from_fn_with_state(
+            limiter,
+            rate_limit::limit_rate,
+        ));
+    }
+
+    let state = AppState {
+        settings,
+        proxy,
+        coalescer,
+        header_policy,
+        hotlink_policy,
+        image_limits,
+        mime_policy,
+        metrics,
+    };
+
+    Ok(router
         .route("/__heartbeat__", get(heartbeat_handler))
+        .route("/__metrics__", get(metrics_handler))
         .route("/__version__", get(version_handler))
         .route("/robots.txt", get(robotstxt_handler))
         .fallback(fallback_handler)
-        .with_state(state)
+        .with_state(state))
 }
 
-/// The handler for all GET/HEAD/OPTION requests to a URL in the right format.
-/// This is a wrapper around `process_camo_request` to allow for reasonable
-/// HTTP responses depending on what goes wrong.
-#[instrument(level = "warn", skip_all, fields(req_digest, req_target, target_url))]
+/// The handler for all GET/HEAD requests to a URL in the right format.
 async fn proxy_handler(
     State(app_state): State<AppState>,
     Path((req_digest, req_target)): Path<(String, String)>,
+    RawQuery(req_query): RawQuery,
     req_method: Method,
     req_headers: HeaderMap,
 ) -> impl IntoResponse {
+    handle_camo_request(
+        app_state,
+        Endpoint::Proxy,
+        req_digest,
+        req_target,
+        req_query,
+        req_method,
+        req_headers,
+    )
+    .await
+}
+
+/// The handler for requests to the meta endpoint. The query string is
+/// ignored, as the details are always about the original image.
+async fn meta_handler(
+    State(app_state): State<AppState>,
+    Path((req_digest, req_target)): Path<(String, String)>,
+    req_headers: HeaderMap,
+) -> impl IntoResponse {
+    handle_camo_request(
+        app_state,
+        Endpoint::Meta,
+        req_digest,
+        req_target,
+        None,
+        Method::GET,
+        req_headers,
+    )
+    .await
+}
+
+/// The handler for requests to the preview endpoint. Like for the meta
+/// endpoint, the query string is ignored.
+async fn preview_handler(
+    State(app_state): State<AppState>,
+    Path((req_digest, req_target)): Path<(String, String)>,
+    req_headers: HeaderMap,
+) -> impl IntoResponse {
+    handle_camo_request(
+        app_state,
+        Endpoint::Preview,
+        req_digest,
+        req_target,
+        None,
+        Method::GET,
+        req_headers,
+    )
+    .await
+}
+
+/// This is a wrapper around `process_camo_request` to allow for reasonable
+/// HTTP responses depending on what goes wrong.
+#[instrument(level = "warn", skip_all, fields(req_digest, req_target, target_url))]
+async fn handle_camo_request(
+    app_state: AppState,
+    endpoint: Endpoint,
+    req_digest: String,
+    req_target: String,
+    req_query: Option<String>,
+    req_method: Method,
+    req_headers: HeaderMap,
+) -> Response<Body> {
     // [ToDo] I'm currently skipping all arguments and then manually re-adding
     // them, as otherwise, I get a double-qouted JSON output, so instead of
     // `"req_digest":"aaa"`, I get `"req_digest":"\"aaa\""` - which is rather
@@ -65,19 +222,66 @@ async fn proxy_handler(
     Span::current().record("req_digest", &req_digest);
     Span::current().record("req_target", &req_target);
 
-    let result =
-        process_camo_request(app_state, req_digest, req_target, req_method, req_headers).await;
+    let header_policy = app_state.header_policy.clone();
+    let origin = req_headers.get(header::ORIGIN).cloned();
+    let result = process_camo_request(
+        app_state,
+        endpoint,
+        req_digest,
+        req_target,
+        req_query,
+        req_method,
+        req_headers,
+    )
+    .await;
 
     // explicitly call into_reponse() here instead of returning the result to
     // allow the into_response() handler to run inside this tracing span, which
     // is important for the log output.
-    result.into_response()
+    let mut res = match result {
+        Ok(res) => res,
+        Err(err) => {
+            // Proxied responses already have the security headers, but error
+            // responses are built without access to the settings.
+            let mut res = err.into_response();
+            header_policy.force_secure_response_headers(res.headers_mut());
+            res
+        }
+    };
+
+    // CORS headers depend on the client's origin, so they can't be set in
+    // the proxy, where responses might be shared between clients.
+    header_policy.assign_cors_headers(origin.as_ref(), res.headers_mut());
+    res
+}
+
+/// Answers OPTIONS requests, including CORS preflight requests, without
+/// asking the upstream. Preflight requests never carry credentials, so
+/// there's nothing to learn from the upstream's answer.
+async fn options_handler(
+    State(app_state): State<AppState>,
+    req_headers: HeaderMap,
+) -> impl IntoResponse {
+    let mut res = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(header::ALLOW, "GET, HEAD, OPTIONS")
+        .body(Body::empty())
+        .expect("this is filled with static data only and should not fail");
+
+    let header_policy = &app_state.header_policy;
+    header_policy.force_secure_response_headers(res.headers_mut());
+    header_policy.assign_cors_preflight_headers(req_headers.get(header::ORIGIN), res.headers_mut());
+    res
 }
 
 async fn heartbeat_handler() -> impl IntoResponse {
     get_response_with_status_and_text(200, "ok")
 }
 
+async fn metrics_handler(State(app_state): State<AppState>) -> impl IntoResponse {
+    get_response_with_status_and_text(200, &app_state.metrics.render())
+}
+
 async fn version_handler() -> impl IntoResponse {
     get_response_with_status_and_text(200, env!("CAMO_RS_VERSION"))
 }
@@ -94,31 +298,167 @@ async fn fallback_handler() -> impl IntoResponse {
 /// directly inside the header to allow to return a CamoError early.
 async fn process_camo_request(
     app_state: AppState,
+    endpoint: Endpoint,
     req_digest: String,
     req_target: String,
+    req_query: Option<String>,
     req_method: Method,
-    req_headers: HeaderMap,
+    mut req_headers: HeaderMap,
 ) -> Result<Response<Body>, CamoError> {
-    let settings = app_state.settings;
+    let settings = &app_state.settings;
 
-    let authenticated_target = AuthenticatedTarget::from_encoded_strings(
+    let mut authenticated_target = AuthenticatedTarget::from_encoded_strings(
         settings.key.as_bytes(),
         &req_digest,
         &req_target,
     )
     .map_err(CamoError::AuthParsingError)?;
 
+    // Without image transforms, the query string is ignored, like it always
+    // was, so existing URLs with junk appended keep working.
+    if settings.image_transforms
+        && let Some(query) = &req_query
+    {
+        authenticated_target = authenticated_target
+            .with_encoded_transform(query)
+            .map_err(CamoError::AuthParsingError)?;
+    }
+
     let target = authenticated_target
         .validated_target_url()
         .map_err(CamoError::AuthValidationError)?;
 
     Span::current().record("target_url", &target);
 
+    let transform = authenticated_target.transform().cloned();
+    // The meta and preview endpoints need the image from its start.
+    if endpoint != Endpoint::Proxy {
+        req_headers.remove(header::RANGE);
+        req_headers.remove(header::IF_RANGE);
+    }
+
+    if let Some(transform) = &transform {
+        let width = transform.width.unwrap_or_default();
+        let height = transform.height.unwrap_or_default();
+        if width > settings.transform_max_width || height > settings.transform_max_height {
+            return Err(CamoError::TransformTooLarge(width, height));
+        }
+
+        // Ranges of the original image are useless for the resized one, so
+        // the whole image is requested.
+        req_headers.remove(header::RANGE);
+        req_headers.remove(header::IF_RANGE);
+    }
+
+    if let Some(hotlink_policy) = &app_state.hotlink_policy {
+        hotlink_policy.check(&req_headers)?;
+    }
+
+    let transcode = endpoint == Endpoint::Proxy
+        && settings.transcode_images
+        && image_transform::accepts_webp(&req_headers);
+
+    // Bodies that get inspected or changed have to arrive uncompressed.
+    // Whether the body is an SVG or an image is only known from the
+    // response, so compression is turned off for all requests.
+    let processes_body = endpoint != Endpoint::Proxy
+        || transform.is_some()
+        || transcode
+        || app_state.image_limits.is_some()
+        || settings.strip_metadata
+        || matches!(
+            settings.svg_handling,
+            SvgHandling::Sanitize | SvgHandling::Rasterize
+        );
+    if processes_body {
+        req_headers.remove(header::ACCEPT_ENCODING);
+    }
+
+    if let Some(coalescer) = &app_state.coalescer {
+        // Different sizes and formats of the same image are different
+        // responses, even if the `accept` header isn't passed upstream.
+        let mut key_target = target.clone();
+        if let Some(transform) = &transform {
+            key_target = format!("{key_target}\0{}", transform.to_query());
+        }
+        if transcode {
+            key_target.push_str("\0webp");
+        }
+        if endpoint != Endpoint::Proxy {
+            key_target = format!("{key_target}\0{}", endpoint.path_suffix());
+        }
+        let key = coalescer.key(&req_method, &key_target, &req_headers);
+        let fetch = fetch_upstream(
+            app_state.clone(),
+            endpoint,
+            target,
+            transform,
+            transcode,
+            req_method,
+            req_headers,
+        );
+        return coalescer.run(key, fetch).await;
+    }
+
+    fetch_upstream(
+        app_state,
+        endpoint,
+        target,
+        transform,
+        transcode,
+        req_method,
+        req_headers,
+    )
+    .await
+}
+
+/// Requests the target from the upstream and runs all checks on the response.
+/// This is split from `process_camo_request`, as this part can be shared
+/// between multiple clients if requests are coalesced.
+async fn fetch_upstream(
+    app_state: AppState,
+    endpoint: Endpoint,
+    target: String,
+    transform: Option<ImageTransform>,
+    transcode: bool,
+    req_method: Method,
+    mut req_headers: HeaderMap,
+) -> Result<Response<Body>, CamoError> {
+    let settings = app_state.settings;
+
     let mut upstream_res = app_state
         .proxy
         .run_request(&req_method, &req_headers, &target)
         .await
-        .map_err(CamoError::ProxyError)?;
+        .map_err(CamoError::from)?;
+
+    // A range of an image would skip stripping its metadata, so the whole
+    // image is requested instead. Clients have to accept a complete response
+    // to a range request anyway.
+    let is_partial_strippable = upstream_res.status() == StatusCode::PARTIAL_CONTENT
+        && try_parse_header::<String>(upstream_res.headers(), &header::CONTENT_TYPE)
+            .and_then(|content_type| ImageFormat::from_content_type(&content_type))
+            .is_some();
+    if settings.strip_metadata && is_partial_strippable {
+        req_headers.remove(header::RANGE);
+        req_headers.remove(header::IF_RANGE);
+        upstream_res = app_state
+            .proxy
+            .run_request(&req_method, &req_headers, &target)
+            .await
+            .map_err(CamoError::from)?;
+    }
+
+    // Unsatisfiable ranges are passed along, so the client learns the
+    // complete length from the Content-Range header. The body is dropped, as
+    // it's usually an error page that wouldn't pass the content-type checks.
+    if upstream_res.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        let (mut parts, _) = upstream_res.into_parts();
+        parts.headers.remove(header::CONTENT_TYPE);
+        parts.headers.remove(header::CONTENT_ENCODING);
+        parts.headers.remove(header::CONTENT_LENGTH);
+        return Ok(Response::from_parts(parts, Body::empty()));
+    }
 
     if !(upstream_res.status().is_success() || upstream_res.status().is_redirection()) {
         return Err(CamoError::UnexpectedUpstreamStatus(
@@ -137,25 +477,131 @@ async fn process_camo_request(
         return Err(CamoError::UpstreamResponseTooLong(content_length));
     }
 
+    // For partial responses, the content-length only covers the range, so the
+    // complete length from the Content-Range header has to be checked as well.
+    if upstream_res.status() == StatusCode::PARTIAL_CONTENT {
+        // Without a Range header in the request, a partial response is bogus,
+        // and passing it along would skip the checks on the start of the body.
+        if !req_headers.contains_key(header::RANGE) {
+            return Err(CamoError::UpstreamPartialContentUnrequested);
+        }
+
+        let content_range =
+            try_parse_header::<String>(upstream_res.headers(), &header::CONTENT_RANGE)
+                .and_then(|content_range| parse_content_range(&content_range))
+                .ok_or(CamoError::UpstreamContentRangeInvalid)?;
+
+        if let Some(complete_length) = content_range.complete_length
+            && complete_length > settings.length_limit as u64
+        {
+            return Err(CamoError::UpstreamResponseTooLong(complete_length as usize));
+        }
+
+        if let Some(content_length) = maybe_content_length
+            && content_length as u64 != content_range.range_length()
+        {
+            return Err(CamoError::UpstreamContentRangeInvalid);
+        }
+    }
+
     // For everything that is not a 3xx status code on a GET request, let's
-    // enforce content-types - unless explicitly told so by a setting. This will
-    // break some misconfigured servers, but that's usually worth it.
-    if !settings.allow_all_types
-        && req_method == Method::GET
-        && !upstream_res.status().is_redirection()
-    {
-        let maybe_content_type =
+    // enforce content-types. This will break some misconfigured servers, but
+    // that's usually worth it.
+    if req_method == Method::GET && !upstream_res.status().is_redirection() {
+        let mut maybe_content_type =
             try_parse_header::<String>(upstream_res.headers(), &header::CONTENT_TYPE);
-        if let Some(content_type) = maybe_content_type {
-            let is_accepted = (settings.allow_audio && content_type.starts_with("audio/"))
-                || (settings.allow_image && content_type.starts_with("image/"))
-                || (settings.allow_video && content_type.starts_with("video/"));
 
-            if !is_accepted {
-                return Err(CamoError::ContentTypeNotAccepted(content_type));
+        // Servers that don't know better send generic content-types, or none
+        // at all, so the real type has to be inferred before the policy can
+        // be applied to it. If that's not possible, the original content-type
+        // is checked as usual.
+        if settings.infer_content_type && sniff::is_generic(maybe_content_type.as_deref()) {
+            let inferred;
+            (upstream_res, inferred) =
+                infer_content_type(upstream_res, &target, settings.infer_content_type_from_url)
+                    .await?;
+
+            if let Some(inferred) = inferred {
+                upstream_res
+                    .headers_mut()
+                    .insert(header::CONTENT_TYPE, HeaderValue::from_static(inferred));
+                maybe_content_type = Some(inferred.to_owned());
             }
+        }
+
+        app_state.mime_policy.check(maybe_content_type.as_deref())?;
+
+        if settings.content_sniffing != ContentSniffing::Off
+            && upstream_res.status() == StatusCode::OK
+            && !is_compressed(upstream_res.headers())
+            && let Some(content_type) = maybe_content_type
+        {
+            upstream_res = sniff_content_type(
+                upstream_res,
+                content_type,
+                settings.content_sniffing,
+                &app_state.mime_policy,
+            )
+            .await?;
+        }
+
+        let is_image = try_parse_header::<String>(upstream_res.headers(), &header::CONTENT_TYPE)
+            .is_some_and(|content_type| sniff::essence(&content_type).starts_with("image/"));
+        if let Some(image_limits) = &app_state.image_limits
+            && is_image
+            && upstream_res.status() == StatusCode::OK
+        {
+            upstream_res = check_image_limits(upstream_res, image_limits).await?;
+        }
+
+        let is_svg = try_parse_header::<String>(upstream_res.headers(), &header::CONTENT_TYPE)
+            .is_some_and(|content_type| sniff::is_svg(&content_type));
+        if is_svg
+            && matches!(
+                settings.svg_handling,
+                SvgHandling::Sanitize | SvgHandling::Rasterize
+            )
+        {
+            upstream_res =
+                process_svg(upstream_res, settings.svg_handling, settings.svg_size_limit).await?;
+        }
+
+        let is_strippable =
+            try_parse_header::<String>(upstream_res.headers(), &header::CONTENT_TYPE)
+                .and_then(|content_type| ImageFormat::from_content_type(&content_type))
+                .is_some();
+        if settings.strip_metadata && is_strippable {
+            upstream_res = strip_image_metadata(upstream_res, settings.length_limit).await?;
+        }
+    }
+
+    let is_transformable =
+        try_parse_header::<String>(upstream_res.headers(), &header::CONTENT_TYPE)
+            .is_some_and(|content_type| image_transform::is_supported(&content_type));
+    if let Some(transform) = &transform
+        && is_transformable
+        && upstream_res.status() == StatusCode::OK
+    {
+        if req_method == Method::GET {
+            upstream_res =
+                transform_image(upstream_res, transform.clone(), settings.length_limit).await?;
         } else {
-            return Err(CamoError::MissingContentType);
+            // The size of the resized image isn't known without resizing it.
+            upstream_res.headers_mut().remove(header::CONTENT_LENGTH);
+        }
+    }
+
+    // Whether the image gets transcoded depends on the `accept` header, so
+    // caches have to know that, even if this response wasn't transcoded.
+    let is_transcodable = try_parse_header::<String>(upstream_res.headers(), &header::CONTENT_TYPE)
+        .is_some_and(|content_type| image_transform::is_transcodable(&content_type));
+    if settings.transcode_images && is_transcodable && upstream_res.status() == StatusCode::OK {
+        upstream_res
+            .headers_mut()
+            .append(header::VARY, HeaderValue::from_static("accept"));
+
+        if transcode && req_method == Method::GET {
+            upstream_res = transcode_image(upstream_res, settings.length_limit).await?;
         }
     }
 
@@ -164,12 +610,29 @@ async fn process_camo_request(
     // which allows redirects to be cached by the client.
     // However, instead of providing the original Location, we have to wrap that
     // in a Camo URL again so that the redirect will be tunneled through Camo...
+    // The new URL keeps the transform, and points to the same endpoint.
     let maybe_location = try_parse_header::<String>(upstream_res.headers(), &header::LOCATION);
     if let Some(location) = maybe_location {
         if let Ok(resolved_location) = resolve_location_header(&target, &location) {
-            let new_target =
-                AuthenticatedTarget::from_target(settings.key.as_bytes(), &resolved_location);
-            let new_target = format!("{}{}", settings.root_url, new_target.encoded_full_path());
+            let key = settings.key.as_bytes();
+            let new_target = match &transform {
+                Some(transform) => AuthenticatedTarget::from_target_with_transform(
+                    key,
+                    &resolved_location,
+                    transform.clone(),
+                ),
+                None => AuthenticatedTarget::from_target(key, &resolved_location),
+            };
+            let mut new_target = format!(
+                "{}{}/{}{}",
+                settings.root_url,
+                new_target.encoded_digest(),
+                new_target.encoded_target_url(),
+                endpoint.path_suffix()
+            );
+            if let Some(transform) = &transform {
+                new_target = format!("{new_target}?{}", transform.to_query());
+            }
 
             let location_header = upstream_res
                 .headers_mut()
@@ -182,7 +645,379 @@ async fn process_camo_request(
         }
     }
 
-    Ok(upstream_res)
+    // Redirects for the other endpoints don't need the upstream's body.
+    if endpoint != Endpoint::Proxy && upstream_res.status().is_redirection() {
+        let (mut parts, _) = upstream_res.into_parts();
+        parts.headers.remove(header::CONTENT_TYPE);
+        parts.headers.remove(header::CONTENT_ENCODING);
+        parts.headers.remove(header::CONTENT_LENGTH);
+        return Ok(Response::from_parts(parts, Body::empty()));
+    }
+
+    match endpoint {
+        Endpoint::Proxy => Ok(upstream_res),
+        Endpoint::Meta => describe_image(upstream_res, settings.length_limit).await,
+        Endpoint::Preview => render_preview(upstream_res, settings.length_limit).await,
+    }
+}
+
+/// Detects the type of the body from its first bytes, with a fallback to the
+/// file extension in the target URL if `from_url` is set. The body is only
+/// inspected for complete, uncompressed responses, as partial and compressed
+/// ones don't start at the signature.
+async fn infer_content_type(
+    upstream_res: Response<Body>,
+    target: &str,
+    from_url: bool,
+) -> Result<(Response<Body>, Option<&'static str>), CamoError> {
+    let (parts, body) = upstream_res.into_parts();
+    let (detected, body) = if parts.status == StatusCode::OK && !is_compressed(&parts.headers) {
+        let (prefix, body) = read_prefix(body, sniff::SNIFF_LENGTH)
+            .await
+            .map_err(CamoError::UpstreamBodyFailed)?;
+        let detected = sniff::detect(&prefix).map(|format| format.mime);
+        (detected, Body::new(body))
+    } else {
+        (None, body)
+    };
+
+    let inferred = detected.or_else(|| from_url.then(|| sniff::guess_from_url(target)).flatten());
+    Ok((Response::from_parts(parts, body), inferred))
+}
+
+/// Checks the start of the body against the content-type. This buffers the
+/// first bytes of the body before anything is sent to the client. Partial and
+/// compressed responses are not checked, as they don't start at the signature.
+async fn sniff_content_type(
+    upstream_res: Response<Body>,
+    content_type: String,
+    mode: ContentSniffing,
+    mime_policy: &MimePolicy,
+) -> Result<Response<Body>, CamoError> {
+    let (mut parts, body) = upstream_res.into_parts();
+    let (prefix, body) = read_prefix(body, sniff::SNIFF_LENGTH)
+        .await
+        .map_err(CamoError::UpstreamBodyFailed)?;
+
+    match sniff::check(&content_type, &prefix) {
+        Verdict::Matches | Verdict::Unknown => {}
+        Verdict::Contradicts(Some(format)) if mode == ContentSniffing::Correct => {
+            // The corrected type has to pass the policy as well, so an HTML
+            // page labelled as an image is still rejected.
+            mime_policy.check(Some(format.mime))?;
+            parts
+                .headers
+                .insert(header::CONTENT_TYPE, HeaderValue::from_static(format.mime));
+        }
+        Verdict::Contradicts(format) => {
+            let detected = format.map_or("unknown data", |format| format.mime);
+            return Err(CamoError::ContentTypeMismatch(
+                content_type,
+                detected.to_owned(),
+            ));
+        }
+    }
+
+    Ok(Response::from_parts(parts, Body::new(body)))
+}
+
+/// Rejects images whose header declares dimensions or a number of frames over
+/// the limits. Only the header is buffered, which is usually in the first few
+/// bytes, but JPEGs can have large metadata segments before it. Formats
+/// without a known header, and headers that aren't found within
+/// `MAX_HEADER_LENGTH`, are passed along. The frames of GIFs and animated
+/// WebPs are counted while streaming, as their header doesn't declare them.
+async fn check_image_limits(
+    upstream_res: Response<Body>,
+    image_limits: &ImageLimits,
+) -> Result<Response<Body>, CamoError> {
+    let (parts, body) = upstream_res.into_parts();
+    let (header, prefix, mut body) = read_image_header(body).await?;
+
+    match header {
+        Header::Parsed(info) => {
+            image_limits.check(&info)?;
+
+            let max_frames = image_limits.max_frames();
+            if info.frames.is_none()
+                && max_frames > 0
+                && let Some(counter) = FrameCounter::new(&prefix)
+            {
+                // Small images are often read completely with the header,
+                // and can still be rejected with a proper status code.
+                let frames = counter.clone().feed(&prefix);
+                if frames > max_frames {
+                    return Err(CamoError::ImageTooLarge(format!("{frames} frames")));
+                }
+
+                body = Body::new(FrameLimitBody::new(body, counter, max_frames));
+            }
+        }
+        // JPEGs with more metadata than fits into the window are valid, so
+        // they are passed along like formats without a known header.
+        Header::Unknown | Header::Incomplete(_) => {}
+        Header::Malformed => {
+            return Err(CamoError::UpstreamBodyUnprocessable(
+                "image header is malformed".to_owned(),
+            ));
+        }
+    }
+
+    Ok(Response::from_parts(parts, body))
+}
+
+/// Describes the image in a response as JSON. Only the header is read,
+/// unless the image can be decoded, in which case the whole image is read to
+/// find its dominant color and count its frames.
+async fn describe_image(
+    upstream_res: Response<Body>,
+    length_limit: usize,
+) -> Result<Response<Body>, CamoError> {
+    let (mut parts, body) = upstream_res.into_parts();
+    parts.headers.remove(header::ACCEPT_RANGES);
+    parts.headers.remove(header::CONTENT_RANGE);
+    parts.headers.remove(header::ETAG);
+
+    let mut meta = ImageMeta {
+        content_type: try_parse_header(&parts.headers, &header::CONTENT_TYPE),
+        byte_length: try_parse_header(&parts.headers, &header::CONTENT_LENGTH),
+        ..Default::default()
+    };
+
+    if parts.status == StatusCode::OK && !is_compressed(&parts.headers) {
+        let (header, prefix, body) = read_image_header(body).await?;
+        if let Header::Parsed(info) = header {
+            meta.width = Some(info.width);
+            meta.height = Some(info.height);
+            meta.frames = info.frames;
+
+            if image_meta::is_decodable(&prefix) && info.pixels() <= image_meta::MAX_DECODE_PIXELS {
+                let image = read_whole_body(&parts, body, length_limit).await?;
+                meta.byte_length = Some(image.len() as u64);
+
+                let (frames, dominant_color) = run_blocking(move || {
+                    (
+                        image_header::count_frames(&image),
+                        image_meta::dominant_color(&image),
+                    )
+                })
+                .await?;
+                meta.frames = frames.or(meta.frames);
+                meta.dominant_color = dominant_color;
+            }
+        }
+    }
+
+    let json = serde_json::to_vec(&meta).expect("ImageMeta can always be serialized");
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    Ok(replace_body(parts, json))
+}
+
+/// Renders a preview of the image in a response. The header is checked first,
+/// so only images that can be decoded without too much work are read
+/// completely. Upstreams that don't say how long responses may be cached get
+/// a default, as previews are cheap to keep around.
+async fn render_preview(
+    upstream_res: Response<Body>,
+    length_limit: usize,
+) -> Result<Response<Body>, CamoError> {
+    let unprocessable =
+        || CamoError::UpstreamBodyUnprocessable("image can't be previewed".to_owned());
+
+    let (mut parts, body) = upstream_res.into_parts();
+    let (header, prefix, body) = read_image_header(body).await?;
+    let Header::Parsed(info) = header else {
+        return Err(unprocessable());
+    };
+    if !image_meta::is_decodable(&prefix) || info.pixels() > image_meta::MAX_DECODE_PIXELS {
+        return Err(unprocessable());
+    }
+
+    let image = read_whole_body(&parts, body, length_limit).await?;
+    let preview = run_blocking(move || preview::render(&image))
+        .await?
+        .ok_or_else(unprocessable)?;
+
+    parts.headers.remove(header::ACCEPT_RANGES);
+    parts.headers.remove(header::ETAG);
+    parts
+        .headers
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static("image/jpeg"));
+    if !parts.headers.contains_key(header::CACHE_CONTROL)
+        && !parts.headers.contains_key(header::EXPIRES)
+    {
+        parts.headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static(PREVIEW_CACHE_CONTROL),
+        );
+    }
+    Ok(replace_body(parts, preview))
+}
+
+/// Reads the start of a body until the image header is found, or it's clear
+/// that there isn't one. Returns the result, the bytes read, and a body that
+/// yields them again.
+async fn read_image_header(mut body: Body) -> Result<(Header, Bytes, Body), CamoError> {
+    let mut len = sniff::SNIFF_LENGTH;
+    loop {
+        let (prefix, prefixed) = read_prefix(body, len)
+            .await
+            .map_err(CamoError::UpstreamBodyFailed)?;
+        body = Body::new(prefixed);
+
+        // Reading at least twice as much each time keeps the number of
+        // rounds low if there are many small segments.
+        match image_header::parse(&prefix) {
+            Header::Incomplete(needed)
+                if prefix.len() >= len && needed <= image_header::MAX_HEADER_LENGTH =>
+            {
+                len = needed.max(len * 2).min(image_header::MAX_HEADER_LENGTH);
+            }
+            header => return Ok((header, prefix, body)),
+        }
+    }
+}
+
+/// Sanitizes or rasterizes an SVG.
+async fn process_svg(
+    upstream_res: Response<Body>,
+    handling: SvgHandling,
+    size_limit: usize,
+) -> Result<Response<Body>, CamoError> {
+    let (mut parts, body) = upstream_res.into_parts();
+    let svg = read_whole_body(&parts, body, size_limit).await?;
+
+    let processed = run_blocking(move || match handling {
+        SvgHandling::Rasterize => svg::rasterize(&svg),
+        _ => svg::sanitize(&svg),
+    })
+    .await??;
+
+    if handling == SvgHandling::Rasterize {
+        parts
+            .headers
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static("image/png"));
+    }
+    Ok(replace_body(parts, processed))
+}
+
+/// Removes metadata from an image.
+async fn strip_image_metadata(
+    upstream_res: Response<Body>,
+    length_limit: usize,
+) -> Result<Response<Body>, CamoError> {
+    let (parts, body) = upstream_res.into_parts();
+    let image = read_whole_body(&parts, body, length_limit).await?;
+    let stripped = metadata::strip(&image)?;
+
+    Ok(replace_body(parts, stripped))
+}
+
+/// Resizes an image. Formats and images that can't be resized are passed
+/// along unchanged.
+async fn transform_image(
+    upstream_res: Response<Body>,
+    transform: ImageTransform,
+    length_limit: usize,
+) -> Result<Response<Body>, CamoError> {
+    let (mut parts, body) = upstream_res.into_parts();
+    let image = read_whole_body(&parts, body, length_limit).await?;
+
+    let original = image.clone();
+    let transformed = run_blocking(move || transform.apply(&image)).await??;
+
+    let Some((transformed, content_type)) = transformed else {
+        return Ok(Response::from_parts(parts, Body::from(original)));
+    };
+
+    parts
+        .headers
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    parts.headers.remove(header::ACCEPT_RANGES);
+    Ok(replace_body(parts, transformed))
+}
+
+/// Transcodes an image to WebP. The original is passed along if it can't be
+/// decoded, or if the WebP isn't smaller.
+async fn transcode_image(
+    upstream_res: Response<Body>,
+    length_limit: usize,
+) -> Result<Response<Body>, CamoError> {
+    let (mut parts, body) = upstream_res.into_parts();
+    let image = read_whole_body(&parts, body, length_limit).await?;
+
+    let original = image.clone();
+    let transcoded = run_blocking(move || image_transform::transcode_to_webp(&image)).await?;
+
+    let Some(transcoded) = transcoded else {
+        return Ok(Response::from_parts(parts, Body::from(original)));
+    };
+
+    // The upstream's ETag identifies the original, not the WebP.
+    parts
+        .headers
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static("image/webp"));
+    parts.headers.remove(header::ACCEPT_RANGES);
+    parts.headers.remove(header::ETAG);
+    Ok(replace_body(parts, transcoded))
+}
+
+/// Buffers a whole upstream body, for checks and changes that can't be done
+/// while streaming. Bodies over `limit` are rejected. Partial and compressed
+/// responses can't be processed, as the body isn't the whole file then.
+async fn read_whole_body(parts: &Parts, body: Body, limit: usize) -> Result<Bytes, CamoError> {
+    if parts.status != StatusCode::OK {
+        return Err(CamoError::UpstreamBodyUnprocessable(
+            "partial responses can't be processed".to_owned(),
+        ));
+    }
+    if is_compressed(&parts.headers) {
+        return Err(CamoError::UpstreamBodyUnprocessable(
+            "compressed responses can't be processed".to_owned(),
+        ));
+    }
+
+    let (data, _) = read_prefix(body, limit + 1)
+        .await
+        .map_err(CamoError::UpstreamBodyFailed)?;
+    if data.len() > limit {
+        return Err(CamoError::UpstreamResponseTooLong(data.len()));
+    }
+
+    Ok(data)
+}
+
+/// Runs work like decoding or rendering an image on the blocking thread pool,
+/// as it takes a while, and would block other requests otherwise.
+async fn run_blocking<T, F>(f: F) -> Result<T, CamoError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|err| CamoError::UpstreamBodyUnprocessable(err.to_string()))
+}
+
+/// Builds a response with a changed body, and a matching content-length.
+fn replace_body(mut parts: Parts, body: Vec<u8>) -> Response<Body> {
+    parts.headers.remove(header::CONTENT_ENCODING);
+    parts
+        .headers
+        .insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// Returns whether a response body has a content-encoding, in which case it
+/// doesn't start with the file's signature.
+fn is_compressed(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_ENCODING)
+        .is_some_and(|encoding| encoding != "identity")
 }
 
 /// Small helper to build a response with a provided status code and a plain
@@ -1,5 +1,12 @@
 //! The Application Settings Module(tm)
 
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use hyper::header::{HeaderName, HeaderValue};
 use tracing::Level;
 
 /// Specifies the log's output format
@@ -38,6 +45,219 @@ impl LogLevel {
     }
 }
 
+/// Specifies how upstream bodies are checked against their content-type
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentSniffing {
+    /// Trusts the content-type sent by the upstream.
+    Off,
+
+    /// Rejects responses if the start of the body contradicts the
+    /// content-type.
+    Reject,
+
+    /// Replaces the content-type with the detected one if they contradict,
+    /// and rejects responses if no type could be detected.
+    Correct,
+}
+
+/// Specifies how SVGs are handled
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SvgHandling {
+    /// Passes SVGs through unchanged, if their content-type is allowed.
+    Pass,
+
+    /// Removes scripts, event handlers, and external references from SVGs.
+    Sanitize,
+
+    /// Renders SVGs to PNGs.
+    Rasterize,
+
+    /// Rejects all SVGs.
+    Block,
+}
+
+/// Specifies which IP families can be used for upstream connections
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpFamily {
+    /// Uses IPv4 and IPv6, in the order returned by the resolver.
+    Any,
+
+    /// Only connects to upstreams via IPv4.
+    Ipv4,
+
+    /// Only connects to upstreams via IPv6.
+    Ipv6,
+
+    /// Uses IPv4 and IPv6, but tries IPv4 addresses first.
+    PreferIpv4,
+
+    /// Uses IPv4 and IPv6, but tries IPv6 addresses first.
+    PreferIpv6,
+}
+
+impl IpFamily {
+    /// Returns whether connecting to the given address is allowed.
+    pub fn permits(&self, addr: &IpAddr) -> bool {
+        match self {
+            IpFamily::Ipv4 => addr.is_ipv4(),
+            IpFamily::Ipv6 => addr.is_ipv6(),
+            IpFamily::Any | IpFamily::PreferIpv4 | IpFamily::PreferIpv6 => true,
+        }
+    }
+}
+
+/// Specifies which kinds of upstream failures can be retried
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetryOn {
+    /// Retries if the connection to the upstream could not be established.
+    Connect,
+
+    /// Retries if connecting or waiting for the response headers timed out.
+    Timeout,
+
+    /// Retries if the upstream closed or reset the connection before sending
+    /// the response headers.
+    Reset,
+}
+
+/// Specifies which root certificates are trusted for upstream connections
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsRoots {
+    /// Uses the root certificates provided by the operating system.
+    Native,
+
+    /// Uses the Mozilla root certificates bundled into camo-rs.
+    Webpki,
+
+    /// Uses both the operating system's and the bundled root certificates.
+    Both,
+}
+
+/// Specifies the minimum TLS version for upstream connections
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsVersion {
+    /// Allows TLS 1.2 and TLS 1.3.
+    #[value(name = "1.2")]
+    Tls12,
+
+    /// Only allows TLS 1.3.
+    #[value(name = "1.3")]
+    Tls13,
+}
+
+/// A static header sent to the upstream, written as `host Name: value`. The
+/// host `*` matches all upstreams.
+#[derive(Clone, Debug)]
+pub struct UpstreamHeader {
+    /// The upstream host this header is sent to, or `None` for all hosts.
+    pub host: Option<String>,
+    pub name: HeaderName,
+    pub value: HeaderValue,
+}
+
+impl FromStr for UpstreamHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("`{s}` is not in the `host Name: value` format");
+
+        let (host, header) = s.trim().split_once(' ').ok_or_else(invalid)?;
+        let (name, value) = header.split_once(':').ok_or_else(invalid)?;
+
+        Ok(Self {
+            host: (host != "*").then(|| host.to_ascii_lowercase()),
+            name: name
+                .trim()
+                .parse()
+                .map_err(|_| format!("`{}` is not a valid header name", name.trim()))?,
+            value: value
+                .trim()
+                .parse()
+                .map_err(|_| format!("`{}` is not a valid header value", value.trim()))?,
+        })
+    }
+}
+
+/// A security header set on all responses, written as `Name: value`.
+#[derive(Clone, Debug)]
+pub struct SecurityHeader {
+    pub name: HeaderName,
+    pub value: HeaderValue,
+}
+
+impl FromStr for SecurityHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s
+            .split_once(':')
+            .ok_or_else(|| format!("`{s}` is not in the `Name: value` format"))?;
+
+        Ok(Self {
+            name: name
+                .trim()
+                .parse()
+                .map_err(|_| format!("`{}` is not a valid header name", name.trim()))?,
+            value: value
+                .trim()
+                .parse()
+                .map_err(|_| format!("`{}` is not a valid header value", value.trim()))?,
+        })
+    }
+}
+
+/// A MIME type pattern in the content-type policy, written as `type/subtype`,
+/// `type/*`, or `*/*`. A `None` part matches everything.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MimePattern {
+    pub type_: Option<String>,
+    pub subtype: Option<String>,
+}
+
+impl MimePattern {
+    /// Returns whether the pattern matches a MIME type. Both parts of the
+    /// MIME type have to be lowercase.
+    pub fn matches(&self, type_: &str, subtype: &str) -> bool {
+        self.type_.as_deref().is_none_or(|t| t == type_)
+            && self.subtype.as_deref().is_none_or(|s| s == subtype)
+    }
+
+    /// The number of parts that are not wildcards, so `image/png` is more
+    /// specific than `image/*`, which is more specific than `*/*`.
+    pub fn specificity(&self) -> usize {
+        usize::from(self.type_.is_some()) + usize::from(self.subtype.is_some())
+    }
+}
+
+impl FromStr for MimePattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mime: mime::Mime = s
+            .trim()
+            .parse()
+            .map_err(|_| format!("`{s}` is not a valid MIME type"))?;
+        if mime.params().next().is_some() {
+            return Err(format!("`{s}` must not have parameters"));
+        }
+
+        let essence = mime.essence_str().to_ascii_lowercase();
+        let (type_, subtype) = essence
+            .split_once('/')
+            .ok_or_else(|| format!("`{s}` is not a valid MIME type"))?;
+        let wildcard = |part: &str| (part != "*").then(|| part.to_owned());
+        let type_ = wildcard(type_);
+        let subtype = wildcard(subtype);
+        if type_.is_none() && subtype.is_some() {
+            return Err(format!(
+                "`{s}` has a wildcard type, but no wildcard subtype"
+            ));
+        }
+
+        Ok(Self { type_, subtype })
+    }
+}
+
 /// Application Settings Struct, designed to be primarily used by Clap
 #[derive(clap::Parser, Clone, Debug)]
 #[clap(about, author, version = env!("CAMO_RS_VERSION"))]
@@ -58,6 +278,44 @@ pub struct Settings {
     #[clap(long = "allow-all-types", env = "CAMO_ALLOW_ALL_TYPES")]
     pub allow_all_types: bool,
 
+    /// Comma-separated list of MIME types to allow, like `image/png`, or
+    /// `image/*` for all subtypes
+    #[clap(long = "allow-types", env = "CAMO_ALLOW_TYPES", value_delimiter = ',')]
+    pub allow_types: Vec<MimePattern>,
+
+    /// If present, concurrent requests for the same target will share a
+    /// single upstream request
+    #[clap(long = "coalesce-requests", env = "CAMO_COALESCE_REQUESTS")]
+    pub coalesce_requests: bool,
+
+    /// Whether the start of upstream bodies is checked against the
+    /// content-type
+    #[clap(value_enum, long = "content-sniffing", env = "CAMO_CONTENT_SNIFFING", default_value_t = ContentSniffing::Off)]
+    pub content_sniffing: ContentSniffing,
+
+    /// Comma-separated list of origins allowed to read proxied responses via
+    /// CORS - use `*` to allow all origins, or leave empty to disable CORS
+    #[clap(
+        long = "cors-allow-origins",
+        env = "CAMO_CORS_ALLOW_ORIGINS",
+        value_delimiter = ','
+    )]
+    pub cors_allow_origins: Vec<String>,
+
+    /// The number of seconds browsers may cache the answer to a CORS
+    /// preflight request
+    #[clap(
+        long = "cors-max-age",
+        env = "CAMO_CORS_MAX_AGE",
+        default_value_t = 86400
+    )]
+    pub cors_max_age: usize,
+
+    /// Comma-separated list of MIME types to reject, even if they are allowed
+    /// by a less specific pattern, like `image/svg+xml`
+    #[clap(long = "deny-types", env = "CAMO_DENY_TYPES", value_delimiter = ',')]
+    pub deny_types: Vec<MimePattern>,
+
     /// The string used to identify this instance in upstream requests in Via and User-Agent
     #[clap(
         long = "header-via",
@@ -66,6 +324,86 @@ pub struct Settings {
     )]
     pub header_via: String,
 
+    /// If present, requests without a referer are allowed when hotlink
+    /// protection is enabled, unless the browser marks them as cross-site
+    #[clap(
+        long = "hotlink-allow-empty-referer",
+        env = "CAMO_HOTLINK_ALLOW_EMPTY_REFERER"
+    )]
+    pub hotlink_allow_empty_referer: bool,
+
+    /// Comma-separated list of hosts allowed to embed proxied resources -
+    /// `*.example.com` allows all subdomains, and an empty list disables
+    /// hotlink protection
+    #[clap(
+        long = "hotlink-allow-hosts",
+        env = "CAMO_HOTLINK_ALLOW_HOSTS",
+        value_delimiter = ','
+    )]
+    pub hotlink_allow_hosts: Vec<String>,
+
+    /// The maximum number of frames in animated images, as declared in
+    /// their header, or counted while streaming GIFs and animated WebPs - 0
+    /// means unlimited
+    #[clap(
+        long = "image-max-frames",
+        env = "CAMO_IMAGE_MAX_FRAMES",
+        default_value_t = 0
+    )]
+    pub image_max_frames: u32,
+
+    /// The maximum height in pixels of images - 0 means unlimited
+    #[clap(
+        long = "image-max-height",
+        env = "CAMO_IMAGE_MAX_HEIGHT",
+        default_value_t = 0
+    )]
+    pub image_max_height: u32,
+
+    /// The maximum number of pixels of images - 0 means unlimited
+    #[clap(
+        long = "image-max-pixels",
+        env = "CAMO_IMAGE_MAX_PIXELS",
+        default_value_t = 0
+    )]
+    pub image_max_pixels: u64,
+
+    /// The maximum width in pixels of images - 0 means unlimited
+    #[clap(
+        long = "image-max-width",
+        env = "CAMO_IMAGE_MAX_WIDTH",
+        default_value_t = 0
+    )]
+    pub image_max_width: u32,
+
+    /// If present, images are resized according to signed parameters in the
+    /// query string of Camo URLs
+    #[clap(long = "image-transforms", env = "CAMO_IMAGE_TRANSFORMS")]
+    pub image_transforms: bool,
+
+    /// The maximum number of proxy requests handled at the same time - 0
+    /// means unlimited
+    #[clap(
+        long = "in-flight-limit",
+        env = "CAMO_IN_FLIGHT_LIMIT",
+        default_value_t = 0
+    )]
+    pub in_flight_limit: usize,
+
+    /// If present, the type of bodies without a content-type, or with a
+    /// generic one like `application/octet-stream`, is detected from their
+    /// first bytes
+    #[clap(long = "infer-content-type", env = "CAMO_INFER_CONTENT_TYPE")]
+    pub infer_content_type: bool,
+
+    /// If present, content-type inference falls back to the file extension in
+    /// the URL if the body's type can't be detected
+    #[clap(
+        long = "infer-content-type-from-url",
+        env = "CAMO_INFER_CONTENT_TYPE_FROM_URL"
+    )]
+    pub infer_content_type_from_url: bool,
+
     /// Randomly generated string used as a key for calculating the HMAC digest
     #[clap(long = "key", env = "CAMO_KEY")]
     pub key: String,
@@ -90,6 +428,74 @@ pub struct Settings {
     #[clap(value_enum, long = "log-level", env = "CAMO_LOG_LEVEL", default_value_t = LogLevel::Quiet)]
     pub log_level: LogLevel,
 
+    /// If present, `/<digest>/<target>/meta` returns the dimensions and other
+    /// details of an image as JSON
+    #[clap(long = "meta-endpoint", env = "CAMO_META_ENDPOINT")]
+    pub meta_endpoint: bool,
+
+    /// Comma-separated list of request headers passed to the upstream, in
+    /// addition to the default ones
+    #[clap(
+        long = "pass-request-headers",
+        env = "CAMO_PASS_REQUEST_HEADERS",
+        value_delimiter = ','
+    )]
+    pub pass_request_headers: Vec<HeaderName>,
+
+    /// Comma-separated list of upstream response headers passed to the
+    /// client, in addition to the default ones
+    #[clap(
+        long = "pass-response-headers",
+        env = "CAMO_PASS_RESPONSE_HEADERS",
+        value_delimiter = ','
+    )]
+    pub pass_response_headers: Vec<HeaderName>,
+
+    /// If present, `/<digest>/<target>/preview` returns a tiny, blurred JPEG
+    /// of an image
+    #[clap(long = "preview-endpoint", env = "CAMO_PREVIEW_ENDPOINT")]
+    pub preview_endpoint: bool,
+
+    /// The number of proxy requests that may wait for a free slot once the
+    /// in-flight limit is reached
+    #[clap(long = "queue-limit", env = "CAMO_QUEUE_LIMIT", default_value_t = 0)]
+    pub queue_limit: usize,
+
+    /// The number of seconds a queued proxy request waits for a free slot
+    #[clap(
+        long = "queue-timeout",
+        env = "CAMO_QUEUE_TIMEOUT",
+        default_value_t = 5
+    )]
+    pub queue_timeout: usize,
+
+    /// The number of seconds of traffic a client may use at once, on top of
+    /// the rate limits
+    #[clap(
+        long = "rate-limit-burst",
+        env = "CAMO_RATE_LIMIT_BURST",
+        default_value_t = 10
+    )]
+    pub rate_limit_burst: u64,
+
+    /// The number of response body bytes per second a single client may
+    /// receive - 0 disables the limit
+    #[clap(
+        long = "rate-limit-bytes",
+        env = "CAMO_RATE_LIMIT_BYTES",
+        default_value_t = 0
+    )]
+    pub rate_limit_bytes: u64,
+
+    /// The number of proxy requests per second a single client may send - 0
+    /// disables the limit
+    #[clap(
+        long = "rate-limit-requests",
+        env = "CAMO_RATE_LIMIT_REQUESTS",
+        default_value_t = 0
+    )]
+    pub rate_limit_requests: u64,
+
     /// URL, including a trailing slash, relative to the domain Camo is running
     /// on
     ///
@@ -99,15 +505,265 @@ pub struct Settings {
     #[clap(long = "root-url", env = "CAMO_ROOT_URL")]
     pub root_url: String,
 
+    /// Security headers set on all responses, written as `Name: value` -
+    /// these replace default security headers with the same name
+    #[clap(
+        long = "security-headers",
+        env = "CAMO_SECURITY_HEADERS",
+        value_delimiter = '\n'
+    )]
+    pub security_headers: Vec<SecurityHeader>,
+
+    /// If present, EXIF, XMP, and IPTC metadata is removed from JPEG, PNG, and
+    /// WebP images
+    #[clap(long = "strip-metadata", env = "CAMO_STRIP_METADATA")]
+    pub strip_metadata: bool,
+
+    /// Comma-separated list of default request headers that are not passed to
+    /// the upstream
+    #[clap(
+        long = "strip-request-headers",
+        env = "CAMO_STRIP_REQUEST_HEADERS",
+        value_delimiter = ','
+    )]
+    pub strip_request_headers: Vec<HeaderName>,
+
+    /// Comma-separated list of default security headers that are not set
+    #[clap(
+        long = "strip-security-headers",
+        env = "CAMO_STRIP_SECURITY_HEADERS",
+        value_delimiter = ','
+    )]
+    pub strip_security_headers: Vec<HeaderName>,
+
+    /// How SVGs are handled
+    #[clap(value_enum, long = "svg-handling", env = "CAMO_SVG_HANDLING", default_value_t = SvgHandling::Pass)]
+    pub svg_handling: SvgHandling,
+
+    /// The maximum size in bytes of SVGs that are sanitized or rasterized
+    #[clap(
+        long = "svg-size-limit",
+        env = "CAMO_SVG_SIZE_LIMIT",
+        default_value_t = 1048576
+    )]
+    pub svg_size_limit: usize,
+
     /// Limits the number of threads used - defaults to the number of CPU cores
     #[clap(long, env = "CAMO_THREADS")]
     pub threads: Option<usize>,
 
-    /// The number of seconds to wait for an upstream response
+    /// If present, raster images are transcoded to WebP for clients that
+    /// accept it, if that makes them smaller
+    #[clap(long = "transcode-images", env = "CAMO_TRANSCODE_IMAGES")]
+    pub transcode_images: bool,
+
+    /// The maximum height in pixels that images can be resized to
+    #[clap(
+        long = "transform-max-height",
+        env = "CAMO_TRANSFORM_MAX_HEIGHT",
+        default_value_t = 2048
+    )]
+    pub transform_max_height: u32,
+
+    /// The maximum width in pixels that images can be resized to
+    #[clap(
+        long = "transform-max-width",
+        env = "CAMO_TRANSFORM_MAX_WIDTH",
+        default_value_t = 2048
+    )]
+    pub transform_max_width: u32,
+
+    /// The number of reverse proxies in front of camo-rs that add themselves
+    /// to the `Forwarded` or `X-Forwarded-For` headers - used to find the
+    /// client's IP address
+    #[clap(
+        long = "trusted-proxy-hops",
+        env = "CAMO_TRUSTED_PROXY_HOPS",
+        default_value_t = 0
+    )]
+    pub trusted_proxy_hops: usize,
+
+    /// The local IPv4 address used for outgoing upstream connections
+    #[clap(long = "upstream-bind-ipv4", env = "CAMO_UPSTREAM_BIND_IPV4")]
+    pub upstream_bind_ipv4: Option<Ipv4Addr>,
+
+    /// The local IPv6 address used for outgoing upstream connections
+    #[clap(long = "upstream-bind-ipv6", env = "CAMO_UPSTREAM_BIND_IPV6")]
+    pub upstream_bind_ipv6: Option<Ipv6Addr>,
+
+    /// Path to a PEM file with additional CA certificates trusted for
+    /// upstream connections
+    #[clap(long = "upstream-ca-bundle", env = "CAMO_UPSTREAM_CA_BUNDLE")]
+    pub upstream_ca_bundle: Option<PathBuf>,
+
+    /// The number of seconds requests to an upstream host fail fast after its
+    /// circuit breaker opened
+    #[clap(
+        long = "upstream-circuit-breaker-cooldown",
+        env = "CAMO_UPSTREAM_CIRCUIT_BREAKER_COOLDOWN",
+        default_value_t = 30
+    )]
+    pub upstream_circuit_breaker_cooldown: usize,
+
+    /// The number of consecutive failed requests after which the circuit
+    /// breaker for an upstream host opens - 0 disables the circuit breaker
+    #[clap(
+        long = "upstream-circuit-breaker-threshold",
+        env = "CAMO_UPSTREAM_CIRCUIT_BREAKER_THRESHOLD",
+        default_value_t = 0
+    )]
+    pub upstream_circuit_breaker_threshold: usize,
+
+    /// Path to a PEM file with a client certificate chain presented to
+    /// upstreams that request one
+    #[clap(
+        long = "upstream-client-cert",
+        env = "CAMO_UPSTREAM_CLIENT_CERT",
+        requires = "upstream_client_key"
+    )]
+    pub upstream_client_cert: Option<PathBuf>,
+
+    /// Comma-separated list of upstream hosts the client certificate is sent
+    /// to - defaults to all hosts
+    #[clap(
+        long = "upstream-client-cert-hosts",
+        env = "CAMO_UPSTREAM_CLIENT_CERT_HOSTS",
+        value_delimiter = ',',
+        requires = "upstream_client_cert"
+    )]
+    pub upstream_client_cert_hosts: Vec<String>,
+
+    /// Path to a PEM file with the private key for the client certificate
+    #[clap(
+        long = "upstream-client-key",
+        env = "CAMO_UPSTREAM_CLIENT_KEY",
+        requires = "upstream_client_cert"
+    )]
+    pub upstream_client_key: Option<PathBuf>,
+
+    /// The number of seconds to wait for the TCP connection and TLS handshake
+    /// to the upstream
+    #[clap(
+        long = "upstream-connect-timeout",
+        env = "CAMO_UPSTREAM_CONNECT_TIMEOUT",
+        default_value_t = 10
+    )]
+    pub upstream_connect_timeout: usize,
+
+    /// Static headers sent to upstreams, written as `host Name: value` - use
+    /// `*` as the host to send a header to all upstreams
+    #[clap(
+        long = "upstream-headers",
+        env = "CAMO_UPSTREAM_HEADERS",
+        value_delimiter = '\n'
+    )]
+    pub upstream_headers: Vec<UpstreamHeader>,
+
+    /// The maximum number of concurrent requests to a single upstream host -
+    /// 0 means unlimited
+    #[clap(
+        long = "upstream-host-concurrency",
+        env = "CAMO_UPSTREAM_HOST_CONCURRENCY",
+        default_value_t = 0
+    )]
+    pub upstream_host_concurrency: usize,
+
+    /// The number of seconds the upstream may stall while sending the body
+    #[clap(
+        long = "upstream-idle-timeout",
+        env = "CAMO_UPSTREAM_IDLE_TIMEOUT",
+        default_value_t = 30
+    )]
+    pub upstream_idle_timeout: usize,
+
+    /// The IP families used for upstream connections
+    #[clap(value_enum, long = "upstream-ip-family", env = "CAMO_UPSTREAM_IP_FAMILY", default_value_t = IpFamily::Any)]
+    pub upstream_ip_family: IpFamily,
+
+    /// The minimum number of bytes per second an upstream has to send while
+    /// streaming the body - 0 disables the check
+    #[clap(
+        long = "upstream-min-rate",
+        env = "CAMO_UPSTREAM_MIN_RATE",
+        default_value_t = 0
+    )]
+    pub upstream_min_rate: u64,
+
+    /// The number of seconds over which the minimum rate is averaged
+    #[clap(
+        long = "upstream-min-rate-window",
+        env = "CAMO_UPSTREAM_MIN_RATE_WINDOW",
+        default_value_t = 10
+    )]
+    pub upstream_min_rate_window: usize,
+
+    /// The number of times a failed GET or HEAD request to the upstream is
+    /// retried
+    #[clap(
+        long = "upstream-retries",
+        env = "CAMO_UPSTREAM_RETRIES",
+        default_value_t = 0
+    )]
+    pub upstream_retries: usize,
+
+    /// The number of milliseconds to wait before the first retry, doubled on
+    /// every further retry
+    #[clap(
+        long = "upstream-retry-backoff",
+        env = "CAMO_UPSTREAM_RETRY_BACKOFF",
+        default_value_t = 100
+    )]
+    pub upstream_retry_backoff: u64,
+
+    /// The maximum number of milliseconds to wait between two retries
+    #[clap(
+        long = "upstream-retry-max-backoff",
+        env = "CAMO_UPSTREAM_RETRY_MAX_BACKOFF",
+        default_value_t = 2000
+    )]
+    pub upstream_retry_max_backoff: u64,
+
+    /// Comma-separated list of upstream failures that are retried
+    #[clap(
+        value_enum,
+        long = "upstream-retry-on",
+        env = "CAMO_UPSTREAM_RETRY_ON",
+        value_delimiter = ',',
+        default_values_t = [RetryOn::Connect, RetryOn::Timeout, RetryOn::Reset]
+    )]
+    pub upstream_retry_on: Vec<RetryOn>,
+
+    /// Comma-separated list of upstream status codes that are retried
+    #[clap(
+        long = "upstream-retry-statuses",
+        env = "CAMO_UPSTREAM_RETRY_STATUSES",
+        value_delimiter = ',',
+        default_values_t = [502, 503, 504]
+    )]
+    pub upstream_retry_statuses: Vec<u16>,
+
+    /// The number of seconds to wait for the upstream's response headers
     #[clap(
         long = "upstream-timeout",
         env = "CAMO_UPSTREAM_TIMEOUT",
         default_value_t = 10
     )]
     pub upstream_timeout: usize,
+
+    /// The minimum TLS version used for upstream connections
+    #[clap(value_enum, long = "upstream-tls-min-version", env = "CAMO_UPSTREAM_TLS_MIN_VERSION", default_value_t = TlsVersion::Tls12)]
+    pub upstream_tls_min_version: TlsVersion,
+
+    /// The root certificates trusted for upstream connections
+    #[clap(value_enum, long = "upstream-tls-roots", env = "CAMO_UPSTREAM_TLS_ROOTS", default_value_t = TlsRoots::Native)]
+    pub upstream_tls_roots: TlsRoots,
+
+    /// The number of seconds the whole upstream transfer, including the body,
+    /// may take. For the body, only time spent waiting for the upstream counts
+    #[clap(
+        long = "upstream-transfer-timeout",
+        env = "CAMO_UPSTREAM_TRANSFER_TIMEOUT",
+        default_value_t = 300
+    )]
+    pub upstream_transfer_timeout: usize,
 }
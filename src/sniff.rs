@@ -0,0 +1,420 @@
+//! Compares the first bytes of a body against the signatures of common file
+//! formats, to catch upstreams that label something else as media, and to
+//! find the real type of bodies sent with a generic content-type.
+
+/// The number of bytes buffered before a body is checked. This covers all
+/// signatures below, and leaves room for whitespace before markup.
+pub const SNIFF_LENGTH: usize = 512;
+
+/// A file format that can be recognized by its first bytes.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Format {
+    /// The MIME type used when correcting the content-type.
+    pub mime: &'static str,
+
+    /// All MIME types a body in this format may be declared as.
+    declared_as: &'static [&'static str],
+
+    /// Whether a body declared as one of the types above has to start with
+    /// the signature. This is not the case for text-based formats, which can
+    /// start with comments, and for MP3s, which can start with junk.
+    strict: bool,
+}
+
+/// The result of checking a body against its declared content-type.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// The body matches the declared content-type.
+    Matches,
+
+    /// Neither the body nor the declared content-type are known, so there is
+    /// nothing to compare.
+    Unknown,
+
+    /// The body contradicts the declared content-type. Contains the detected
+    /// format, if there is one.
+    Contradicts(Option<&'static Format>),
+}
+
+macro_rules! known_format {
+    ($name:ident, $mime:literal, [$($declared_as:literal),+], $strict:literal) => {
+        static $name: Format = Format {
+            mime: $mime,
+            declared_as: &[$($declared_as),+],
+            strict: $strict,
+        };
+    };
+}
+
+known_format!(AVIF, "image/avif", ["image/avif"], true);
+known_format!(
+    BMP,
+    "image/bmp",
+    ["image/bmp", "image/x-bmp", "image/x-ms-bmp"],
+    true
+);
+known_format!(GIF, "image/gif", ["image/gif"], true);
+known_format!(
+    HEIF,
+    "image/heif",
+    [
+        "image/heif",
+        "image/heic",
+        "image/heif-sequence",
+        "image/heic-sequence"
+    ],
+    true
+);
+known_format!(
+    ICO,
+    "image/x-icon",
+    ["image/x-icon", "image/vnd.microsoft.icon"],
+    true
+);
+known_format!(
+    JPEG,
+    "image/jpeg",
+    ["image/jpeg", "image/jpg", "image/pjpeg"],
+    true
+);
+known_format!(PNG, "image/png", ["image/png", "image/apng"], true);
+known_format!(SVG, "image/svg+xml", ["image/svg+xml"], false);
+known_format!(TIFF, "image/tiff", ["image/tiff"], true);
+known_format!(WEBP, "image/webp", ["image/webp"], true);
+
+known_format!(FLAC, "audio/flac", ["audio/flac", "audio/x-flac"], true);
+known_format!(MIDI, "audio/midi", ["audio/midi", "audio/x-midi"], true);
+known_format!(
+    MPEG_AUDIO,
+    "audio/mpeg",
+    [
+        "audio/mpeg",
+        "audio/mp3",
+        "audio/x-mpeg",
+        "audio/aac",
+        "audio/aacp"
+    ],
+    false
+);
+known_format!(
+    WAV,
+    "audio/wav",
+    ["audio/wav", "audio/wave", "audio/x-wav", "audio/vnd.wave"],
+    true
+);
+
+known_format!(
+    AVI,
+    "video/x-msvideo",
+    ["video/x-msvideo", "video/avi"],
+    true
+);
+known_format!(
+    MATROSKA,
+    "video/webm",
+    [
+        "video/webm",
+        "audio/webm",
+        "video/x-matroska",
+        "audio/x-matroska"
+    ],
+    true
+);
+known_format!(
+    MP4,
+    "video/mp4",
+    [
+        "video/mp4",
+        "audio/mp4",
+        "audio/m4a",
+        "audio/x-m4a",
+        "video/x-m4v",
+        "video/3gpp",
+        "audio/3gpp",
+        "video/3gpp2"
+    ],
+    true
+);
+known_format!(
+    OGG,
+    "audio/ogg",
+    [
+        "audio/ogg",
+        "video/ogg",
+        "application/ogg",
+        "audio/opus",
+        "audio/vorbis"
+    ],
+    true
+);
+known_format!(QUICKTIME, "video/quicktime", ["video/quicktime"], true);
+
+known_format!(
+    GZIP,
+    "application/gzip",
+    ["application/gzip", "application/x-gzip"],
+    true
+);
+known_format!(HTML, "text/html", ["text/html"], false);
+known_format!(PDF, "application/pdf", ["application/pdf"], true);
+known_format!(
+    XML,
+    "application/xml",
+    [
+        "application/xml",
+        "text/xml",
+        "image/svg+xml",
+        "application/atom+xml",
+        "application/rss+xml"
+    ],
+    false
+);
+known_format!(
+    ZIP,
+    "application/zip",
+    ["application/zip", "application/x-zip-compressed"],
+    true
+);
+
+/// All known formats, used to find out whether a declared content-type has a
+/// signature.
+static FORMATS: &[&Format] = &[
+    &AVIF,
+    &BMP,
+    &GIF,
+    &HEIF,
+    &ICO,
+    &JPEG,
+    &PNG,
+    &SVG,
+    &TIFF,
+    &WEBP,
+    &FLAC,
+    &MIDI,
+    &MPEG_AUDIO,
+    &WAV,
+    &AVI,
+    &MATROSKA,
+    &MP4,
+    &OGG,
+    &QUICKTIME,
+    &GZIP,
+    &HTML,
+    &PDF,
+    &XML,
+    &ZIP,
+];
+
+/// Checks the first bytes of a body against the declared content-type.
+/// Parameters and the case of the content-type are ignored. Empty bodies
+/// always pass.
+pub fn check(content_type: &str, prefix: &[u8]) -> Verdict {
+    if prefix.is_empty() {
+        return Verdict::Unknown;
+    }
+
+    let declared = essence(content_type);
+    match detect(prefix) {
+        Some(format) if format.declared_as.contains(&declared.as_str()) => Verdict::Matches,
+        Some(format) => Verdict::Contradicts(Some(format)),
+        None => {
+            let has_signature = FORMATS
+                .iter()
+                .any(|format| format.strict && format.declared_as.contains(&declared.as_str()));
+            match has_signature {
+                true => Verdict::Contradicts(None),
+                false => Verdict::Unknown,
+            }
+        }
+    }
+}
+
+/// Detects the format of a body by its first bytes.
+pub fn detect(prefix: &[u8]) -> Option<&'static Format> {
+    let at = |offset: usize, signature: &[u8]| {
+        prefix
+            .get(offset..offset + signature.len())
+            .is_some_and(|bytes| bytes == signature)
+    };
+
+    let format = match prefix {
+        [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => &PNG,
+        [0xff, 0xd8, 0xff, ..] => &JPEG,
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => &GIF,
+        [b'R', b'I', b'F', b'F', ..] if at(8, b"WEBP") => &WEBP,
+        [b'R', b'I', b'F', b'F', ..] if at(8, b"WAVE") => &WAV,
+        [b'R', b'I', b'F', b'F', ..] if at(8, b"AVI ") => &AVI,
+        [b'B', b'M', ..] if at(6, &[0, 0, 0, 0]) => &BMP,
+        // ISO media files start with the size of the `ftyp` box, so this has
+        // to come before signatures starting with zeros.
+        _ if at(4, b"ftyp") => detect_iso_media(prefix),
+        [0, 0, 1, 0, ..] => &ICO,
+        [b'I', b'I', b'*', 0, ..] | [b'M', b'M', 0, b'*', ..] => &TIFF,
+        [0x1a, 0x45, 0xdf, 0xa3, ..] => &MATROSKA,
+        [b'O', b'g', b'g', b'S', ..] => &OGG,
+        [b'f', b'L', b'a', b'C', ..] => &FLAC,
+        [b'M', b'T', b'h', b'd', ..] => &MIDI,
+        [b'I', b'D', b'3', ..] => &MPEG_AUDIO,
+        [0xff, second, ..] if second & 0xe0 == 0xe0 => &MPEG_AUDIO,
+        [b'%', b'P', b'D', b'F', b'-', ..] => &PDF,
+        [b'P', b'K', 3, 4, ..] => &ZIP,
+        [0x1f, 0x8b, ..] => &GZIP,
+        _ => return detect_markup(prefix),
+    };
+
+    Some(format)
+}
+
+/// Detects the type of an ISO media file from the brands in its `ftyp` box.
+/// AVIFs can have a generic major brand like `mif1`, with `avif` only in the
+/// compatible brands.
+fn detect_iso_media(prefix: &[u8]) -> &'static Format {
+    let Some(size) = prefix.get(0..4) else {
+        return &MP4;
+    };
+    let ftyp_end = (u32::from_be_bytes(size.try_into().unwrap()) as usize).min(prefix.len());
+    let brands = prefix.get(8..ftyp_end).unwrap_or_default();
+    let major_brand = brands.get(..4).unwrap_or_default();
+    let is_brand = |brand: &[u8]| {
+        major_brand == brand
+            || brands
+                .get(8..)
+                .unwrap_or_default()
+                .chunks_exact(4)
+                .any(|compatible| compatible == brand)
+    };
+
+    if is_brand(b"avif") || is_brand(b"avis") {
+        return &AVIF;
+    }
+    match major_brand {
+        b"heic" | b"heix" | b"hevc" | b"heim" | b"heis" | b"mif1" | b"msf1" => &HEIF,
+        b"qt  " => &QUICKTIME,
+        _ => &MP4,
+    }
+}
+
+/// Detects HTML, SVG, and other XML documents, which can start with a byte
+/// order mark and whitespace.
+fn detect_markup(prefix: &[u8]) -> Option<&'static Format> {
+    let prefix = prefix.strip_prefix(b"\xef\xbb\xbf").unwrap_or(prefix);
+    let start = prefix.iter().position(|byte| !byte.is_ascii_whitespace())?;
+    let markup = &prefix[start..];
+    let starts_with_tag = |tag: &[u8]| {
+        markup
+            .get(..tag.len())
+            .is_some_and(|bytes| bytes.eq_ignore_ascii_case(tag))
+            && markup
+                .get(tag.len())
+                .is_some_and(|byte| byte.is_ascii_whitespace() || *byte == b'>')
+    };
+
+    if starts_with_tag(b"<svg") || starts_with_tag(b"<!doctype svg") {
+        Some(&SVG)
+    } else if starts_with_tag(b"<?xml") {
+        Some(&XML)
+    } else if [
+        b"<!doctype html".as_slice(),
+        b"<html",
+        b"<head",
+        b"<body",
+        b"<script",
+        b"<iframe",
+    ]
+    .iter()
+    .any(|tag| starts_with_tag(tag))
+    {
+        Some(&HTML)
+    } else {
+        None
+    }
+}
+
+/// Content-types that don't say anything about the body, and are sent by
+/// servers that don't know better.
+const GENERIC_CONTENT_TYPES: &[&str] = &[
+    "",
+    "application/binary",
+    "application/download",
+    "application/force-download",
+    "application/octet-stream",
+    "application/unknown",
+    "application/x-download",
+    "application/x-unknown",
+    "binary/octet-stream",
+    "unknown/unknown",
+];
+
+/// MIME types for common media file extensions.
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("aac", "audio/aac"),
+    ("apng", "image/apng"),
+    ("avi", "video/x-msvideo"),
+    ("avif", "image/avif"),
+    ("bmp", "image/bmp"),
+    ("flac", "audio/flac"),
+    ("gif", "image/gif"),
+    ("heic", "image/heic"),
+    ("heif", "image/heif"),
+    ("ico", "image/x-icon"),
+    ("jfif", "image/jpeg"),
+    ("jpe", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("jpg", "image/jpeg"),
+    ("m4a", "audio/mp4"),
+    ("m4v", "video/mp4"),
+    ("mid", "audio/midi"),
+    ("midi", "audio/midi"),
+    ("mkv", "video/x-matroska"),
+    ("mov", "video/quicktime"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mp4"),
+    ("oga", "audio/ogg"),
+    ("ogg", "audio/ogg"),
+    ("ogv", "video/ogg"),
+    ("opus", "audio/opus"),
+    ("png", "image/png"),
+    ("svg", "image/svg+xml"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    ("wav", "audio/wav"),
+    ("weba", "audio/webm"),
+    ("webm", "video/webm"),
+    ("webp", "image/webp"),
+];
+
+/// Returns whether a content-type is missing or too generic to be useful, like
+/// `application/octet-stream`.
+pub fn is_generic(content_type: Option<&str>) -> bool {
+    content_type
+        .is_none_or(|content_type| GENERIC_CONTENT_TYPES.contains(&essence(content_type).as_str()))
+}
+
+/// Returns whether a content-type is `image/svg+xml`.
+pub fn is_svg(content_type: &str) -> bool {
+    essence(content_type) == SVG.mime
+}
+
+/// Guesses the MIME type from the file extension of a URL's path. Query
+/// strings and fragments are ignored.
+pub fn guess_from_url(url: &str) -> Option<&'static str> {
+    let url = url::Url::parse(url).ok()?;
+    let file_name = url.path_segments()?.next_back()?;
+    let (_, extension) = file_name.rsplit_once('.')?;
+    let extension = extension.to_ascii_lowercase();
+
+    EXTENSIONS
+        .iter()
+        .find(|(known, _)| *known == extension)
+        .map(|(_, mime)| *mime)
+}
+
+/// Returns the lowercase `type/subtype` of a content-type, without parameters.
+pub(crate) fn essence(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
@@ -0,0 +1,265 @@
+//! Makes SVGs safe to pass to clients, either by removing everything that can
+//! run scripts or load external resources, or by rendering them to PNGs.
+
+use std::fmt::Write;
+
+use resvg::{tiny_skia, usvg};
+use roxmltree::{Document, Node, NodeType, ParsingOptions};
+
+use crate::errors::CamoError;
+
+const SVG_NAMESPACE: &str = "http://www.w3.org/2000/svg";
+const XLINK_NAMESPACE: &str = "http://www.w3.org/1999/xlink";
+const XML_NAMESPACE: &str = "http://www.w3.org/XML/1998/namespace";
+
+/// The maximum number of nodes in an SVG, to limit the work spent on a
+/// single document.
+const MAX_NODES: u32 = 100_000;
+
+/// The maximum width and height of a rasterized SVG. Larger SVGs are scaled
+/// down to fit.
+const MAX_RASTER_SIZE: f32 = 4096.0;
+
+/// Elements that are kept, together with their children. Everything else is
+/// removed, including `script`, `foreignObject`, and animations, which can
+/// change attributes after sanitization.
+const ALLOWED_ELEMENTS: &[&str] = &[
+    "circle",
+    "clipPath",
+    "defs",
+    "desc",
+    "ellipse",
+    "feBlend",
+    "feColorMatrix",
+    "feComponentTransfer",
+    "feComposite",
+    "feConvolveMatrix",
+    "feDiffuseLighting",
+    "feDisplacementMap",
+    "feDistantLight",
+    "feDropShadow",
+    "feFlood",
+    "feFuncA",
+    "feFuncB",
+    "feFuncG",
+    "feFuncR",
+    "feGaussianBlur",
+    "feImage",
+    "feMerge",
+    "feMergeNode",
+    "feMorphology",
+    "feOffset",
+    "fePointLight",
+    "feSpecularLighting",
+    "feSpotLight",
+    "feTile",
+    "feTurbulence",
+    "filter",
+    "g",
+    "image",
+    "line",
+    "linearGradient",
+    "marker",
+    "mask",
+    "path",
+    "pattern",
+    "polygon",
+    "polyline",
+    "radialGradient",
+    "rect",
+    "stop",
+    "style",
+    "svg",
+    "switch",
+    "symbol",
+    "text",
+    "textPath",
+    "title",
+    "tspan",
+    "use",
+];
+
+/// Elements that are removed, but whose children are kept.
+const UNWRAPPED_ELEMENTS: &[&str] = &["a"];
+
+/// Data URLs that may be embedded in `image` and `feImage` elements.
+const ALLOWED_DATA_URLS: &[&str] = &[
+    "data:image/gif",
+    "data:image/jpeg",
+    "data:image/png",
+    "data:image/webp",
+];
+
+/// Removes everything from an SVG that can run scripts or load external
+/// resources: scripts, event handlers, `foreignObject`, animations, links to
+/// anything but elements in the same document, and stylesheets that import
+/// or reference anything else. Comments, processing instructions, and
+/// unknown elements and attributes are removed as well. Fails if the SVG
+/// can't be parsed, or has a document type declaration.
+pub fn sanitize(svg: &[u8]) -> Result<Vec<u8>, CamoError> {
+    let svg = std::str::from_utf8(svg).map_err(|_| invalid("not valid UTF-8"))?;
+    let options = ParsingOptions {
+        allow_dtd: false,
+        nodes_limit: MAX_NODES,
+    };
+    let document =
+        Document::parse_with_options(svg, options).map_err(|err| invalid(&err.to_string()))?;
+
+    let root = document.root_element();
+    if root.tag_name().namespace() != Some(SVG_NAMESPACE) || root.tag_name().name() != "svg" {
+        return Err(invalid("root element is not an SVG"));
+    }
+
+    let mut output = String::with_capacity(svg.len());
+    write_element(&mut output, root, true);
+    Ok(output.into_bytes())
+}
+
+/// Sanitizes an SVG, and renders it to a PNG. Text is not rendered, as no
+/// fonts are available, and neither are embedded raster images.
+pub fn rasterize(svg: &[u8]) -> Result<Vec<u8>, CamoError> {
+    let svg = sanitize(svg)?;
+
+    let mut options = usvg::Options::default();
+    options.image_href_resolver.resolve_string = Box::new(|_, _| None);
+    let tree = usvg::Tree::from_data(&svg, &options).map_err(|err| invalid(&err.to_string()))?;
+
+    let size = tree.size();
+    let scale = (MAX_RASTER_SIZE / size.width().max(size.height())).min(1.0);
+    let width = (size.width() * scale).ceil() as u32;
+    let height = (size.height() * scale).ceil() as u32;
+    let mut pixmap =
+        tiny_skia::Pixmap::new(width, height).ok_or_else(|| invalid("size is invalid"))?;
+
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+    pixmap.encode_png().map_err(|err| invalid(&err.to_string()))
+}
+
+fn invalid(reason: &str) -> CamoError {
+    CamoError::SvgUnprocessable(reason.to_owned())
+}
+
+/// Writes an allowed element with its allowed attributes and children. The
+/// root element also gets the namespace declarations.
+fn write_element(output: &mut String, element: Node, is_root: bool) {
+    let name = element.tag_name().name();
+    let attributes = element
+        .attributes()
+        .filter_map(|attribute| {
+            let value = attribute.value();
+            let name = match attribute.namespace() {
+                None if is_event_handler(attribute.name()) => return None,
+                None if attribute.name() == "href" => {
+                    return is_safe_href(name, value).then(|| ("href".to_owned(), value));
+                }
+                None if attribute.name() == "style" || may_reference(value) => {
+                    return is_safe_css(value).then(|| (attribute.name().to_owned(), value));
+                }
+                None => attribute.name().to_owned(),
+                Some(XLINK_NAMESPACE) if attribute.name() == "href" => {
+                    return is_safe_href(name, value).then(|| ("xlink:href".to_owned(), value));
+                }
+                Some(XML_NAMESPACE) if matches!(attribute.name(), "lang" | "space") => {
+                    format!("xml:{}", attribute.name())
+                }
+                Some(_) => return None,
+            };
+            Some((name, value))
+        })
+        .collect::<Vec<_>>();
+
+    let _ = write!(output, "<{name}");
+    if is_root {
+        let _ = write!(
+            output,
+            " xmlns=\"{SVG_NAMESPACE}\" xmlns:xlink=\"{XLINK_NAMESPACE}\""
+        );
+    }
+    for (name, value) in attributes {
+        let _ = write!(output, " {name}=\"{}\"", escape(value));
+    }
+    output.push('>');
+    write_children(output, element);
+    let _ = write!(output, "</{name}>");
+}
+
+fn write_children(output: &mut String, parent: Node) {
+    for child in parent.children() {
+        let is_svg = child.tag_name().namespace() == Some(SVG_NAMESPACE);
+        let name = child.tag_name().name();
+        match child.node_type() {
+            NodeType::Element if !is_svg => {}
+            NodeType::Element if name == "style" && !is_safe_css(&text_content(child)) => {}
+            NodeType::Element if ALLOWED_ELEMENTS.contains(&name) => {
+                write_element(output, child, false);
+            }
+            NodeType::Element if UNWRAPPED_ELEMENTS.contains(&name) => {
+                write_children(output, child);
+            }
+            NodeType::Text => output.push_str(&escape(child.text().unwrap_or_default())),
+            _ => {}
+        }
+    }
+}
+
+fn text_content(element: Node) -> String {
+    element
+        .descendants()
+        .filter_map(|node| node.is_text().then(|| node.text()).flatten())
+        .collect()
+}
+
+/// Returns whether an attribute value may contain a CSS reference, like
+/// `fill="url(#gradient)"`.
+fn may_reference(value: &str) -> bool {
+    value.contains('\\') || value.to_ascii_lowercase().contains("url(")
+}
+
+fn is_event_handler(name: &str) -> bool {
+    name.get(..2)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("on"))
+}
+
+/// Links may only point to elements in the same document. Images may also
+/// embed raster images as data URLs.
+fn is_safe_href(element: &str, href: &str) -> bool {
+    let href = href.trim();
+    let is_image = matches!(element, "image" | "feImage");
+    href.starts_with('#')
+        || (is_image
+            && ALLOWED_DATA_URLS.iter().any(|prefix| {
+                href.get(..prefix.len())
+                    .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+            }))
+}
+
+/// CSS may not import other stylesheets, and may only reference elements in
+/// the same document. Escapes are rejected, as they could hide both.
+fn is_safe_css(css: &str) -> bool {
+    let css = css.to_ascii_lowercase();
+    if css.contains('\\')
+        || css.contains("@import")
+        || css.contains("expression(")
+        || css.contains("javascript:")
+    {
+        return false;
+    }
+
+    css.split("url(").skip(1).all(|reference| {
+        reference
+            .trim_start()
+            .trim_start_matches(['"', '\''])
+            .starts_with('#')
+    })
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,102 @@
+//! Builds the rustls configuration used for connections to the upstream.
+
+use std::path::Path;
+
+use rustls::{
+    ClientConfig, RootCertStore,
+    pki_types::{
+        CertificateDer, PrivateKeyDer,
+        pem::{self, PemObject},
+    },
+    version::{TLS12, TLS13},
+};
+
+use crate::{
+    Settings,
+    errors::SetupError,
+    settings::{TlsRoots, TlsVersion},
+};
+
+/// Builds a rustls `ClientConfig` based on the TLS-related settings.
+///
+/// If `with_client_cert` is set, the configured client certificate will be
+/// presented to upstreams that ask for it. This fails if no root certificates
+/// could be found at all, as every HTTPS request would fail in that case.
+pub fn build_client_config(
+    settings: &Settings,
+    with_client_cert: bool,
+) -> Result<ClientConfig, SetupError> {
+    let versions = match settings.upstream_tls_min_version {
+        TlsVersion::Tls12 => vec![&TLS13, &TLS12],
+        TlsVersion::Tls13 => vec![&TLS13],
+    };
+
+    let builder = ClientConfig::builder_with_protocol_versions(&versions)
+        .with_root_certificates(build_root_store(settings)?);
+
+    match (
+        with_client_cert,
+        &settings.upstream_client_cert,
+        &settings.upstream_client_key,
+    ) {
+        (true, Some(cert_path), Some(key_path)) => {
+            let cert_chain = read_certificates(cert_path)
+                .map_err(|err| SetupError::ClientCertUnreadable(cert_path.to_owned(), err))?;
+            let key = PrivateKeyDer::from_pem_file(key_path)
+                .map_err(|err| SetupError::ClientKeyUnreadable(key_path.to_owned(), err))?;
+
+            builder
+                .with_client_auth_cert(cert_chain, key)
+                .map_err(SetupError::TlsConfigInvalid)
+        }
+        _ => Ok(builder.with_no_client_auth()),
+    }
+}
+
+/// Collects all trusted root certificates, including the additional CA
+/// bundle, if there is one.
+fn build_root_store(settings: &Settings) -> Result<RootCertStore, SetupError> {
+    let mut roots = RootCertStore::empty();
+
+    if matches!(
+        settings.upstream_tls_roots,
+        TlsRoots::Native | TlsRoots::Both
+    ) {
+        // Unreadable certificates in the system store are skipped, just as
+        // hyper-rustls does. An empty store is caught below.
+        let native = rustls_native_certs::load_native_certs();
+        roots.add_parsable_certificates(native.certs);
+    }
+
+    if matches!(
+        settings.upstream_tls_roots,
+        TlsRoots::Webpki | TlsRoots::Both
+    ) {
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    }
+
+    if let Some(ca_bundle) = &settings.upstream_ca_bundle {
+        let certs = read_certificates(ca_bundle)
+            .map_err(|err| SetupError::CaBundleUnreadable(ca_bundle.to_owned(), err))?;
+        for cert in certs {
+            roots.add(cert).map_err(SetupError::TlsConfigInvalid)?;
+        }
+    }
+
+    if roots.is_empty() {
+        return Err(SetupError::NoTlsRoots);
+    }
+
+    Ok(roots)
+}
+
+/// Reads all certificates from a PEM file. Files without any certificate are
+/// treated as an error, as they are most likely a misconfiguration.
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, pem::Error> {
+    let certs = CertificateDer::pem_file_iter(path)?.collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(pem::Error::NoItemsFound);
+    }
+
+    Ok(certs)
+}
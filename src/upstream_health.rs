@@ -0,0 +1,256 @@
+//! Tracks the health of upstream hosts, limits the number of concurrent
+//! requests per host, and implements a circuit breaker that stops sending
+//! requests to hosts that keep failing.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicI64, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+use tracing::warn;
+
+use crate::{Settings, body::BodyObserver, errors::ProxyError, metrics::Metrics};
+
+/// Hosts without in-flight requests and without recent failures are dropped
+/// once more than this many hosts are tracked.
+const MAX_IDLE_HOSTS: usize = 10_000;
+
+// Hosts are user-controlled, so they are only logged, and not used as labels.
+const CIRCUITS_OPEN_METRIC: &str = "camo_upstream_circuits_open";
+const CIRCUITS_OPEN_HELP: &str = "Number of upstream hosts whose circuit breaker is not closed.";
+const CIRCUIT_TRANSITIONS_METRIC: &str = "camo_upstream_circuit_transitions_total";
+const CIRCUIT_TRANSITIONS_HELP: &str = "Number of circuit breaker state changes.";
+
+/// The state of a host's circuit breaker.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CircuitState {
+    /// Requests pass through.
+    Closed,
+
+    /// Requests fail fast until the cooldown is over. After that, a single
+    /// probe request is let through, and the circuit is half-open.
+    Open { until: Instant },
+
+    /// A probe request was let through, and requests fail fast until the
+    /// cooldown is over again. If the probe succeeds, the circuit closes. If
+    /// it fails, the circuit opens again. If it never reports back, the next
+    /// probe happens after the cooldown.
+    HalfOpen { until: Instant },
+}
+
+impl CircuitState {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Closed => "closed",
+            Self::Open { .. } => "open",
+            Self::HalfOpen { .. } => "half_open",
+        }
+    }
+}
+
+/// Everything camo-rs knows about a single upstream host.
+struct HostState {
+    concurrency: Option<Arc<Semaphore>>,
+    breaker: Mutex<Breaker>,
+}
+
+struct Breaker {
+    state: CircuitState,
+    consecutive_failures: usize,
+}
+
+/// Keeps track of all upstream hosts.
+#[derive(Clone)]
+pub struct HostRegistry {
+    hosts: Arc<Mutex<HashMap<String, Arc<HostState>>>>,
+    max_concurrency: usize,
+    failure_threshold: usize,
+    cooldown: Duration,
+    open_circuits: Arc<AtomicI64>,
+    metrics: Metrics,
+}
+
+/// Permission to send a request to an upstream host. The outcome of the
+/// request has to be reported back via `record_success` or `record_failure`.
+/// Failures while streaming the body are reported automatically if the lease
+/// is used as the body's `BodyObserver`. The concurrency slot is released once
+/// the lease is dropped.
+pub struct HostLease {
+    host: String,
+    state: Arc<HostState>,
+    registry: HostRegistry,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl HostRegistry {
+    /// Creates a new registry based on the settings. Both the concurrency
+    /// limit and the circuit breaker are disabled if set to `0`.
+    pub fn new(settings: &Settings, metrics: Metrics) -> Self {
+        Self {
+            hosts: Arc::new(Mutex::new(HashMap::new())),
+            max_concurrency: settings.upstream_host_concurrency,
+            failure_threshold: settings.upstream_circuit_breaker_threshold,
+            cooldown: Duration::from_secs(settings.upstream_circuit_breaker_cooldown as u64),
+            open_circuits: Arc::new(AtomicI64::new(0)),
+            metrics,
+        }
+    }
+
+    /// Asks for permission to send a request to `host`. Fails immediately if
+    /// the host's circuit is open, and waits until `deadline` for a free
+    /// concurrency slot.
+    pub async fn acquire(&self, host: &str, deadline: Instant) -> Result<HostLease, ProxyError> {
+        let state = self.host_state(host);
+
+        {
+            let mut breaker = state.breaker.lock().expect("lock is never poisoned");
+            match breaker.state {
+                CircuitState::Closed => {}
+                CircuitState::Open { until } | CircuitState::HalfOpen { until }
+                    if Instant::now() >= until =>
+                {
+                    let from = breaker.state;
+                    breaker.state = CircuitState::HalfOpen {
+                        until: Instant::now() + self.cooldown,
+                    };
+                    self.record_transition(from, breaker.state);
+                }
+                CircuitState::Open { .. } | CircuitState::HalfOpen { .. } => {
+                    return Err(ProxyError::CircuitOpen(host.to_owned()));
+                }
+            }
+        }
+
+        let permit = match &state.concurrency {
+            Some(semaphore) => Some(
+                tokio::time::timeout_at(deadline, semaphore.clone().acquire_owned())
+                    .await
+                    .map_err(|_| ProxyError::HostConcurrencyExceeded(host.to_owned()))?
+                    .expect("semaphores are never closed"),
+            ),
+            None => None,
+        };
+
+        Ok(HostLease {
+            host: host.to_owned(),
+            state,
+            registry: self.clone(),
+            _permit: permit,
+        })
+    }
+
+    /// Returns the state for a host, and creates it if needed.
+    fn host_state(&self, host: &str) -> Arc<HostState> {
+        let mut hosts = self.hosts.lock().expect("lock is never poisoned");
+        if let Some(state) = hosts.get(host) {
+            return state.clone();
+        }
+
+        if hosts.len() >= MAX_IDLE_HOSTS {
+            hosts.retain(|_, state| Arc::strong_count(state) > 1 || !state.is_idle());
+        }
+
+        let state = Arc::new(HostState {
+            concurrency: (self.max_concurrency > 0)
+                .then(|| Arc::new(Semaphore::new(self.max_concurrency))),
+            breaker: Mutex::new(Breaker {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+            }),
+        });
+        hosts.insert(host.to_owned(), state.clone());
+        state
+    }
+
+    /// Records a change of a circuit's state. Calls where the state doesn't
+    /// actually change, like probes after a probe that never reported back,
+    /// are ignored.
+    fn record_transition(&self, from: CircuitState, to: CircuitState) {
+        if from.name() == to.name() {
+            return;
+        }
+
+        self.metrics.increment(
+            CIRCUIT_TRANSITIONS_METRIC,
+            CIRCUIT_TRANSITIONS_HELP,
+            &[("state", to.name())],
+        );
+
+        let delta = match (from, to) {
+            (CircuitState::Closed, _) => 1,
+            (_, CircuitState::Closed) => -1,
+            _ => 0,
+        };
+        let open_circuits = self.open_circuits.fetch_add(delta, Ordering::Relaxed) + delta;
+        self.metrics
+            .set(CIRCUITS_OPEN_METRIC, CIRCUITS_OPEN_HELP, &[], open_circuits);
+    }
+}
+
+impl HostState {
+    /// Returns whether there is nothing worth remembering about this host.
+    fn is_idle(&self) -> bool {
+        let breaker = self.breaker.lock().expect("lock is never poisoned");
+        breaker.state == CircuitState::Closed && breaker.consecutive_failures == 0
+    }
+}
+
+impl HostLease {
+    /// Reports a successful request, which closes the circuit.
+    pub fn record_success(&self) {
+        let mut breaker = self.state.breaker.lock().expect("lock is never poisoned");
+        breaker.consecutive_failures = 0;
+        if breaker.state != CircuitState::Closed {
+            let from = breaker.state;
+            breaker.state = CircuitState::Closed;
+            warn!("circuit for upstream host {} closed", self.host);
+            self.registry.record_transition(from, breaker.state);
+        }
+    }
+
+    /// Reports a failed request. Opens the circuit if the failure threshold
+    /// is reached, or restarts the cooldown if the circuit is already open.
+    /// Failures while the circuit is half-open open it again.
+    pub fn record_failure(&self) {
+        let registry = &self.registry;
+        if registry.failure_threshold == 0 {
+            return;
+        }
+
+        let mut breaker = self.state.breaker.lock().expect("lock is never poisoned");
+        breaker.consecutive_failures += 1;
+
+        if breaker.consecutive_failures >= registry.failure_threshold {
+            let from = breaker.state;
+            breaker.state = CircuitState::Open {
+                until: Instant::now() + registry.cooldown,
+            };
+            match from {
+                CircuitState::Closed => warn!(
+                    "circuit for upstream host {} opened after {} consecutive failures",
+                    self.host, breaker.consecutive_failures
+                ),
+                CircuitState::HalfOpen { .. } => warn!(
+                    "circuit for upstream host {} opened again after a failed probe",
+                    self.host
+                ),
+                CircuitState::Open { .. } => {}
+            }
+            registry.record_transition(from, breaker.state);
+        }
+    }
+}
+
+impl BodyObserver for HostLease {
+    fn on_error(&mut self) {
+        self.record_failure();
+    }
+}
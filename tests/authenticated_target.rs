@@ -1,4 +1,7 @@
-use camo_rs::authenticated_target::*;
+use camo_rs::{
+    authenticated_target::*,
+    image_transform::{Fit, ImageTransform},
+};
 
 // The encoded values were generated and validated using the original Camo
 // to make sure that this implementation is frontend-compatible.
@@ -89,3 +92,105 @@ fn from_target_generates_valid_url() {
 
     assert_eq!(target.encoded_full_path(), expected);
 }
+
+#[test]
+fn validate_accepts_signed_transforms() {
+    let transform = ImageTransform {
+        width: Some(64),
+        height: Some(32),
+        fit: Fit::Cover,
+        quality: Some(80),
+    };
+    let signed =
+        AuthenticatedTarget::from_target_with_transform(VALID_KEY, VALID_TARGET, transform);
+    let (path, query) = signed
+        .encoded_full_path()
+        .split_once('?')
+        .map(|(path, query)| (path.to_owned(), query.to_owned()))
+        .unwrap();
+    let (digest, target) = path.split_once('/').unwrap();
+
+    let result = AuthenticatedTarget::from_encoded_strings(VALID_KEY, digest, target)
+        .unwrap()
+        .with_encoded_transform(&query)
+        .unwrap()
+        .validated_target_url();
+
+    assert_eq!(query, "w=64&h=32&fit=cover&q=80");
+    assert!(result.is_ok());
+}
+
+#[test]
+fn validate_rejects_unsigned_transforms() {
+    let result = AuthenticatedTarget::from_encoded_strings(
+        VALID_KEY,
+        VALID_ENCODED_DIGEST,
+        VALID_ENCODED_TARGET,
+    )
+    .unwrap()
+    .with_encoded_transform("w=64")
+    .unwrap()
+    .validated_target_url();
+
+    assert!(result.is_err());
+}
+
+#[test]
+fn validate_rejects_changed_transforms() {
+    let transform = ImageTransform {
+        width: Some(64),
+        ..Default::default()
+    };
+    let signed =
+        AuthenticatedTarget::from_target_with_transform(VALID_KEY, VALID_TARGET, transform);
+
+    let result = AuthenticatedTarget::from_encoded_strings(
+        VALID_KEY,
+        &signed.encoded_digest(),
+        VALID_ENCODED_TARGET,
+    )
+    .unwrap()
+    .with_encoded_transform("w=2048")
+    .unwrap()
+    .validated_target_url();
+
+    assert!(result.is_err());
+}
+
+#[test]
+fn with_encoded_transform_ignores_empty_query() {
+    let target = AuthenticatedTarget::from_encoded_strings(
+        VALID_KEY,
+        VALID_ENCODED_DIGEST,
+        VALID_ENCODED_TARGET,
+    )
+    .unwrap()
+    .with_encoded_transform("")
+    .unwrap();
+
+    assert!(target.transform().is_none());
+    assert!(target.validated_target_url().is_ok());
+}
+
+#[test]
+fn with_encoded_transform_rejects_invalid_parameters() {
+    for query in [
+        "x=1",
+        "w=0",
+        "w=abc",
+        "w=1&w=2",
+        "fit=squash",
+        "q=0",
+        "q=101",
+    ] {
+        let result = AuthenticatedTarget::from_encoded_strings(
+            VALID_KEY,
+            VALID_ENCODED_DIGEST,
+            VALID_ENCODED_TARGET,
+        )
+        .unwrap()
+        .with_encoded_transform(query);
+
+        assert!(result.is_err(), "{query} was accepted");
+    }
+}
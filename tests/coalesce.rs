@@ -0,0 +1,22 @@
+use camo_rs::{coalesce::Coalescer, errors::CamoError, header_wrangler::HeaderPolicy};
+use hyper::{Method, StatusCode};
+
+#[tokio::test]
+async fn fails_waiting_requests_if_the_fetch_panics() {
+    let coalescer = Coalescer::new(HeaderPolicy::default());
+    let key = coalescer.key(&Method::GET, "http://example.com/", &Default::default());
+
+    let res = coalescer
+        .run(key.clone(), async { panic!("fetch panicked") })
+        .await;
+    assert!(matches!(
+        res,
+        Err(CamoError::Coalesced(err)) if matches!(*err, CamoError::CoalescedFetchAborted)
+    ));
+
+    let res = coalescer
+        .run(key, async { Ok(Default::default()) })
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+}
@@ -1,6 +1,9 @@
 use camo_rs::header_wrangler::*;
 use hyper::{HeaderMap, header};
 
+pub mod helpers;
+use helpers::application::*;
+
 #[test]
 fn assign_filtered_request_headers_does_work() {
     let mut original_headers = HeaderMap::new();
@@ -9,14 +12,174 @@ fn assign_filtered_request_headers_does_work() {
     original_headers.append(header::USER_AGENT, "example".parse().unwrap());
 
     let mut new_headers = HeaderMap::new();
-    assign_filtered_request_headers(&original_headers, &mut new_headers);
+    HeaderPolicy::default().assign_filtered_request_headers(&original_headers, &mut new_headers);
+    assert_eq!(new_headers.len(), 0);
+}
+
+#[test]
+fn assign_filtered_request_headers_passes_single_ranges() {
+    let mut original_headers = HeaderMap::new();
+    original_headers.append(header::RANGE, "bytes=0-1023".parse().unwrap());
+    original_headers.append(header::IF_RANGE, "\"etag\"".parse().unwrap());
+
+    let mut new_headers = HeaderMap::new();
+    HeaderPolicy::default().assign_filtered_request_headers(&original_headers, &mut new_headers);
+    assert_eq!(new_headers.len(), 2);
+}
+
+#[test]
+fn assign_filtered_request_headers_drops_multiple_ranges() {
+    let mut original_headers = HeaderMap::new();
+    original_headers.append(header::RANGE, "bytes=0-10, 20-30".parse().unwrap());
+
+    let mut new_headers = HeaderMap::new();
+    HeaderPolicy::default().assign_filtered_request_headers(&original_headers, &mut new_headers);
     assert_eq!(new_headers.len(), 0);
 }
 
+#[test]
+fn parse_content_range_does_work() {
+    assert_eq!(
+        parse_content_range("bytes 0-1023/4096"),
+        Some(ContentRange {
+            first_byte: 0,
+            last_byte: 1023,
+            complete_length: Some(4096),
+        })
+    );
+    assert_eq!(
+        parse_content_range("bytes 10-19/*").map(|range| range.range_length()),
+        Some(10)
+    );
+}
+
+#[test]
+fn parse_content_range_rejects_invalid_ranges() {
+    assert_eq!(parse_content_range("bytes */4096"), None);
+    assert_eq!(parse_content_range("bytes 20-10/4096"), None);
+    assert_eq!(parse_content_range("bytes 0-4096/4096"), None);
+    assert_eq!(parse_content_range("items 0-10/20"), None);
+}
+
+#[test]
+fn header_policy_passes_and_strips_configured_headers() {
+    let mut settings = get_test_settings();
+    settings.pass_request_headers = vec!["sec-fetch-dest".parse().unwrap()];
+    settings.strip_request_headers = vec![header::ACCEPT_LANGUAGE];
+    let policy = HeaderPolicy::new(&settings);
+
+    let mut original_headers = HeaderMap::new();
+    original_headers.append(header::ACCEPT_LANGUAGE, "de".parse().unwrap());
+    original_headers.append("sec-fetch-dest", "image".parse().unwrap());
+
+    let mut new_headers = HeaderMap::new();
+    policy.assign_filtered_request_headers(&original_headers, &mut new_headers);
+    assert_eq!(new_headers.len(), 1);
+    assert_eq!(new_headers.get("sec-fetch-dest").unwrap(), "image");
+}
+
+#[test]
+fn header_policy_assigns_upstream_headers_per_host() {
+    let mut settings = get_test_settings();
+    settings.upstream_headers = vec![
+        "* Accept: image/*".parse().unwrap(),
+        "cdn.example.com Accept: image/webp".parse().unwrap(),
+        "cdn.example.com X-Token: secret".parse().unwrap(),
+    ];
+    let policy = HeaderPolicy::new(&settings);
+
+    let mut headers = HeaderMap::new();
+    policy.assign_upstream_headers("example.com", &mut headers);
+    assert_eq!(headers.len(), 1);
+    assert_eq!(headers.get(header::ACCEPT).unwrap(), "image/*");
+
+    let mut headers = HeaderMap::new();
+    policy.assign_upstream_headers("CDN.example.com", &mut headers);
+    assert_eq!(headers.len(), 2);
+    assert_eq!(headers.get(header::ACCEPT).unwrap(), "image/webp");
+    assert_eq!(headers.get("x-token").unwrap(), "secret");
+}
+
+#[test]
+fn header_policy_filters_response_headers() {
+    let mut headers = HeaderMap::new();
+    headers.append(header::CONTENT_TYPE, "image/png".parse().unwrap());
+    headers.append(header::SET_COOKIE, "session=1".parse().unwrap());
+    headers.append(
+        header::STRICT_TRANSPORT_SECURITY,
+        "max-age=1".parse().unwrap(),
+    );
+
+    HeaderPolicy::default().filter_response_headers(&mut headers);
+    assert_eq!(headers.len(), 1);
+    assert!(headers.contains_key(header::CONTENT_TYPE));
+}
+
+#[test]
+fn force_secure_response_headers_uses_configured_headers() {
+    let mut settings = get_test_settings();
+    settings.security_headers = vec![
+        "Content-Security-Policy: default-src 'none'"
+            .parse()
+            .unwrap(),
+        "Referrer-Policy: no-referrer".parse().unwrap(),
+    ];
+    settings.strip_security_headers = vec![header::X_XSS_PROTECTION];
+    let policy = HeaderPolicy::new(&settings);
+
+    let mut headers = HeaderMap::new();
+    policy.force_secure_response_headers(&mut headers);
+    assert_eq!(headers.len(), SECURE_RESPONSE_HEADERS.len());
+    assert_eq!(
+        headers.get(header::CONTENT_SECURITY_POLICY).unwrap(),
+        "default-src 'none'"
+    );
+    assert_eq!(headers.get(header::REFERRER_POLICY).unwrap(), "no-referrer");
+    assert!(headers.get(header::X_XSS_PROTECTION).is_none());
+}
+
+#[test]
+fn assign_cors_headers_replaces_upstream_cors_headers() {
+    let mut settings = get_test_settings();
+    settings.cors_allow_origins = vec!["*".to_owned()];
+    let policy = HeaderPolicy::new(&settings);
+
+    let mut headers = HeaderMap::new();
+    headers.append(
+        header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        "https://upstream.example.com".parse().unwrap(),
+    );
+    headers.append(
+        header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+        "true".parse().unwrap(),
+    );
+
+    policy.assign_cors_headers(None, &mut headers);
+    assert_eq!(
+        headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+        "*"
+    );
+    assert!(
+        headers
+            .get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS)
+            .is_none()
+    );
+    assert!(headers.get(header::VARY).is_none());
+}
+
+#[test]
+fn assign_cors_headers_does_nothing_if_disabled() {
+    let mut headers = HeaderMap::new();
+    headers.append(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*".parse().unwrap());
+
+    HeaderPolicy::default().assign_cors_headers(None, &mut headers);
+    assert!(headers.is_empty());
+}
+
 #[test]
 fn force_secure_response_headers_does_work() {
     let mut headers = HeaderMap::new();
-    force_secure_response_headers(&mut headers);
+    HeaderPolicy::default().force_secure_response_headers(&mut headers);
     assert_eq!(headers.len(), SECURE_RESPONSE_HEADERS.len());
 }
 
@@ -25,6 +188,6 @@ fn force_secure_response_headers_does_not_override_existing() {
     let mut headers = HeaderMap::new();
     headers.append(header::CONTENT_LENGTH, "4242".parse().unwrap());
 
-    force_secure_response_headers(&mut headers);
+    HeaderPolicy::default().force_secure_response_headers(&mut headers);
     assert_eq!(headers.len(), SECURE_RESPONSE_HEADERS.len() + 1);
 }
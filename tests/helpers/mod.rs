@@ -13,12 +13,77 @@ pub mod application {
             allow_image: true,
             allow_video: false,
             allow_all_types: false,
+            allow_types: vec![],
+            coalesce_requests: false,
+            content_sniffing: camo_rs::settings::ContentSniffing::Off,
+            cors_allow_origins: vec![],
+            cors_max_age: 86400,
+            deny_types: vec![],
             header_via: "camo-rs".to_owned(),
+            hotlink_allow_empty_referer: false,
+            hotlink_allow_hosts: vec![],
+            image_max_frames: 0,
+            image_max_height: 0,
+            image_max_pixels: 0,
+            image_max_width: 0,
+            image_transforms: false,
+            in_flight_limit: 0,
+            infer_content_type: false,
+            infer_content_type_from_url: false,
             key: "camo-rs".to_owned(),
+            upstream_bind_ipv4: None,
+            upstream_bind_ipv6: None,
+            upstream_ca_bundle: None,
+            upstream_circuit_breaker_cooldown: 30,
+            upstream_circuit_breaker_threshold: 0,
+            upstream_client_cert: None,
+            upstream_client_cert_hosts: vec![],
+            upstream_client_key: None,
+            upstream_connect_timeout: 10,
+            upstream_headers: vec![],
+            upstream_host_concurrency: 0,
+            upstream_idle_timeout: 10,
+            upstream_ip_family: camo_rs::settings::IpFamily::Any,
+            upstream_min_rate: 0,
+            upstream_min_rate_window: 10,
+            upstream_retries: 0,
+            upstream_retry_backoff: 10,
+            upstream_retry_max_backoff: 100,
+            upstream_retry_on: vec![
+                camo_rs::settings::RetryOn::Connect,
+                camo_rs::settings::RetryOn::Timeout,
+                camo_rs::settings::RetryOn::Reset,
+            ],
+            upstream_retry_statuses: vec![502, 503, 504],
             upstream_timeout: 10,
+            upstream_tls_min_version: camo_rs::settings::TlsVersion::Tls12,
+
+            // the bundled roots keep the tests independent of the system's
+            // certificate store.
+            upstream_tls_roots: camo_rs::settings::TlsRoots::Webpki,
+            upstream_transfer_timeout: 60,
             log_format: camo_rs::settings::LogFormat::Text,
             log_level: camo_rs::settings::LogLevel::Quiet,
+            meta_endpoint: false,
+            pass_request_headers: vec![],
+            pass_response_headers: vec![],
+            preview_endpoint: false,
+            queue_limit: 0,
+            queue_timeout: 5,
+            rate_limit_burst: 10,
+            rate_limit_bytes: 0,
+            rate_limit_requests: 0,
+            security_headers: vec![],
+            strip_metadata: false,
+            strip_request_headers: vec![],
+            strip_security_headers: vec![],
+            svg_handling: camo_rs::settings::SvgHandling::Pass,
+            svg_size_limit: 1048576,
             threads: None,
+            transcode_images: false,
+            transform_max_height: 2048,
+            transform_max_width: 2048,
+            trusted_proxy_hops: 0,
 
             // the test harness will always generate empty bodies, so any body
             // length that's there can be used to test the too-long checks.
@@ -41,7 +106,7 @@ pub mod wiremock {
     use std::time::Duration;
 
     use wiremock::{
-        Mock, MockServer, ResponseTemplate,
+        Mock, MockServer, Request, ResponseTemplate,
         matchers::{header, method, path},
     };
 
@@ -71,6 +136,32 @@ pub mod wiremock {
         mount_one_time_mock_with_response(build_valid_response(status_code, "image/webp")).await
     }
 
+    /// Sets up Wiremock to respond to `GET /` with a given status code for the
+    /// first `failures` requests, and with a 200 status code afterwards. The
+    /// successful response is expected exactly `expected_successes` times.
+    pub async fn get_flaky_file_mock(
+        status_code: u16,
+        failures: u64,
+        expected_successes: u64,
+    ) -> MockServer {
+        let mockserver = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/"))
+            .respond_with(build_valid_response(status_code, "image/webp"))
+            .up_to_n_times(failures)
+            .with_priority(1)
+            .mount(&mockserver)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/"))
+            .respond_with(build_valid_response(200, "image/webp"))
+            .expect(expected_successes)
+            .mount(&mockserver)
+            .await;
+
+        mockserver
+    }
+
     /// Sets up Wiremock to respond one time to `GET /` with a 200 status code,
     /// but it does delay the response by 60 seconds, and thus can be used for
     /// testing timeouts.
@@ -81,6 +172,29 @@ pub mod wiremock {
         .await
     }
 
+    /// Sets up Wiremock to respond exactly `expected_calls` times to `GET /`
+    /// with a given status code and body, delayed by 500 milliseconds. Useful
+    /// for testing concurrent requests.
+    pub async fn get_delayed_file_mock(
+        status_code: u16,
+        body: &str,
+        expected_calls: u64,
+    ) -> MockServer {
+        let mockserver = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/"))
+            .respond_with(
+                ResponseTemplate::new(status_code)
+                    .set_body_raw(body, "image/webp")
+                    .set_delay(Duration::from_millis(500)),
+            )
+            .expect(expected_calls)
+            .mount(&mockserver)
+            .await;
+
+        mockserver
+    }
+
     /// Sets up Wiremock to respond one time to `GET /` with a 200 status code.
     /// The Mock does expect the `user-agent` and `via` headers to be set to
     /// `camo-rs`, and will fail otherwise.
@@ -98,6 +212,21 @@ pub mod wiremock {
         mockserver
     }
 
+    /// Sets up a Wiremock to respond one time to `GET /` with a 200 status
+    /// code, but only if the request has a header `name` set to `value`.
+    pub async fn get_single_file_mock_with_header(name: &str, value: &str) -> MockServer {
+        let mockserver = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/"))
+            .and(header(name, value))
+            .respond_with(build_valid_response(200, "image/webp"))
+            .expect(1)
+            .mount(&mockserver)
+            .await;
+
+        mockserver
+    }
+
     /// Sets up a Wiremock to respond one time to `GET /` with a 200 status
     /// and a body that's too long for the test settings.
     pub async fn get_long_response_mock() -> MockServer {
@@ -124,6 +253,79 @@ pub mod wiremock {
         mount_one_time_mock_with_response(build_valid_response(200, "text/plain")).await
     }
 
+    /// Sets up a Wiremock to respond one time to `GET /` with a 200 status
+    /// code, and a given body and content-type.
+    pub async fn get_body_mock(body: &[u8], content_type: &str) -> MockServer {
+        mount_one_time_mock_with_response(
+            ResponseTemplate::new(200).set_body_raw(body.to_vec(), content_type),
+        )
+        .await
+    }
+
+    /// Sets up a Wiremock to respond one time to `GET /` with a 200 status
+    /// code, and a given body, content-type, and content-encoding.
+    pub async fn get_encoded_body_mock(
+        body: &[u8],
+        content_type: &str,
+        content_encoding: &str,
+    ) -> MockServer {
+        mount_one_time_mock_with_response(
+            ResponseTemplate::new(200)
+                .set_body_raw(body.to_vec(), content_type)
+                .insert_header("content-encoding", content_encoding),
+        )
+        .await
+    }
+
+    /// Sets up a Wiremock to respond one time to `GET /` with a 200 status
+    /// code, and a given body and content-type, but only if the request
+    /// doesn't ask for a compressed response.
+    pub async fn get_uncompressed_body_mock(body: &[u8], content_type: &str) -> MockServer {
+        let mockserver = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/"))
+            .and(|req: &Request| !req.headers.contains_key("accept-encoding"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body.to_vec(), content_type))
+            .expect(1)
+            .mount(&mockserver)
+            .await;
+
+        mockserver
+    }
+
+    /// Sets up a Wiremock to respond one time to `GET /` with a given status
+    /// code, `content-range` header, and body, but only if the request's
+    /// `range` header matches `expected_range`. If `expected_range` is `None`,
+    /// the request must not have a `range` header at all.
+    pub async fn get_range_mock(
+        expected_range: Option<&'static str>,
+        status_code: u16,
+        content_range: Option<&str>,
+        body: &str,
+        content_type: &str,
+    ) -> MockServer {
+        let mut response = ResponseTemplate::new(status_code).set_body_raw(body, content_type);
+        if let Some(content_range) = content_range {
+            response = response.insert_header("content-range", content_range);
+        }
+
+        let mockserver = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/"))
+            .and(move |req: &wiremock::Request| {
+                req.headers
+                    .get("range")
+                    .map(|range| range.to_str().unwrap())
+                    == expected_range
+            })
+            .respond_with(response)
+            .expect(1)
+            .mount(&mockserver)
+            .await;
+
+        mockserver
+    }
+
     /// Sets up a Wiremock to respond one time to `GET /`, but with a 302
     /// redirect to a different URL.
     pub async fn get_redirect_mock(location: &str) -> MockServer {
@@ -133,3 +335,121 @@ pub mod wiremock {
         .await
     }
 }
+
+/// Raw TCP servers for upstream behavior that Wiremock can't simulate
+pub mod tcp {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    async fn spawn_server<F, Fut>(handler: F) -> SocketAddr
+    where
+        F: Fn(TcpStream) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("could not bind ephemeral socket");
+        let listen_addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handler(stream));
+            }
+        });
+
+        listen_addr
+    }
+
+    /// Starts a server that accepts connections, but never sends anything.
+    /// Connecting via TLS will therefore hang in the handshake.
+    pub async fn get_silent_server() -> SocketAddr {
+        spawn_server(|mut stream| async move {
+            let mut buf = [0; 1024];
+            while let Ok(1..) = stream.read(&mut buf).await {}
+        })
+        .await
+    }
+
+    /// Starts a server that sends valid response headers and the first bytes
+    /// of the body, but then stalls for 60 seconds.
+    pub async fn get_stalling_body_server() -> SocketAddr {
+        spawn_server(|mut stream| async move {
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await;
+            let _ = stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\ncontent-type: image/webp\r\ncontent-length: 100\r\n\r\nRIFF",
+                )
+                .await;
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        })
+        .await
+    }
+
+    /// Starts a server that sends valid response headers with the given
+    /// content-type, and the first `split` bytes of the body. The rest of the
+    /// body follows after 200 milliseconds.
+    pub async fn get_split_body_server(
+        content_type: &'static str,
+        body: Vec<u8>,
+        split: usize,
+    ) -> SocketAddr {
+        spawn_server(move |mut stream| {
+            let body = body.clone();
+            async move {
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: {content_type}\r\ncontent-length: {}\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(&body[..split]).await;
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                let _ = stream.write_all(&body[split..]).await;
+            }
+        })
+        .await
+    }
+
+    /// Starts a server that sends valid response headers, followed by a body
+    /// of 4 MiB, as fast as the client reads it.
+    pub async fn get_fast_body_server() -> SocketAddr {
+        spawn_server(|mut stream| async move {
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await;
+            let _ = stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\ncontent-type: image/webp\r\ncontent-length: 4194304\r\n\r\n",
+                )
+                .await;
+            let _ = stream.write_all(&[b'x'; 4 * 1024 * 1024]).await;
+        })
+        .await
+    }
+
+    /// Starts a server that sends valid response headers, but then only
+    /// sends one byte of the body every 500 milliseconds.
+    pub async fn get_dripping_body_server() -> SocketAddr {
+        spawn_server(|mut stream| async move {
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await;
+            let _ = stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\ncontent-type: image/webp\r\ncontent-length: 100\r\n\r\n",
+                )
+                .await;
+            for _ in 0..100 {
+                if stream.write_all(b"x").await.is_err() {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+        })
+        .await
+    }
+}
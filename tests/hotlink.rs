@@ -0,0 +1,97 @@
+use camo_rs::{Settings, hotlink::*};
+use hyper::HeaderMap;
+
+pub mod helpers;
+use helpers::application::*;
+
+fn get_hotlink_settings() -> Settings {
+    let mut settings = get_test_settings();
+    settings.hotlink_allow_hosts = vec!["example.com".to_owned(), "*.example.org".to_owned()];
+    settings
+}
+
+fn check_headers(settings: &Settings, headers: &[(&'static str, &str)]) -> bool {
+    let mut header_map = HeaderMap::new();
+    for (name, value) in headers {
+        header_map.append(*name, value.parse().unwrap());
+    }
+
+    HotlinkPolicy::new(settings)
+        .unwrap()
+        .check(&header_map)
+        .is_ok()
+}
+
+#[test]
+fn is_disabled_without_allowed_hosts() {
+    assert!(HotlinkPolicy::new(&get_test_settings()).is_none());
+}
+
+#[test]
+fn allows_allowed_referers() {
+    let settings = get_hotlink_settings();
+
+    assert!(check_headers(
+        &settings,
+        &[("referer", "https://example.com/page")]
+    ));
+    assert!(check_headers(
+        &settings,
+        &[("referer", "https://www.example.org/page")]
+    ));
+    assert!(check_headers(
+        &settings,
+        &[("origin", "https://EXAMPLE.com")]
+    ));
+}
+
+#[test]
+fn rejects_other_referers() {
+    let settings = get_hotlink_settings();
+
+    assert!(!check_headers(
+        &settings,
+        &[("referer", "https://evil.com/page")]
+    ));
+    assert!(!check_headers(
+        &settings,
+        &[("referer", "https://example.org/page")]
+    ));
+    assert!(!check_headers(
+        &settings,
+        &[("referer", "https://notexample.com/page")]
+    ));
+    assert!(!check_headers(&settings, &[("referer", "not a url")]));
+}
+
+#[test]
+fn prefers_origin_over_referer() {
+    let settings = get_hotlink_settings();
+
+    assert!(!check_headers(
+        &settings,
+        &[
+            ("origin", "https://evil.com"),
+            ("referer", "https://example.com/page")
+        ]
+    ));
+}
+
+#[test]
+fn handles_requests_without_referer() {
+    let mut settings = get_hotlink_settings();
+    assert!(!check_headers(&settings, &[]));
+    assert!(!check_headers(
+        &settings,
+        &[("sec-fetch-site", "cross-site")]
+    ));
+    assert!(check_headers(&settings, &[("sec-fetch-site", "same-site")]));
+
+    settings.hotlink_allow_empty_referer = true;
+    assert!(check_headers(&settings, &[]));
+    assert!(check_headers(&settings, &[("sec-fetch-site", "none")]));
+    assert!(!check_headers(
+        &settings,
+        &[("sec-fetch-site", "cross-site")]
+    ));
+}
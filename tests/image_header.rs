@@ -0,0 +1,263 @@
+use std::io::Cursor;
+
+use camo_rs::image_header::*;
+
+fn encode(width: u32, height: u32, format: image::ImageFormat) -> Vec<u8> {
+    let mut data = Cursor::new(Vec::new());
+    image::RgbImage::new(width, height)
+        .write_to(&mut data, format)
+        .unwrap();
+    data.into_inner()
+}
+
+fn png_chunk(chunk_type: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(chunk_type);
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(&[0, 0, 0, 0]);
+    chunk
+}
+
+fn png(width: u32, height: u32, chunks: &[Vec<u8>]) -> Vec<u8> {
+    let mut ihdr = width.to_be_bytes().to_vec();
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    png.extend_from_slice(&png_chunk(b"IHDR", &ihdr));
+    png.extend_from_slice(&chunks.concat());
+    png
+}
+
+fn iso_box(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut iso_box = (data.len() as u32 + 8).to_be_bytes().to_vec();
+    iso_box.extend_from_slice(kind);
+    iso_box.extend_from_slice(data);
+    iso_box
+}
+
+fn avif(brand: &[u8; 4], sizes: &[(u32, u32)]) -> Vec<u8> {
+    let ispe = sizes
+        .iter()
+        .map(|(width, height)| {
+            let mut data = vec![0; 4];
+            data.extend_from_slice(&width.to_be_bytes());
+            data.extend_from_slice(&height.to_be_bytes());
+            iso_box(b"ispe", &data)
+        })
+        .collect::<Vec<_>>()
+        .concat();
+    let ipco = iso_box(b"ipco", &ispe);
+    let iprp = iso_box(b"iprp", &ipco);
+    let mut meta = vec![0; 4];
+    meta.extend_from_slice(&iso_box(b"hdlr", &[0; 24]));
+    meta.extend_from_slice(&iprp);
+
+    let mut ftyp = brand.to_vec();
+    ftyp.extend_from_slice(&[0; 4]);
+    ftyp.extend_from_slice(b"mif1miaf");
+
+    [
+        iso_box(b"ftyp", &ftyp),
+        iso_box(b"meta", &meta),
+        iso_box(b"mdat", &[0; 16]),
+    ]
+    .concat()
+}
+
+fn parsed(width: u32, height: u32, frames: Option<u32>) -> Header {
+    Header::Parsed(ImageInfo {
+        width,
+        height,
+        frames,
+    })
+}
+
+#[test]
+fn parses_png() {
+    let data = encode(300, 200, image::ImageFormat::Png);
+
+    assert_eq!(parse(&data), parsed(300, 200, Some(1)));
+}
+
+#[test]
+fn parses_apng_frame_count() {
+    let actl = [24u32.to_be_bytes(), 0u32.to_be_bytes()].concat();
+    let data = png(
+        64,
+        32,
+        &[png_chunk(b"tEXt", b"a\0b"), png_chunk(b"acTL", &actl)],
+    );
+
+    assert_eq!(parse(&data), parsed(64, 32, Some(24)));
+}
+
+#[test]
+fn parses_jpeg() {
+    let data = encode(300, 200, image::ImageFormat::Jpeg);
+
+    assert_eq!(parse(&data), parsed(300, 200, Some(1)));
+}
+
+#[test]
+fn parses_jpeg_after_large_segments() {
+    let mut data = vec![0xff, 0xd8];
+    for _ in 0..4 {
+        data.extend_from_slice(&[0xff, 0xe1, 0xff, 0xff]);
+        data.extend_from_slice(&[0; 0xfffd]);
+    }
+    data.extend_from_slice(&encode(30, 20, image::ImageFormat::Jpeg)[2..]);
+
+    assert_eq!(parse(&data[..1024]), Header::Incomplete(0x10005));
+    assert_eq!(parse(&data), parsed(30, 20, Some(1)));
+}
+
+#[test]
+fn parses_gif() {
+    let data = encode(300, 200, image::ImageFormat::Gif);
+
+    assert_eq!(parse(&data), parsed(300, 200, None));
+}
+
+#[test]
+fn parses_lossless_webp() {
+    let data = encode(300, 200, image::ImageFormat::WebP);
+
+    assert_eq!(parse(&data), parsed(300, 200, Some(1)));
+}
+
+#[test]
+fn parses_extended_webp() {
+    let mut vp8x = vec![0x02, 0, 0, 0];
+    vp8x.extend_from_slice(&[0x3f, 0x01, 0x00, 0xc7, 0x00, 0x00]);
+    let mut data = b"RIFF\0\0\0\0WEBPVP8X\x0a\0\0\0".to_vec();
+    data.extend_from_slice(&vp8x);
+
+    assert_eq!(parse(&data), parsed(320, 200, None));
+}
+
+#[test]
+fn parses_avif() {
+    let data = avif(b"avif", &[(64, 32), (640, 320)]);
+
+    assert_eq!(parse(&data), parsed(640, 320, Some(1)));
+}
+
+#[test]
+fn parses_avif_sequences_without_frame_count() {
+    let data = avif(b"avis", &[(64, 32)]);
+
+    assert_eq!(parse(&data), parsed(64, 32, None));
+}
+
+#[test]
+fn ignores_other_iso_media_files() {
+    let mut ftyp = b"isom".to_vec();
+    ftyp.extend_from_slice(&[0; 4]);
+    ftyp.extend_from_slice(b"isomavc1");
+
+    assert_eq!(parse(&iso_box(b"ftyp", &ftyp)), Header::Unknown);
+}
+
+#[test]
+fn asks_for_more_data_for_truncated_headers() {
+    let data = encode(300, 200, image::ImageFormat::Png);
+
+    assert_eq!(parse(&data[..18]), Header::Incomplete(20));
+}
+
+#[test]
+fn rejects_malformed_headers() {
+    let mut data = png(64, 32, &[png_chunk(b"IDAT", &[])]);
+    data[12..16].copy_from_slice(b"IDAT");
+
+    assert_eq!(parse(&data), Header::Malformed);
+}
+
+#[test]
+fn ignores_unknown_formats() {
+    assert_eq!(parse(b"BM\0\0\0\0\0\0\0\0\0\0\0\0"), Header::Unknown);
+}
+
+fn gif(frames: usize) -> Vec<u8> {
+    let mut data = Vec::new();
+    {
+        let mut encoder = image::codecs::gif::GifEncoder::new(&mut data);
+        let frames = (0..frames).map(|_| image::Frame::new(image::RgbaImage::new(4, 4)));
+        encoder.encode_frames(frames).unwrap();
+    }
+    data
+}
+
+#[test]
+fn parses_pngs_with_a_lot_of_metadata_before_the_image_data() {
+    let data = png(
+        64,
+        32,
+        &[
+            png_chunk(b"tEXt", &vec![b'x'; MAX_HEADER_LENGTH]),
+            png_chunk(b"IDAT", &[]),
+        ],
+    );
+
+    let expected = ImageInfo {
+        width: 64,
+        height: 32,
+        frames: None,
+    };
+    assert_eq!(parse(&data[..MAX_HEADER_LENGTH]), Header::Parsed(expected));
+}
+
+#[test]
+fn counts_gif_frames() {
+    assert_eq!(count_frames(&gif(3)), Some(3));
+}
+
+#[test]
+fn counts_frames_while_streaming() {
+    let data = gif(3);
+    let mut counter = FrameCounter::new(&data).unwrap();
+    let counts: Vec<u32> = data.chunks(1).map(|byte| counter.feed(byte)).collect();
+
+    assert_eq!(counts.last(), Some(&3));
+    assert!(counts.windows(2).all(|pair| pair[0] <= pair[1]));
+    assert_eq!(counter.finish(), Some(3));
+}
+
+#[test]
+fn does_not_count_truncated_gifs() {
+    let data = gif(3);
+
+    assert_eq!(count_frames(&data[..data.len() - 1]), None);
+}
+
+#[test]
+fn only_counts_frames_of_gifs_and_animated_webps() {
+    assert!(FrameCounter::new(&encode(3, 2, image::ImageFormat::Png)).is_none());
+    assert!(FrameCounter::new(&encode(3, 2, image::ImageFormat::WebP)).is_none());
+}
+
+#[test]
+fn counts_animated_webp_frames() {
+    let mut vp8x = vec![0x02, 0, 0, 0];
+    vp8x.extend_from_slice(&[0x03, 0, 0, 0x03, 0, 0]);
+    let mut chunks = b"VP8X\x0a\0\0\0".to_vec();
+    chunks.extend_from_slice(&vp8x);
+    chunks.extend_from_slice(b"ANIM\x06\0\0\0\0\0\0\0\0\0");
+    for _ in 0..2 {
+        chunks.extend_from_slice(b"ANMF\x03\0\0\0abc\0");
+    }
+    let mut data = b"RIFF".to_vec();
+    data.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+    data.extend_from_slice(b"WEBP");
+    data.extend_from_slice(&chunks);
+
+    assert_eq!(count_frames(&data), Some(2));
+}
+
+#[test]
+fn counts_frames_from_the_header_for_other_formats() {
+    let data = encode(3, 2, image::ImageFormat::Png);
+
+    assert_eq!(count_frames(&data), Some(1));
+}
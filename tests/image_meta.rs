@@ -0,0 +1,46 @@
+use std::io::Cursor;
+
+use camo_rs::image_meta::*;
+
+fn encode(image: image::RgbaImage) -> Vec<u8> {
+    let mut data = Cursor::new(Vec::new());
+    image.write_to(&mut data, image::ImageFormat::Png).unwrap();
+    data.into_inner()
+}
+
+#[test]
+fn finds_the_most_common_color() {
+    let mut image = image::RgbaImage::from_pixel(100, 100, image::Rgba([0, 0, 255, 255]));
+    for x in 0..30 {
+        for y in 0..100 {
+            image.put_pixel(x, y, image::Rgba([255, 255, 0, 255]));
+        }
+    }
+
+    assert_eq!(dominant_color(&encode(image)), Some("#0000ff".to_owned()));
+}
+
+#[test]
+fn ignores_transparent_pixels() {
+    let mut image = image::RgbaImage::from_pixel(100, 100, image::Rgba([0, 0, 0, 0]));
+    for x in 0..10 {
+        for y in 0..100 {
+            image.put_pixel(x, y, image::Rgba([0, 128, 0, 255]));
+        }
+    }
+
+    assert_eq!(dominant_color(&encode(image)), Some("#008000".to_owned()));
+}
+
+#[test]
+fn has_no_color_for_fully_transparent_images() {
+    let image = image::RgbaImage::from_pixel(10, 10, image::Rgba([0, 0, 0, 0]));
+
+    assert_eq!(dominant_color(&encode(image)), None);
+}
+
+#[test]
+fn has_no_color_for_undecodable_data() {
+    assert!(!is_decodable(b"not an image"));
+    assert_eq!(dominant_color(b"not an image"), None);
+}
@@ -0,0 +1,176 @@
+use camo_rs::metadata::*;
+
+fn jpeg_segment(marker: u8, data: &[u8]) -> Vec<u8> {
+    let mut segment = vec![0xff, marker];
+    segment.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+    segment.extend_from_slice(data);
+    segment
+}
+
+fn png_chunk(chunk_type: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(chunk_type);
+    chunk.extend_from_slice(data);
+    // The CRC isn't checked, so any value does.
+    chunk.extend_from_slice(&[0, 0, 0, 0]);
+    chunk
+}
+
+fn webp_chunk(chunk_type: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = chunk_type.to_vec();
+    chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    chunk.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        chunk.push(0);
+    }
+    chunk
+}
+
+fn webp(chunks: &[Vec<u8>]) -> Vec<u8> {
+    let chunks = chunks.concat();
+    let mut webp = b"RIFF".to_vec();
+    webp.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+    webp.extend_from_slice(b"WEBP");
+    webp.extend_from_slice(&chunks);
+    webp
+}
+
+#[test]
+fn strips_jpeg_metadata() {
+    let app0 = jpeg_segment(0xe0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+    let icc = jpeg_segment(0xe2, b"ICC_PROFILE\0data");
+    let scan = [
+        jpeg_segment(0xda, b"\x01\x01\0\0\x3f\0"),
+        vec![0x12, 0xff, 0x00, 0x34, 0xff, 0xd9],
+    ]
+    .concat();
+    let jpeg = [
+        vec![0xff, 0xd8],
+        app0.clone(),
+        jpeg_segment(0xe1, b"Exif\0\0GPS 52.5N 13.4E"),
+        jpeg_segment(0xe1, b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta/>"),
+        icc.clone(),
+        jpeg_segment(0xed, b"Photoshop 3.0\0IPTC"),
+        jpeg_segment(0xfe, b"comment"),
+        scan.clone(),
+    ]
+    .concat();
+
+    let stripped = strip(&jpeg).unwrap();
+
+    assert_eq!(stripped, [vec![0xff, 0xd8], app0, icc, scan].concat());
+}
+
+/// EXIF data with the given orientation, and a GPS IFD pointer.
+fn exif_with_orientation(orientation: u16) -> Vec<u8> {
+    [
+        &b"Exif\0\0II\x2a\0\x08\0\0\0\x02\0"[..],
+        &[
+            0x25, 0x88, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00, 0x26, 0x00, 0x00, 0x00,
+        ],
+        &[0x12, 0x01, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00],
+        &orientation.to_le_bytes(),
+        b"\0\0\0\0\0\0GPS 52.5N 13.4E",
+    ]
+    .concat()
+}
+
+#[test]
+fn keeps_the_jpeg_orientation() {
+    let scan = [
+        jpeg_segment(0xda, b"\x01\x01\0\0\x3f\0"),
+        vec![0x12, 0xff, 0xd9],
+    ]
+    .concat();
+    let jpeg = [
+        vec![0xff, 0xd8],
+        jpeg_segment(0xe1, &exif_with_orientation(6)),
+        scan.clone(),
+    ]
+    .concat();
+
+    let stripped = strip(&jpeg).unwrap();
+
+    let orientation = jpeg_segment(
+        0xe1,
+        b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01\0\x06\0\0\0\0\0\0",
+    );
+    assert_eq!(stripped, [vec![0xff, 0xd8], orientation, scan].concat());
+}
+
+#[test]
+fn drops_the_default_jpeg_orientation() {
+    let scan = [
+        jpeg_segment(0xda, b"\x01\x01\0\0\x3f\0"),
+        vec![0x12, 0xff, 0xd9],
+    ]
+    .concat();
+    let jpeg = [
+        vec![0xff, 0xd8],
+        jpeg_segment(0xe1, &exif_with_orientation(1)),
+        scan.clone(),
+    ]
+    .concat();
+
+    assert_eq!(strip(&jpeg).unwrap(), [vec![0xff, 0xd8], scan].concat());
+}
+
+#[test]
+fn strips_png_metadata() {
+    let ihdr = png_chunk(b"IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, 6, 0, 0, 0]);
+    let idat = png_chunk(b"IDAT", b"pixels");
+    let iend = png_chunk(b"IEND", b"");
+    let png = [
+        b"\x89PNG\r\n\x1a\n".to_vec(),
+        ihdr.clone(),
+        png_chunk(b"eXIf", b"GPS"),
+        png_chunk(b"tEXt", b"Author\0someone"),
+        png_chunk(b"iTXt", b"XML:com.adobe.xmp\0\0\0\0\0<x:xmpmeta/>"),
+        idat.clone(),
+        iend.clone(),
+        b"trailing data".to_vec(),
+    ]
+    .concat();
+
+    let stripped = strip(&png).unwrap();
+
+    assert_eq!(
+        stripped,
+        [b"\x89PNG\r\n\x1a\n".to_vec(), ihdr, idat, iend].concat()
+    );
+}
+
+#[test]
+fn strips_webp_metadata() {
+    let image = webp_chunk(b"VP8L", b"pixels");
+    let original = webp(&[
+        webp_chunk(b"VP8X", &[0x0c, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+        image.clone(),
+        webp_chunk(b"EXIF", b"GPS"),
+        webp_chunk(b"XMP ", b"<x:xmpmeta/>"),
+    ]);
+
+    let stripped = strip(&original).unwrap();
+
+    assert_eq!(stripped, webp(&[webp_chunk(b"VP8X", &[0; 10]), image]));
+}
+
+#[test]
+fn passes_other_formats_unchanged() {
+    assert_eq!(strip(b"GIF89a...").unwrap(), b"GIF89a...");
+}
+
+#[test]
+fn rejects_malformed_images() {
+    assert!(strip(&[0xff, 0xd8, 0xff, 0xe1, 0xff, 0xff]).is_err());
+    assert!(strip(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR").is_err());
+}
+
+#[test]
+fn knows_strippable_content_types() {
+    assert_eq!(
+        ImageFormat::from_content_type("Image/JPEG; charset=binary"),
+        Some(ImageFormat::Jpeg)
+    );
+    assert_eq!(ImageFormat::from_content_type("image/gif"), None);
+}
@@ -0,0 +1,109 @@
+use camo_rs::{Settings, errors::CamoError, mime_policy::*, settings::MimePattern};
+
+pub mod helpers;
+use helpers::application::*;
+
+fn patterns(patterns: &[&str]) -> Vec<MimePattern> {
+    patterns.iter().map(|p| p.parse().unwrap()).collect()
+}
+
+fn is_accepted(settings: &Settings, content_type: Option<&str>) -> bool {
+    MimePolicy::new(settings).check(content_type).is_ok()
+}
+
+#[test]
+fn parses_patterns() {
+    assert_eq!(
+        "Image/SVG+XML".parse::<MimePattern>().unwrap(),
+        MimePattern {
+            type_: Some("image".to_owned()),
+            subtype: Some("svg+xml".to_owned()),
+        }
+    );
+    assert_eq!(
+        "image/*".parse::<MimePattern>().unwrap(),
+        MimePattern {
+            type_: Some("image".to_owned()),
+            subtype: None,
+        }
+    );
+    assert!("image".parse::<MimePattern>().is_err());
+    assert!("*/png".parse::<MimePattern>().is_err());
+    assert!("text/html; charset=utf-8".parse::<MimePattern>().is_err());
+}
+
+#[test]
+fn maps_flags_onto_patterns() {
+    let settings = get_test_settings();
+
+    assert!(is_accepted(&settings, Some("image/png")));
+    assert!(!is_accepted(&settings, Some("video/mp4")));
+    assert!(matches!(
+        MimePolicy::new(&settings).check(None),
+        Err(CamoError::MissingContentType)
+    ));
+}
+
+#[test]
+fn ignores_case_and_parameters() {
+    let settings = get_test_settings();
+
+    assert!(is_accepted(&settings, Some("IMAGE/PNG")));
+    assert!(is_accepted(&settings, Some("image/png; charset=binary")));
+    assert!(!is_accepted(&settings, Some("imagefoo/png")));
+    assert!(!is_accepted(&settings, Some("not a mime type")));
+}
+
+#[test]
+fn allows_exact_types() {
+    let mut settings = get_test_settings();
+    settings.allow_types = patterns(&["application/pdf"]);
+
+    assert!(is_accepted(&settings, Some("application/pdf")));
+    assert!(!is_accepted(&settings, Some("application/json")));
+}
+
+#[test]
+fn denies_types_within_allowed_wildcards() {
+    let mut settings = get_test_settings();
+    settings.deny_types = patterns(&["image/svg+xml"]);
+
+    assert!(is_accepted(&settings, Some("image/png")));
+    assert!(!is_accepted(&settings, Some("image/svg+xml")));
+}
+
+#[test]
+fn prefers_the_most_specific_pattern() {
+    let mut settings = get_test_settings();
+    settings.allow_image = false;
+    settings.allow_types = patterns(&["image/png"]);
+    settings.deny_types = patterns(&["image/*"]);
+
+    assert!(is_accepted(&settings, Some("image/png")));
+    assert!(!is_accepted(&settings, Some("image/gif")));
+
+    settings.allow_types = patterns(&["image/png"]);
+    settings.deny_types = patterns(&["image/png"]);
+    assert!(!is_accepted(&settings, Some("image/png")));
+}
+
+#[test]
+fn allows_everything_with_allow_all_types() {
+    let mut settings = get_test_settings();
+    settings.allow_all_types = true;
+    settings.deny_types = patterns(&["text/html"]);
+
+    assert!(is_accepted(&settings, Some("application/octet-stream")));
+    assert!(is_accepted(&settings, None));
+    assert!(!is_accepted(&settings, Some("text/html")));
+}
+
+#[test]
+fn knows_whether_anything_is_allowed() {
+    let mut settings = get_test_settings();
+    settings.allow_image = false;
+    assert!(!MimePolicy::new(&settings).allows_anything());
+
+    settings.allow_types = patterns(&["application/pdf"]);
+    assert!(MimePolicy::new(&settings).allows_anything());
+}
@@ -0,0 +1,45 @@
+use std::io::Cursor;
+
+use camo_rs::preview::*;
+
+fn encode(image: image::RgbaImage) -> Vec<u8> {
+    let mut data = Cursor::new(Vec::new());
+    image.write_to(&mut data, image::ImageFormat::Png).unwrap();
+    data.into_inner()
+}
+
+#[test]
+fn renders_small_previews_keeping_the_aspect_ratio() {
+    let image = image::RgbaImage::from_pixel(90, 300, image::Rgba([10, 20, 30, 255]));
+    let preview = image::load_from_memory(&render(&encode(image)).unwrap()).unwrap();
+
+    assert_eq!(preview.height(), PREVIEW_SIZE);
+    assert!(preview.width() < PREVIEW_SIZE);
+}
+
+#[test]
+fn does_not_enlarge_small_images() {
+    let image = image::RgbaImage::from_pixel(8, 4, image::Rgba([10, 20, 30, 255]));
+    let preview = image::load_from_memory(&render(&encode(image)).unwrap()).unwrap();
+
+    assert_eq!((preview.width(), preview.height()), (8, 4));
+}
+
+#[test]
+fn puts_transparent_images_on_white() {
+    let image = image::RgbaImage::from_pixel(16, 16, image::Rgba([0, 0, 0, 0]));
+    let preview = image::load_from_memory(&render(&encode(image)).unwrap())
+        .unwrap()
+        .to_rgb8();
+
+    assert!(
+        preview
+            .pixels()
+            .all(|pixel| pixel.0.iter().all(|v| *v > 245))
+    );
+}
+
+#[test]
+fn does_not_render_undecodable_data() {
+    assert_eq!(render(b"not an image"), None);
+}
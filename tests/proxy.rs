@@ -1,14 +1,22 @@
 use axum::body::Body;
+use http_body_util::BodyExt;
 use hyper::{HeaderMap, Method};
 use wiremock::MockServer;
 
-use camo_rs::{errors::ProxyError, proxy::*};
+use camo_rs::{Settings, errors::ProxyError, metrics::Metrics, proxy::*, settings::IpFamily};
 
 pub mod helpers;
-use helpers::wiremock::*;
+use helpers::{application::*, tcp::*, wiremock::*};
 
 async fn run_proxy_request(upstream: &MockServer) -> Result<hyper::Response<Body>, ProxyError> {
-    let proxy = Proxy::new("camo-rs", 10);
+    run_proxy_request_with_settings(get_test_settings(), upstream).await
+}
+
+async fn run_proxy_request_with_settings(
+    settings: Settings,
+    upstream: &MockServer,
+) -> Result<hyper::Response<Body>, ProxyError> {
+    let proxy = Proxy::new(&settings, Metrics::default()).unwrap();
     let headers = HeaderMap::new();
 
     proxy
@@ -18,7 +26,7 @@ async fn run_proxy_request(upstream: &MockServer) -> Result<hyper::Response<Body
 
 #[tokio::test]
 async fn fails_gracefully_for_invalid_params() {
-    let proxy = Proxy::new("camo-rs", 10);
+    let proxy = Proxy::new(&get_test_settings(), Metrics::default()).unwrap();
     let headers = HeaderMap::new();
 
     let proxy_res = proxy.run_request(&Method::GET, &headers, "").await;
@@ -29,7 +37,9 @@ async fn fails_gracefully_for_invalid_params() {
 #[tokio::test]
 async fn fails_gracefully_for_timeouting_connections() {
     let upstream = get_single_slow_file_mock().await;
-    let proxy = Proxy::new("camo-rs", 1); // Note the 1 second timeout.
+    let mut settings = get_test_settings();
+    settings.upstream_timeout = 1; // Note the 1 second timeout.
+    let proxy = Proxy::new(&settings, Metrics::default()).unwrap();
     let headers = HeaderMap::new();
 
     let proxy_res = proxy
@@ -39,6 +49,243 @@ async fn fails_gracefully_for_timeouting_connections() {
     assert!(proxy_res.is_err());
 }
 
+#[tokio::test]
+async fn fails_with_connect_timeout_for_stalled_tls_handshakes() {
+    let upstream = get_silent_server().await;
+    let mut settings = get_test_settings();
+    settings.upstream_connect_timeout = 1;
+    let proxy = Proxy::new(&settings, Metrics::default()).unwrap();
+    let headers = HeaderMap::new();
+
+    let proxy_res = proxy
+        .run_request(&Method::GET, &headers, &format!("https://{upstream}/"))
+        .await;
+
+    assert!(matches!(proxy_res, Err(ProxyError::ConnectTimeout)));
+}
+
+#[tokio::test]
+async fn aborts_stalled_bodies() {
+    let upstream = get_stalling_body_server().await;
+    let mut settings = get_test_settings();
+    settings.upstream_idle_timeout = 1;
+    let proxy = Proxy::new(&settings, Metrics::default()).unwrap();
+    let headers = HeaderMap::new();
+
+    let proxy_res = proxy
+        .run_request(&Method::GET, &headers, &format!("http://{upstream}/"))
+        .await
+        .unwrap();
+
+    assert!(proxy_res.into_body().collect().await.is_err());
+}
+
+#[tokio::test]
+async fn aborts_transfers_exceeding_the_transfer_timeout() {
+    let upstream = get_stalling_body_server().await;
+    let mut settings = get_test_settings();
+    settings.upstream_transfer_timeout = 1;
+    let proxy = Proxy::new(&settings, Metrics::default()).unwrap();
+    let headers = HeaderMap::new();
+
+    let proxy_res = proxy
+        .run_request(&Method::GET, &headers, &format!("http://{upstream}/"))
+        .await
+        .unwrap();
+
+    assert!(proxy_res.into_body().collect().await.is_err());
+}
+
+#[tokio::test]
+async fn does_not_count_slow_clients_against_the_transfer_timeout() {
+    let upstream = get_fast_body_server().await;
+    let mut settings = get_test_settings();
+    settings.upstream_transfer_timeout = 1;
+    let proxy = Proxy::new(&settings, Metrics::default()).unwrap();
+    let headers = HeaderMap::new();
+
+    let proxy_res = proxy
+        .run_request(&Method::GET, &headers, &format!("http://{upstream}/"))
+        .await
+        .unwrap();
+
+    let mut body = proxy_res.into_body();
+    for _ in 0..10 {
+        body.frame().await.unwrap().unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    }
+    assert!(body.collect().await.is_ok());
+}
+
+#[tokio::test]
+async fn aborts_transfers_below_the_minimum_rate() {
+    let upstream = get_dripping_body_server().await;
+    let mut settings = get_test_settings();
+    settings.upstream_min_rate = 100;
+    settings.upstream_min_rate_window = 2;
+    let proxy = Proxy::new(&settings, Metrics::default()).unwrap();
+    let headers = HeaderMap::new();
+
+    let proxy_res = proxy
+        .run_request(&Method::GET, &headers, &format!("http://{upstream}/"))
+        .await
+        .unwrap();
+
+    assert!(proxy_res.into_body().collect().await.is_err());
+}
+
+#[tokio::test]
+async fn passes_transfers_above_the_minimum_rate() {
+    let upstream = get_long_response_mock().await;
+    let mut settings = get_test_settings();
+    settings.upstream_min_rate = 100;
+    settings.upstream_min_rate_window = 1;
+    let proxy_res = run_proxy_request_with_settings(settings, &upstream)
+        .await
+        .unwrap();
+
+    assert!(proxy_res.into_body().collect().await.is_ok());
+}
+
+#[tokio::test]
+async fn does_not_blame_the_upstream_for_slow_clients() {
+    let upstream = get_fast_body_server().await;
+    let mut settings = get_test_settings();
+    settings.upstream_min_rate = 1024 * 1024;
+    settings.upstream_min_rate_window = 1;
+    let proxy = Proxy::new(&settings, Metrics::default()).unwrap();
+    let headers = HeaderMap::new();
+
+    let proxy_res = proxy
+        .run_request(&Method::GET, &headers, &format!("http://{upstream}/"))
+        .await
+        .unwrap();
+
+    // Reading a few chunks with pauses in between is a lot slower than the
+    // minimum rate, but that's not the upstream's fault.
+    let mut body = proxy_res.into_body();
+    for _ in 0..10 {
+        body.frame().await.unwrap().unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    }
+    assert!(body.collect().await.is_ok());
+}
+
+#[tokio::test]
+async fn does_not_retry_by_default() {
+    let upstream = get_flaky_file_mock(503, 1, 0).await;
+    let proxy_res = run_proxy_request(&upstream).await.unwrap();
+
+    assert_eq!(proxy_res.status(), 503);
+}
+
+#[tokio::test]
+async fn retries_retryable_statuses() {
+    let upstream = get_flaky_file_mock(503, 2, 1).await;
+    let mut settings = get_test_settings();
+    settings.upstream_retries = 2;
+    let proxy_res = run_proxy_request_with_settings(settings, &upstream)
+        .await
+        .unwrap();
+
+    assert_eq!(proxy_res.status(), 200);
+}
+
+#[tokio::test]
+async fn gives_up_after_the_configured_retries() {
+    let upstream = get_flaky_file_mock(503, 3, 0).await;
+    let mut settings = get_test_settings();
+    settings.upstream_retries = 2;
+    let proxy_res = run_proxy_request_with_settings(settings, &upstream)
+        .await
+        .unwrap();
+
+    assert_eq!(proxy_res.status(), 503);
+}
+
+#[tokio::test]
+async fn does_not_retry_other_statuses() {
+    let upstream = get_flaky_file_mock(500, 1, 0).await;
+    let mut settings = get_test_settings();
+    settings.upstream_retries = 2;
+    let proxy_res = run_proxy_request_with_settings(settings, &upstream)
+        .await
+        .unwrap();
+
+    assert_eq!(proxy_res.status(), 500);
+}
+
+#[tokio::test]
+async fn retries_failed_connections() {
+    let mut settings = get_test_settings();
+    settings.upstream_retries = 2;
+    settings.upstream_connect_timeout = 1;
+    let proxy = Proxy::new(&settings, Metrics::default()).unwrap();
+    let headers = HeaderMap::new();
+
+    // Every attempt runs into the connect timeout, so three attempts take at
+    // least three seconds.
+    let upstream = get_silent_server().await;
+    let started = std::time::Instant::now();
+    let proxy_res = proxy
+        .run_request(&Method::GET, &headers, &format!("https://{upstream}/"))
+        .await;
+
+    assert!(matches!(proxy_res, Err(ProxyError::ConnectTimeout)));
+    assert!(started.elapsed() >= std::time::Duration::from_secs(3));
+}
+
+#[tokio::test]
+async fn opens_the_circuit_after_repeated_failures() {
+    let upstream = get_flaky_file_mock(500, 2, 0).await;
+    let mut settings = get_test_settings();
+    settings.upstream_circuit_breaker_threshold = 2;
+    let metrics = Metrics::default();
+    let proxy = Proxy::new(&settings, metrics.clone()).unwrap();
+    let headers = HeaderMap::new();
+
+    for _ in 0..2 {
+        let proxy_res = proxy
+            .run_request(&Method::GET, &headers, &upstream.uri())
+            .await
+            .unwrap();
+        assert_eq!(proxy_res.status(), 500);
+    }
+
+    // The third request never reaches the upstream.
+    let proxy_res = proxy
+        .run_request(&Method::GET, &headers, &upstream.uri())
+        .await;
+
+    assert!(matches!(proxy_res, Err(ProxyError::CircuitOpen(_))));
+    assert!(metrics.render().contains("camo_upstream_circuits_open 1"));
+}
+
+#[tokio::test]
+async fn limits_concurrent_requests_per_host() {
+    let upstream = get_single_file_mock(200).await;
+    let mut settings = get_test_settings();
+    settings.upstream_host_concurrency = 1;
+    settings.upstream_timeout = 1;
+    let proxy = Proxy::new(&settings, Metrics::default()).unwrap();
+    let headers = HeaderMap::new();
+
+    // The first response holds on to the slot until its body is dropped.
+    let first_res = proxy
+        .run_request(&Method::GET, &headers, &upstream.uri())
+        .await
+        .unwrap();
+    let second_res = proxy
+        .run_request(&Method::GET, &headers, &upstream.uri())
+        .await;
+
+    assert!(matches!(
+        second_res,
+        Err(ProxyError::HostConcurrencyExceeded(_))
+    ));
+    drop(first_res);
+}
+
 #[tokio::test]
 async fn proxies_a_request() {
     let upstream = get_single_file_mock(200).await;
@@ -47,6 +294,36 @@ async fn proxies_a_request() {
     assert_eq!(proxy_res.status(), 200);
 }
 
+#[tokio::test]
+async fn binds_to_the_configured_local_address() {
+    let mut settings = get_test_settings();
+    settings.upstream_bind_ipv4 = Some("127.0.0.1".parse().unwrap());
+    let upstream = get_single_file_mock(200).await;
+    let proxy_res = run_proxy_request_with_settings(settings, &upstream)
+        .await
+        .unwrap();
+
+    assert_eq!(proxy_res.status(), 200);
+}
+
+#[tokio::test]
+async fn rejects_targets_outside_the_allowed_ip_family() {
+    let mut settings = get_test_settings();
+    settings.upstream_ip_family = IpFamily::Ipv6;
+    let proxy = Proxy::new(&settings, Metrics::default()).unwrap();
+    let headers = HeaderMap::new();
+
+    // No upstream needed, the request gets rejected before connecting.
+    let proxy_res = proxy
+        .run_request(&Method::GET, &headers, "http://127.0.0.1:1/")
+        .await;
+
+    assert!(matches!(
+        proxy_res,
+        Err(ProxyError::IpFamilyNotPermitted(_))
+    ));
+}
+
 #[tokio::test]
 async fn passes_errors_without_failing() {
     let upstream = get_single_file_mock(500).await;
@@ -56,9 +333,13 @@ async fn passes_errors_without_failing() {
 }
 
 #[tokio::test]
-async fn passes_upstream_headers() {
+async fn passes_allowed_upstream_headers() {
     let upstream = get_single_file_mock(200).await;
-    let proxy_res = run_proxy_request(&upstream).await.unwrap();
+    let mut settings = get_test_settings();
+    settings.pass_response_headers = vec!["x-upstream-header".parse().unwrap()];
+    let proxy_res = run_proxy_request_with_settings(settings, &upstream)
+        .await
+        .unwrap();
 
     assert_eq!(
         proxy_res.headers().get("x-upstream-header").unwrap(),
@@ -66,6 +347,18 @@ async fn passes_upstream_headers() {
     )
 }
 
+#[tokio::test]
+async fn strips_other_upstream_headers() {
+    let upstream = get_single_file_mock(200).await;
+    let proxy_res = run_proxy_request(&upstream).await.unwrap();
+
+    assert!(proxy_res.headers().get("x-upstream-header").is_none());
+    assert_eq!(
+        proxy_res.headers().get("content-type").unwrap(),
+        "image/webp"
+    );
+}
+
 #[tokio::test]
 async fn sets_the_camo_via_and_ua() {
     let upstream = get_single_file_mock_with_camo_headers().await;
@@ -74,6 +367,18 @@ async fn sets_the_camo_via_and_ua() {
     // no assertions - test will happen when the mock goes out of scope.
 }
 
+#[tokio::test]
+async fn sets_configured_upstream_headers() {
+    let upstream = get_single_file_mock_with_header("x-token", "secret").await;
+    let mut settings = get_test_settings();
+    settings.upstream_headers = vec!["127.0.0.1 X-Token: secret".parse().unwrap()];
+    let _ = run_proxy_request_with_settings(settings, &upstream)
+        .await
+        .unwrap();
+
+    // no assertions - test will happen when the mock goes out of scope.
+}
+
 #[tokio::test]
 async fn sets_the_original_url_response_header() {
     let upstream = get_single_file_mock(200).await;
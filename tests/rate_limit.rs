@@ -0,0 +1,105 @@
+use std::net::IpAddr;
+
+use camo_rs::{Settings, header_wrangler::HeaderPolicy, metrics::Metrics, rate_limit::*};
+use hyper::HeaderMap;
+
+pub mod helpers;
+use helpers::application::*;
+
+fn get_client_ip(trusted_proxy_hops: usize, headers: &[(&'static str, &str)]) -> IpAddr {
+    let mut settings = get_test_settings();
+    settings.rate_limit_requests = 1;
+    settings.trusted_proxy_hops = trusted_proxy_hops;
+
+    let mut header_map = HeaderMap::new();
+    for (name, value) in headers {
+        header_map.append(*name, value.parse().unwrap());
+    }
+
+    get_limiter(&settings).client_ip(&header_map, Some("10.0.0.1".parse().unwrap()))
+}
+
+fn get_limiter(settings: &Settings) -> RateLimiter {
+    RateLimiter::new(settings, HeaderPolicy::new(settings), Metrics::default()).unwrap()
+}
+
+#[test]
+fn is_disabled_without_limits() {
+    let settings = get_test_settings();
+    assert!(
+        RateLimiter::new(&settings, HeaderPolicy::new(&settings), Metrics::default()).is_none()
+    );
+}
+
+#[test]
+fn uses_the_peer_address_without_trusted_proxies() {
+    assert_eq!(
+        get_client_ip(0, &[("x-forwarded-for", "192.0.2.1")]),
+        "10.0.0.1".parse::<IpAddr>().unwrap()
+    );
+}
+
+#[test]
+fn uses_the_address_added_by_the_outermost_trusted_proxy() {
+    assert_eq!(
+        get_client_ip(1, &[("x-forwarded-for", "198.51.100.1, 192.0.2.1")]),
+        "192.0.2.1".parse::<IpAddr>().unwrap()
+    );
+    assert_eq!(
+        get_client_ip(
+            2,
+            &[("x-forwarded-for", "198.51.100.1, 192.0.2.1, 10.0.0.2")]
+        ),
+        "192.0.2.1".parse::<IpAddr>().unwrap()
+    );
+}
+
+#[test]
+fn uses_the_first_address_if_there_are_fewer_hops() {
+    assert_eq!(
+        get_client_ip(3, &[("x-forwarded-for", "192.0.2.1, 10.0.0.2")]),
+        "192.0.2.1".parse::<IpAddr>().unwrap()
+    );
+}
+
+#[test]
+fn prefers_the_forwarded_header() {
+    assert_eq!(
+        get_client_ip(
+            1,
+            &[
+                (
+                    "forwarded",
+                    "for=198.51.100.1, for=\"[2001:db8::1]:4711\";proto=https"
+                ),
+                ("x-forwarded-for", "192.0.2.1"),
+            ]
+        ),
+        "2001:db8::1".parse::<IpAddr>().unwrap()
+    );
+}
+
+#[test]
+fn falls_back_to_the_peer_address_for_invalid_addresses() {
+    assert_eq!(
+        get_client_ip(1, &[("x-forwarded-for", "unknown")]),
+        "10.0.0.1".parse::<IpAddr>().unwrap()
+    );
+    assert_eq!(get_client_ip(1, &[]), "10.0.0.1".parse::<IpAddr>().unwrap());
+}
+
+#[test]
+fn tracks_ipv6_clients_by_their_network() {
+    assert_eq!(
+        client_key("2001:db8:1:2:3:4:5:6".parse().unwrap()),
+        "2001:db8:1:2::".parse::<IpAddr>().unwrap()
+    );
+    assert_eq!(
+        client_key("::ffff:192.0.2.1".parse().unwrap()),
+        "192.0.2.1".parse::<IpAddr>().unwrap()
+    );
+    assert_eq!(
+        client_key("192.0.2.1".parse().unwrap()),
+        "192.0.2.1".parse::<IpAddr>().unwrap()
+    );
+}
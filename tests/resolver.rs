@@ -0,0 +1,27 @@
+use std::str::FromStr;
+
+use hyper_util::client::legacy::connect::dns::Name;
+use tower_service::Service;
+
+use camo_rs::{resolver::*, settings::IpFamily};
+
+async fn resolve_localhost(family: IpFamily) -> std::io::Result<Vec<std::net::SocketAddr>> {
+    let mut resolver = FamilyResolver::new(family);
+    let addrs = resolver.call(Name::from_str("localhost").unwrap()).await?;
+    Ok(addrs.collect())
+}
+
+#[tokio::test]
+async fn only_returns_addresses_of_the_allowed_family() {
+    let addrs = resolve_localhost(IpFamily::Ipv4).await.unwrap();
+
+    assert!(!addrs.is_empty());
+    assert!(addrs.iter().all(|addr| addr.is_ipv4()));
+}
+
+#[tokio::test]
+async fn sorts_addresses_by_the_preferred_family() {
+    let addrs = resolve_localhost(IpFamily::PreferIpv4).await.unwrap();
+
+    assert!(addrs.first().unwrap().is_ipv4());
+}
@@ -1,12 +1,20 @@
 use std::net::SocketAddr;
 
 use tokio::net::TcpListener;
-use wiremock::MockServer;
+use wiremock::{
+    Mock, MockServer, ResponseTemplate,
+    matchers::{method, path},
+};
 
-use camo_rs::{AuthenticatedTarget, Settings, server::*};
+use camo_rs::{
+    AuthenticatedTarget, Settings,
+    image_transform::{Fit, ImageTransform},
+    server::*,
+    settings::{ContentSniffing, SvgHandling},
+};
 
 pub mod helpers;
-use helpers::{application::*, wiremock::*};
+use helpers::{application::*, tcp::*, wiremock::*};
 
 async fn run_test_server(mut settings: Settings) -> (SocketAddr, reqwest::Client) {
     let listener = TcpListener::bind("127.0.0.1:0")
@@ -20,10 +28,14 @@ async fn run_test_server(mut settings: Settings) -> (SocketAddr, reqwest::Client
 
     settings.root_url = format!("http://{listen_addr}/");
 
+    let router = build(settings).unwrap();
     tokio::spawn(async move {
-        axum::serve(listener, build(settings).into_make_service())
-            .await
-            .unwrap()
+        axum::serve(
+            listener,
+            router.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap()
     });
 
     (listen_addr, client)
@@ -70,6 +82,65 @@ async fn rejects_invalid_targets() {
     assert_eq!(resp.status(), 403);
 }
 
+#[tokio::test]
+async fn sets_security_headers_on_error_responses() {
+    let (listen_addr, client) = run_test_server(get_test_settings()).await;
+    let auth_target =
+        AuthenticatedTarget::from_target("some random key".as_bytes(), "http://example.com");
+
+    let resp = client
+        .get(get_test_url(listen_addr, &auth_target))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 403);
+    assert_eq!(
+        resp.headers().get("x-content-type-options").unwrap(),
+        "nosniff"
+    );
+}
+
+#[tokio::test]
+async fn sets_configured_security_headers() {
+    let mut settings = get_test_settings();
+    settings.security_headers = vec![
+        "Cross-Origin-Resource-Policy: cross-origin"
+            .parse()
+            .unwrap(),
+    ];
+    settings.strip_security_headers = vec!["x-xss-protection".parse().unwrap()];
+    let upstream = get_single_file_mock(200).await;
+    let resp = run_valid_upstream_request(settings, &upstream)
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        resp.headers().get("cross-origin-resource-policy").unwrap(),
+        "cross-origin"
+    );
+    assert!(resp.headers().get("x-xss-protection").is_none());
+}
+
+#[tokio::test]
+async fn rejects_hotlinked_requests() {
+    let mut settings = get_test_settings();
+    settings.hotlink_allow_hosts = vec!["example.com".to_owned()];
+    let (listen_addr, client) = run_test_server(settings).await;
+
+    // The upstream is never requested, so there's no need for a mock.
+    let auth_target = AuthenticatedTarget::from_target("camo-rs".as_bytes(), "http://127.0.0.1:1/");
+    let resp = client
+        .get(get_test_url(listen_addr, &auth_target))
+        .header("referer", "https://evil.com/")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 403);
+}
+
 #[tokio::test]
 async fn rejects_but_forwards_unexpected_status_codes() {
     let upstream = get_single_file_mock(418).await;
@@ -80,6 +151,44 @@ async fn rejects_but_forwards_unexpected_status_codes() {
     assert_eq!(resp.status(), 418);
 }
 
+#[tokio::test]
+async fn rejects_timeouting_upstreams_with_gateway_timeout() {
+    let mut settings = get_test_settings();
+    settings.upstream_timeout = 1;
+    let upstream = get_single_slow_file_mock().await;
+    let resp = run_valid_upstream_request(settings, &upstream)
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 504);
+}
+
+#[tokio::test]
+async fn rejects_requests_to_failing_hosts_with_service_unavailable() {
+    let mut settings = get_test_settings();
+    settings.upstream_circuit_breaker_threshold = 1;
+    let upstream = get_flaky_file_mock(500, 1, 0).await;
+    let auth_target = AuthenticatedTarget::from_target(settings.key.as_bytes(), &upstream.uri());
+    let (listen_addr, client) = run_test_server(settings).await;
+    let url = get_test_url(listen_addr, &auth_target);
+
+    let resp = client.get(&url).send().await.unwrap();
+    assert_eq!(resp.status(), 500);
+
+    let resp = client.get(&url).send().await.unwrap();
+    assert_eq!(resp.status(), 503);
+
+    let metrics = client
+        .get(format!("http://{listen_addr}/__metrics__"))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("camo_upstream_circuits_open 1"));
+}
+
 #[tokio::test]
 async fn rejects_long_responses() {
     let upstream = get_long_response_mock().await;
@@ -134,6 +243,128 @@ async fn passes_invalid_content_type_if_configured() {
     assert_eq!(resp.status(), 200);
 }
 
+async fn run_range_request(
+    mut settings: Settings,
+    upstream: &MockServer,
+    range: &str,
+) -> reqwest::Response {
+    settings.length_limit = 1024;
+    let auth_target = AuthenticatedTarget::from_target(settings.key.as_bytes(), &upstream.uri());
+    let (listen_addr, client) = run_test_server(settings).await;
+
+    client
+        .get(get_test_url(listen_addr, &auth_target))
+        .header("range", range)
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn passes_partial_responses() {
+    let upstream = get_range_mock(
+        Some("bytes=0-4"),
+        206,
+        Some("bytes 0-4/11"),
+        "hello",
+        "image/webp",
+    )
+    .await;
+    let resp = run_range_request(get_test_settings(), &upstream, "bytes=0-4").await;
+
+    assert_eq!(resp.status(), 206);
+    assert_eq!(resp.headers().get("content-range").unwrap(), "bytes 0-4/11");
+    assert_eq!(resp.text().await.unwrap(), "hello");
+}
+
+#[tokio::test]
+async fn rejects_partial_responses_exceeding_the_length_limit() {
+    let upstream = get_range_mock(
+        Some("bytes=0-4"),
+        206,
+        Some("bytes 0-4/2048"),
+        "hello",
+        "image/webp",
+    )
+    .await;
+    let resp = run_range_request(get_test_settings(), &upstream, "bytes=0-4").await;
+
+    assert_eq!(resp.status(), 422);
+}
+
+#[tokio::test]
+async fn rejects_partial_responses_without_content_range() {
+    let upstream = get_range_mock(Some("bytes=0-4"), 206, None, "hello", "image/webp").await;
+    let resp = run_range_request(get_test_settings(), &upstream, "bytes=0-4").await;
+
+    assert_eq!(resp.status(), 422);
+}
+
+#[tokio::test]
+async fn rejects_partial_responses_with_invalid_content_type() {
+    let upstream = get_range_mock(
+        Some("bytes=0-4"),
+        206,
+        Some("bytes 0-4/11"),
+        "hello",
+        "text/plain",
+    )
+    .await;
+    let resp = run_range_request(get_test_settings(), &upstream, "bytes=0-4").await;
+
+    assert_eq!(resp.status(), 422);
+}
+
+#[tokio::test]
+async fn rejects_partial_responses_to_requests_without_a_range() {
+    let upstream = get_range_mock(
+        None,
+        206,
+        Some("bytes 0-12/14"),
+        "<h1>evil</h1>",
+        "image/png",
+    )
+    .await;
+    let settings = get_test_settings();
+    let auth_target = AuthenticatedTarget::from_target(settings.key.as_bytes(), &upstream.uri());
+
+    let (listen_addr, client) = run_test_server(settings).await;
+    let resp = client
+        .get(get_test_url(listen_addr, &auth_target))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 422);
+}
+
+#[tokio::test]
+async fn requests_full_responses_for_multiple_ranges() {
+    let upstream = get_range_mock(None, 200, None, "hello", "image/webp").await;
+    let resp = run_range_request(get_test_settings(), &upstream, "bytes=0-1,3-4").await;
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().await.unwrap(), "hello");
+}
+
+#[tokio::test]
+async fn passes_unsatisfiable_ranges() {
+    let upstream = get_range_mock(
+        Some("bytes=100-"),
+        416,
+        Some("bytes */11"),
+        "<h1>nope</h1>",
+        "text/html",
+    )
+    .await;
+    let resp = run_range_request(get_test_settings(), &upstream, "bytes=100-").await;
+
+    assert_eq!(resp.status(), 416);
+    assert_eq!(resp.headers().get("content-range").unwrap(), "bytes */11");
+    assert!(resp.headers().get("content-type").is_none());
+    assert_eq!(resp.text().await.unwrap(), "");
+}
+
 #[tokio::test]
 async fn rewrites_redirects_to_camo_urls() {
     let settings = get_test_settings();
@@ -188,3 +419,1264 @@ async fn rewrites_relative_redirects_to_absolute_camo_urls() {
         format!("http://{listen_addr}/{expected_target}")
     );
 }
+
+async fn run_concurrent_upstream_requests(
+    settings: Settings,
+    upstream: &MockServer,
+    count: usize,
+) -> Vec<(u16, String)> {
+    let auth_target = AuthenticatedTarget::from_target(settings.key.as_bytes(), &upstream.uri());
+    let (listen_addr, client) = run_test_server(settings).await;
+    let url = get_test_url(listen_addr, &auth_target);
+
+    let requests = (0..count).map(|_| {
+        let client = client.clone();
+        let url = url.clone();
+        tokio::spawn(async move {
+            let resp = client.get(url).send().await.unwrap();
+            (resp.status().as_u16(), resp.text().await.unwrap())
+        })
+    });
+
+    let mut results = vec![];
+    for request in requests.collect::<Vec<_>>() {
+        results.push(request.await.unwrap());
+    }
+    results
+}
+
+#[tokio::test]
+async fn coalesces_concurrent_requests() {
+    let mut settings = get_test_settings();
+    settings.coalesce_requests = true;
+    settings.length_limit = 1024;
+    let upstream = get_delayed_file_mock(200, "hello", 1).await;
+
+    let results = run_concurrent_upstream_requests(settings, &upstream, 5).await;
+
+    assert!(results.iter().all(|res| *res == (200, "hello".to_owned())));
+}
+
+#[tokio::test]
+async fn coalesces_requests_for_bodies_larger_than_the_buffer() {
+    let mut settings = get_test_settings();
+    settings.coalesce_requests = true;
+    settings.length_limit = 4 * 1024 * 1024;
+    let body = "camo".repeat(768 * 1024);
+    let upstream = get_delayed_file_mock(200, &body, 1).await;
+
+    let results = run_concurrent_upstream_requests(settings, &upstream, 5).await;
+
+    assert!(results.iter().all(|res| *res == (200, body.clone())));
+}
+
+#[tokio::test]
+async fn coalesces_failing_requests() {
+    let mut settings = get_test_settings();
+    settings.coalesce_requests = true;
+    let upstream = get_delayed_file_mock(418, "", 1).await;
+
+    let results = run_concurrent_upstream_requests(settings, &upstream, 5).await;
+
+    assert!(results.iter().all(|(status, _)| *status == 418));
+}
+
+#[tokio::test]
+async fn does_not_coalesce_by_default() {
+    let mut settings = get_test_settings();
+    settings.length_limit = 1024;
+    let upstream = get_delayed_file_mock(200, "hello", 5).await;
+
+    let results = run_concurrent_upstream_requests(settings, &upstream, 5).await;
+
+    assert!(results.iter().all(|res| *res == (200, "hello".to_owned())));
+}
+
+#[tokio::test]
+async fn sheds_requests_beyond_the_in_flight_limit() {
+    let mut settings = get_test_settings();
+    settings.in_flight_limit = 1;
+    settings.length_limit = 1024;
+    let upstream = get_delayed_file_mock(200, "hello", 1).await;
+    let auth_target = AuthenticatedTarget::from_target(settings.key.as_bytes(), &upstream.uri());
+    let (listen_addr, client) = run_test_server(settings).await;
+    let url = get_test_url(listen_addr, &auth_target);
+
+    let first_req = tokio::spawn(client.get(&url).send());
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    let resp = client.get(&url).send().await.unwrap();
+    assert_eq!(resp.status(), 503);
+    assert_eq!(resp.headers().get("retry-after").unwrap(), "1");
+
+    let resp = client
+        .get(format!("http://{listen_addr}/__heartbeat__"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    assert_eq!(first_req.await.unwrap().unwrap().status(), 200);
+}
+
+#[tokio::test]
+async fn queues_requests_beyond_the_in_flight_limit() {
+    let mut settings = get_test_settings();
+    settings.in_flight_limit = 1;
+    settings.queue_limit = 2;
+    settings.length_limit = 1024;
+    let upstream = get_delayed_file_mock(200, "hello", 3).await;
+
+    let results = run_concurrent_upstream_requests(settings, &upstream, 3).await;
+
+    assert!(results.iter().all(|res| *res == (200, "hello".to_owned())));
+}
+
+async fn run_cors_request(
+    method: reqwest::Method,
+    origin: &str,
+    target: &str,
+) -> reqwest::Response {
+    let mut settings = get_test_settings();
+    settings.cors_allow_origins = vec!["https://app.example.com".to_owned()];
+    let auth_target = AuthenticatedTarget::from_target(settings.key.as_bytes(), target);
+    let (listen_addr, client) = run_test_server(settings).await;
+
+    client
+        .request(method, get_test_url(listen_addr, &auth_target))
+        .header("origin", origin)
+        .header("access-control-request-method", "GET")
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn answers_options_requests_locally() {
+    let (listen_addr, client) = run_test_server(get_test_settings()).await;
+    let auth_target = AuthenticatedTarget::from_target("camo-rs".as_bytes(), "http://127.0.0.1:1/");
+
+    let resp = client
+        .request(
+            reqwest::Method::OPTIONS,
+            get_test_url(listen_addr, &auth_target),
+        )
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 204);
+    assert_eq!(resp.headers().get("allow").unwrap(), "GET, HEAD, OPTIONS");
+    assert!(resp.headers().get("access-control-allow-origin").is_none());
+}
+
+#[tokio::test]
+async fn answers_cors_preflight_requests() {
+    // OPTIONS requests never reach the upstream, so none is needed.
+    let resp = run_cors_request(
+        reqwest::Method::OPTIONS,
+        "https://app.example.com",
+        "http://127.0.0.1:1/",
+    )
+    .await;
+
+    assert_eq!(resp.status(), 204);
+    assert_eq!(
+        resp.headers().get("access-control-allow-origin").unwrap(),
+        "https://app.example.com"
+    );
+    assert_eq!(
+        resp.headers().get("access-control-allow-methods").unwrap(),
+        "GET, HEAD"
+    );
+    assert_eq!(
+        resp.headers().get("access-control-max-age").unwrap(),
+        "86400"
+    );
+}
+
+#[tokio::test]
+async fn sets_cors_headers_for_allowed_origins() {
+    let upstream = get_single_file_mock(200).await;
+    let resp = run_cors_request(
+        reqwest::Method::GET,
+        "https://app.example.com",
+        &upstream.uri(),
+    )
+    .await;
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        resp.headers().get("access-control-allow-origin").unwrap(),
+        "https://app.example.com"
+    );
+    assert_eq!(resp.headers().get("vary").unwrap(), "origin");
+}
+
+#[tokio::test]
+async fn does_not_set_cors_headers_for_other_origins() {
+    let upstream = get_single_file_mock(200).await;
+    let resp = run_cors_request(
+        reqwest::Method::GET,
+        "https://evil.example.com",
+        &upstream.uri(),
+    )
+    .await;
+
+    assert_eq!(resp.status(), 200);
+    assert!(resp.headers().get("access-control-allow-origin").is_none());
+}
+
+#[tokio::test]
+async fn rate_limits_requests_per_client() {
+    let mut settings = get_test_settings();
+    settings.rate_limit_requests = 1;
+    settings.rate_limit_burst = 1;
+    settings.trusted_proxy_hops = 1;
+    settings.length_limit = 1024;
+    let upstream = get_delayed_file_mock(200, "hello", 2).await;
+    let auth_target = AuthenticatedTarget::from_target(settings.key.as_bytes(), &upstream.uri());
+    let (listen_addr, client) = run_test_server(settings).await;
+    let url = get_test_url(listen_addr, &auth_target);
+
+    let send =
+        |client_ip: &'static str| client.get(&url).header("x-forwarded-for", client_ip).send();
+
+    assert_eq!(send("192.0.2.1").await.unwrap().status(), 200);
+
+    let resp = send("192.0.2.1").await.unwrap();
+    assert_eq!(resp.status(), 429);
+    assert_eq!(resp.headers().get("retry-after").unwrap(), "1");
+    assert_eq!(
+        resp.headers().get("x-content-type-options").unwrap(),
+        "nosniff"
+    );
+
+    assert_eq!(send("192.0.2.2").await.unwrap().status(), 200);
+}
+
+#[tokio::test]
+async fn rate_limits_bytes_per_client() {
+    let mut settings = get_test_settings();
+    settings.rate_limit_bytes = 1;
+    settings.rate_limit_burst = 1;
+    settings.length_limit = 1024;
+    let upstream = get_delayed_file_mock(200, "hello", 1).await;
+    let auth_target = AuthenticatedTarget::from_target(settings.key.as_bytes(), &upstream.uri());
+    let (listen_addr, client) = run_test_server(settings).await;
+    let url = get_test_url(listen_addr, &auth_target);
+
+    let resp = client.get(&url).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+    resp.bytes().await.unwrap();
+
+    let resp = client.get(&url).send().await.unwrap();
+    assert_eq!(resp.status(), 429);
+    assert!(resp.headers().contains_key("retry-after"));
+}
+
+async fn run_sniffing_request(
+    mode: ContentSniffing,
+    body: &[u8],
+    content_type: &str,
+) -> reqwest::Response {
+    let mut settings = get_test_settings();
+    settings.content_sniffing = mode;
+    settings.length_limit = 1024;
+    let upstream = get_body_mock(body, content_type).await;
+    run_valid_upstream_request(settings, &upstream)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn passes_bodies_matching_the_content_type() {
+    let body = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    let resp = run_sniffing_request(ContentSniffing::Reject, body, "image/png").await;
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/png");
+    assert_eq!(resp.bytes().await.unwrap(), body.as_slice());
+}
+
+#[tokio::test]
+async fn rejects_bodies_contradicting_the_content_type() {
+    let resp = run_sniffing_request(
+        ContentSniffing::Reject,
+        b"<!DOCTYPE html><script>alert(1)</script>",
+        "image/png",
+    )
+    .await;
+
+    assert_eq!(resp.status(), 422);
+}
+
+#[tokio::test]
+async fn does_not_sniff_compressed_bodies() {
+    let mut settings = get_test_settings();
+    settings.content_sniffing = ContentSniffing::Reject;
+    settings.length_limit = 1024;
+    let upstream = get_encoded_body_mock(b"\x1f\x8b\x08\0\0\0\0\0", "image/svg+xml", "gzip").await;
+    let resp = run_valid_upstream_request(settings, &upstream)
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/svg+xml");
+    assert_eq!(resp.headers().get("content-encoding").unwrap(), "gzip");
+}
+
+#[tokio::test]
+async fn corrects_contradicting_content_types() {
+    let body = b"GIF89a\x01\0\x01\0";
+    let resp = run_sniffing_request(ContentSniffing::Correct, body, "image/png").await;
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/gif");
+    assert_eq!(resp.bytes().await.unwrap(), body.as_slice());
+}
+
+#[tokio::test]
+async fn rejects_corrected_content_types_that_are_not_allowed() {
+    let resp = run_sniffing_request(
+        ContentSniffing::Correct,
+        b"<!DOCTYPE html><script>alert(1)</script>",
+        "image/png",
+    )
+    .await;
+
+    assert_eq!(resp.status(), 422);
+}
+
+async fn run_inference_request(
+    from_url: bool,
+    upstream: &MockServer,
+    path: &str,
+) -> reqwest::Response {
+    let mut settings = get_test_settings();
+    settings.infer_content_type = true;
+    settings.infer_content_type_from_url = from_url;
+    settings.length_limit = 1024;
+    let target = format!("{}{path}", upstream.uri());
+    let auth_target = AuthenticatedTarget::from_target(settings.key.as_bytes(), &target);
+
+    let (listen_addr, client) = run_test_server(settings).await;
+    client
+        .get(get_test_url(listen_addr, &auth_target))
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn infers_generic_content_types_from_the_body() {
+    let body = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    let upstream = get_body_mock(body, "application/octet-stream").await;
+    let resp = run_inference_request(false, &upstream, "/").await;
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/png");
+    assert_eq!(resp.bytes().await.unwrap(), body.as_slice());
+}
+
+#[tokio::test]
+async fn applies_the_policy_to_inferred_content_types() {
+    let upstream = get_body_mock(b"<html><body>hi</body></html>", "application/octet-stream").await;
+    let resp = run_inference_request(false, &upstream, "/").await;
+
+    assert_eq!(resp.status(), 422);
+}
+
+#[tokio::test]
+async fn infers_content_types_from_the_url_if_enabled() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/cat.jpg"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(b"not sniffable".to_vec()))
+        .expect(2)
+        .mount(&upstream)
+        .await;
+
+    let resp = run_inference_request(false, &upstream, "/cat.jpg").await;
+    assert_eq!(resp.status(), 422);
+
+    let resp = run_inference_request(true, &upstream, "/cat.jpg").await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/jpeg");
+}
+
+#[tokio::test]
+async fn infers_content_types_of_compressed_bodies_from_the_url() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/image.svg"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_raw(
+                    b"\x1f\x8b\x08\0\0\0\0\0".to_vec(),
+                    "application/octet-stream",
+                )
+                .insert_header("content-encoding", "gzip"),
+        )
+        .expect(1)
+        .mount(&upstream)
+        .await;
+    let resp = run_inference_request(true, &upstream, "/image.svg").await;
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/svg+xml");
+}
+
+/// Sends a request that asks for a compressed response, like browsers do.
+async fn run_compressible_request(settings: Settings, upstream: &MockServer) -> reqwest::Response {
+    let auth_target = AuthenticatedTarget::from_target(settings.key.as_bytes(), &upstream.uri());
+
+    let (listen_addr, client) = run_test_server(settings).await;
+    client
+        .get(get_test_url(listen_addr, &auth_target))
+        .header("accept-encoding", "gzip, br")
+        .send()
+        .await
+        .unwrap()
+}
+
+const TEST_SVG: &[u8] = br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="4" onload="alert(1)"><rect width="4" height="4"/></svg>"#;
+
+async fn run_svg_request(handling: SvgHandling) -> reqwest::Response {
+    let mut settings = get_test_settings();
+    settings.svg_handling = handling;
+    settings.length_limit = 1024;
+    let upstream = get_body_mock(TEST_SVG, "image/svg+xml").await;
+    run_valid_upstream_request(settings, &upstream)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn sanitizes_svgs() {
+    let resp = run_svg_request(SvgHandling::Sanitize).await;
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/svg+xml");
+    let body = resp.text().await.unwrap();
+    assert!(body.starts_with("<svg"));
+    assert!(!body.contains("alert"));
+}
+
+#[tokio::test]
+async fn requests_uncompressed_svgs_for_processing() {
+    let mut settings = get_test_settings();
+    settings.svg_handling = SvgHandling::Sanitize;
+    settings.length_limit = 1024;
+    let upstream = get_uncompressed_body_mock(TEST_SVG, "image/svg+xml").await;
+    let resp = run_compressible_request(settings, &upstream).await;
+
+    assert_eq!(resp.status(), 200);
+    assert!(!resp.text().await.unwrap().contains("alert"));
+}
+
+#[tokio::test]
+async fn rasterizes_svgs() {
+    let resp = run_svg_request(SvgHandling::Rasterize).await;
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/png");
+    assert!(resp.bytes().await.unwrap().starts_with(b"\x89PNG"));
+}
+
+#[tokio::test]
+async fn blocks_svgs() {
+    let resp = run_svg_request(SvgHandling::Block).await;
+
+    assert_eq!(resp.status(), 422);
+}
+
+#[tokio::test]
+async fn rejects_svgs_exceeding_the_size_limit() {
+    let mut settings = get_test_settings();
+    settings.svg_handling = SvgHandling::Sanitize;
+    settings.svg_size_limit = 16;
+    settings.length_limit = 1024;
+    let upstream = get_body_mock(TEST_SVG, "image/svg+xml").await;
+    let resp = run_valid_upstream_request(settings, &upstream)
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 422);
+}
+
+#[tokio::test]
+async fn strips_metadata_from_images() {
+    let mut settings = get_test_settings();
+    settings.strip_metadata = true;
+    settings.length_limit = 1024;
+    let jpeg = b"\xff\xd8\xff\xe1\0\x0aExif\0\0GP\xff\xda\0\x02\x12\xff\xd9";
+    let upstream = get_body_mock(jpeg, "image/jpeg").await;
+    let resp = run_valid_upstream_request(settings, &upstream)
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-length").unwrap(), "9");
+    assert_eq!(
+        resp.bytes().await.unwrap(),
+        b"\xff\xd8\xff\xda\0\x02\x12\xff\xd9".as_slice()
+    );
+}
+
+#[tokio::test]
+async fn strips_metadata_from_images_requested_with_a_range() {
+    let mut settings = get_test_settings();
+    settings.strip_metadata = true;
+    settings.length_limit = 1024;
+    let jpeg = b"\xff\xd8\xff\xe1\0\x0aExif\0\0GP\xff\xda\0\x02\x12\xff\xd9";
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/"))
+        .and(|req: &wiremock::Request| req.headers.contains_key("range"))
+        .respond_with(
+            ResponseTemplate::new(206)
+                .set_body_raw(jpeg[..12].to_vec(), "image/jpeg")
+                .insert_header("content-range", "bytes 0-11/21"),
+        )
+        .expect(1)
+        .mount(&upstream)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/"))
+        .and(|req: &wiremock::Request| !req.headers.contains_key("range"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(jpeg.to_vec(), "image/jpeg"))
+        .expect(1)
+        .mount(&upstream)
+        .await;
+    let auth_target = AuthenticatedTarget::from_target(settings.key.as_bytes(), &upstream.uri());
+
+    let (listen_addr, client) = run_test_server(settings).await;
+    let resp = client
+        .get(get_test_url(listen_addr, &auth_target))
+        .header("range", "bytes=0-11")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        resp.bytes().await.unwrap(),
+        b"\xff\xd8\xff\xda\0\x02\x12\xff\xd9".as_slice()
+    );
+}
+
+#[tokio::test]
+async fn requests_uncompressed_images_for_stripping_metadata() {
+    let mut settings = get_test_settings();
+    settings.strip_metadata = true;
+    settings.length_limit = 1024;
+    let jpeg = b"\xff\xd8\xff\xe1\0\x0aExif\0\0GP\xff\xda\0\x02\x12\xff\xd9";
+    let upstream = get_uncompressed_body_mock(jpeg, "image/jpeg").await;
+    let resp = run_compressible_request(settings, &upstream).await;
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-length").unwrap(), "9");
+}
+
+fn get_test_png(width: u32, height: u32) -> Vec<u8> {
+    let mut png = std::io::Cursor::new(Vec::new());
+    image::RgbImage::new(width, height)
+        .write_to(&mut png, image::ImageFormat::Png)
+        .unwrap();
+    png.into_inner()
+}
+
+async fn run_transform_request(
+    settings: Settings,
+    upstream: &MockServer,
+    transform: ImageTransform,
+) -> reqwest::Response {
+    let auth_target = AuthenticatedTarget::from_target_with_transform(
+        settings.key.as_bytes(),
+        &upstream.uri(),
+        transform,
+    );
+
+    let (listen_addr, client) = run_test_server(settings).await;
+    client
+        .get(get_test_url(listen_addr, &auth_target))
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn does_not_resize_images_that_are_too_large_to_decode() {
+    let mut settings = get_test_settings();
+    settings.image_transforms = true;
+    settings.length_limit = 4096;
+    let png = get_png_header(20000, 20000, None);
+    let upstream = get_body_mock(&png, "image/png").await;
+    let transform = ImageTransform {
+        width: Some(64),
+        ..Default::default()
+    };
+    let resp = run_transform_request(settings, &upstream, transform).await;
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.bytes().await.unwrap(), png.as_slice());
+}
+
+#[tokio::test]
+async fn requests_uncompressed_images_for_transforms() {
+    let mut settings = get_test_settings();
+    settings.image_transforms = true;
+    settings.length_limit = 4096;
+    let upstream = get_uncompressed_body_mock(&get_test_png(200, 100), "image/png").await;
+    let transform = ImageTransform {
+        width: Some(50),
+        ..Default::default()
+    };
+    let auth_target = AuthenticatedTarget::from_target_with_transform(
+        settings.key.as_bytes(),
+        &upstream.uri(),
+        transform,
+    );
+
+    let (listen_addr, client) = run_test_server(settings).await;
+    let resp = client
+        .get(get_test_url(listen_addr, &auth_target))
+        .header("accept-encoding", "gzip, br")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    let image = image::load_from_memory(&resp.bytes().await.unwrap()).unwrap();
+    assert_eq!(image.width(), 50);
+}
+
+#[tokio::test]
+async fn resizes_images() {
+    let mut settings = get_test_settings();
+    settings.image_transforms = true;
+    settings.length_limit = 4096;
+    let upstream = get_body_mock(&get_test_png(200, 100), "image/png").await;
+    let transform = ImageTransform {
+        width: Some(50),
+        ..Default::default()
+    };
+    let resp = run_transform_request(settings, &upstream, transform).await;
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/png");
+    let image = image::load_from_memory(&resp.bytes().await.unwrap()).unwrap();
+    assert_eq!((image.width(), image.height()), (50, 25));
+}
+
+#[tokio::test]
+async fn keeps_the_transform_when_rewriting_redirects() {
+    let mut settings = get_test_settings();
+    settings.image_transforms = true;
+    let redirect_target = "https://example.com/another-image";
+    let upstream = get_redirect_mock(redirect_target).await;
+    let key = settings.key.clone();
+    let transform = ImageTransform {
+        width: Some(50),
+        ..Default::default()
+    };
+    let resp = run_transform_request(settings, &upstream, transform.clone()).await;
+
+    let expected_target =
+        AuthenticatedTarget::from_target_with_transform(key.as_bytes(), redirect_target, transform)
+            .encoded_full_path();
+    assert_eq!(resp.status(), 302);
+    let location = resp.headers().get("location").unwrap().to_str().unwrap();
+    assert!(location.ends_with(&format!("/{expected_target}")));
+    assert!(location.ends_with("?w=50"));
+}
+
+#[tokio::test]
+async fn crops_images_to_cover() {
+    let mut settings = get_test_settings();
+    settings.image_transforms = true;
+    settings.length_limit = 4096;
+    let upstream = get_body_mock(&get_test_png(200, 100), "image/png").await;
+    let transform = ImageTransform {
+        width: Some(40),
+        height: Some(40),
+        fit: Fit::Cover,
+        quality: None,
+    };
+    let resp = run_transform_request(settings, &upstream, transform).await;
+
+    assert_eq!(resp.status(), 200);
+    let image = image::load_from_memory(&resp.bytes().await.unwrap()).unwrap();
+    assert_eq!((image.width(), image.height()), (40, 40));
+}
+
+#[tokio::test]
+async fn rejects_unsigned_transform_parameters() {
+    let mut settings = get_test_settings();
+    settings.image_transforms = true;
+    let upstream = MockServer::start().await;
+    let auth_target = AuthenticatedTarget::from_target(settings.key.as_bytes(), &upstream.uri());
+
+    let (listen_addr, client) = run_test_server(settings).await;
+    let resp = client
+        .get(format!("{}?w=50", get_test_url(listen_addr, &auth_target)))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 403);
+}
+
+#[tokio::test]
+async fn rejects_transforms_exceeding_the_limits() {
+    let mut settings = get_test_settings();
+    settings.image_transforms = true;
+    settings.transform_max_width = 100;
+    let upstream = MockServer::start().await;
+    let transform = ImageTransform {
+        width: Some(101),
+        ..Default::default()
+    };
+    let resp = run_transform_request(settings, &upstream, transform).await;
+
+    assert_eq!(resp.status(), 400);
+}
+
+#[tokio::test]
+async fn ignores_query_strings_without_image_transforms() {
+    let mut settings = get_test_settings();
+    settings.length_limit = 4096;
+    let png = get_test_png(200, 100);
+    let upstream = get_body_mock(&png, "image/png").await;
+    let transform = ImageTransform {
+        width: Some(50),
+        ..Default::default()
+    };
+    let auth_target = AuthenticatedTarget::from_target(settings.key.as_bytes(), &upstream.uri());
+    let signed_transform = AuthenticatedTarget::from_target_with_transform(
+        settings.key.as_bytes(),
+        &upstream.uri(),
+        transform,
+    );
+    let (listen_addr, client) = run_test_server(settings).await;
+    let resp = client
+        .get(format!(
+            "{}?{}",
+            get_test_url(listen_addr, &auth_target),
+            signed_transform.transform().unwrap().to_query()
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.bytes().await.unwrap(), png);
+}
+
+async fn run_transcode_request(
+    settings: Settings,
+    upstream: &MockServer,
+    accept: &str,
+) -> reqwest::Response {
+    let auth_target = AuthenticatedTarget::from_target(settings.key.as_bytes(), &upstream.uri());
+
+    let (listen_addr, client) = run_test_server(settings).await;
+    client
+        .get(get_test_url(listen_addr, &auth_target))
+        .header("accept", accept)
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn transcodes_images_to_webp() {
+    let mut settings = get_test_settings();
+    settings.transcode_images = true;
+    settings.length_limit = 4096;
+    let upstream = get_body_mock(&get_test_png(200, 100), "image/png").await;
+    let resp = run_transcode_request(settings, &upstream, "image/webp,image/*;q=0.8").await;
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/webp");
+    assert_eq!(resp.headers().get("vary").unwrap(), "accept");
+    let image = image::load_from_memory(&resp.bytes().await.unwrap()).unwrap();
+    assert_eq!((image.width(), image.height()), (200, 100));
+}
+
+#[tokio::test]
+async fn does_not_transcode_without_webp_in_accept() {
+    let mut settings = get_test_settings();
+    settings.transcode_images = true;
+    settings.length_limit = 4096;
+    let png = get_test_png(200, 100);
+    let upstream = get_body_mock(&png, "image/png").await;
+    let resp = run_transcode_request(settings, &upstream, "image/*,*/*;q=0.8").await;
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/png");
+    assert_eq!(resp.headers().get("vary").unwrap(), "accept");
+    assert_eq!(resp.bytes().await.unwrap(), png);
+}
+
+#[tokio::test]
+async fn does_not_transcode_jpegs() {
+    let mut settings = get_test_settings();
+    settings.transcode_images = true;
+    settings.length_limit = 4096;
+    let mut jpeg = std::io::Cursor::new(Vec::new());
+    image::RgbImage::new(20, 10)
+        .write_to(&mut jpeg, image::ImageFormat::Jpeg)
+        .unwrap();
+    let jpeg = jpeg.into_inner();
+    let upstream = get_body_mock(&jpeg, "image/jpeg").await;
+    let resp = run_transcode_request(settings, &upstream, "image/webp").await;
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/jpeg");
+    assert!(resp.headers().get("vary").is_none());
+    assert_eq!(resp.bytes().await.unwrap(), jpeg);
+}
+
+#[tokio::test]
+async fn passes_undecodable_images_instead_of_transcoding() {
+    let mut settings = get_test_settings();
+    settings.transcode_images = true;
+    settings.length_limit = 1024;
+    let upstream = get_body_mock(b"not really a png", "image/png").await;
+    let resp = run_transcode_request(settings, &upstream, "image/webp").await;
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/png");
+    assert_eq!(resp.bytes().await.unwrap(), b"not really a png".as_slice());
+}
+
+/// A PNG that only has a header, declaring the given size.
+fn get_png_header(width: u32, height: u32, frames: Option<u32>) -> Vec<u8> {
+    let chunk = |chunk_type: &[u8; 4], data: &[u8]| {
+        [
+            &(data.len() as u32).to_be_bytes()[..],
+            chunk_type,
+            data,
+            &[0, 0, 0, 0],
+        ]
+        .concat()
+    };
+
+    let ihdr = [
+        &width.to_be_bytes()[..],
+        &height.to_be_bytes(),
+        &[8, 6, 0, 0, 0],
+    ]
+    .concat();
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    png.extend_from_slice(&chunk(b"IHDR", &ihdr));
+    if let Some(frames) = frames {
+        png.extend_from_slice(&chunk(b"acTL", &[frames.to_be_bytes(), [0; 4]].concat()));
+    }
+    png.extend_from_slice(&chunk(b"IDAT", &[]));
+    png
+}
+
+#[tokio::test]
+async fn rejects_images_over_the_pixel_limit() {
+    let mut settings = get_test_settings();
+    settings.image_max_pixels = 1_000_000;
+    settings.length_limit = 1024;
+    let upstream = get_body_mock(&get_png_header(100_000, 100_000, None), "image/png").await;
+    let resp = run_valid_upstream_request(settings, &upstream)
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 422);
+}
+
+#[tokio::test]
+async fn rejects_partial_images_to_requests_without_a_range() {
+    let mut settings = get_test_settings();
+    settings.image_max_pixels = 1_000_000;
+    settings.length_limit = 1024;
+    let png = get_png_header(100_000, 100_000, None);
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/"))
+        .respond_with(
+            ResponseTemplate::new(206)
+                .set_body_raw(png.clone(), "image/png")
+                .insert_header(
+                    "content-range",
+                    format!("bytes 0-{}/{}", png.len() - 1, png.len() + 1),
+                ),
+        )
+        .expect(1)
+        .mount(&upstream)
+        .await;
+    let resp = run_valid_upstream_request(settings, &upstream)
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 422);
+}
+
+#[tokio::test]
+async fn passes_jpegs_with_headers_beyond_the_window() {
+    let mut settings = get_test_settings();
+    settings.image_max_pixels = 1_000_000;
+    settings.length_limit = 1024 * 1024;
+    let mut jpeg = b"\xff\xd8".to_vec();
+    for _ in 0..5 {
+        jpeg.extend_from_slice(b"\xff\xe2\xff\xff");
+        jpeg.extend_from_slice(&[0; 0xfffd]);
+    }
+    jpeg.extend_from_slice(b"\xff\xc0\0\x0b\x08\0\x0a\0\x0a\x01\x01\x11\0\xff\xd9");
+    let upstream = get_body_mock(&jpeg, "image/jpeg").await;
+    let resp = run_valid_upstream_request(settings, &upstream)
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.bytes().await.unwrap().len(), jpeg.len());
+}
+
+#[tokio::test]
+async fn rejects_images_over_the_frame_limit() {
+    let mut settings = get_test_settings();
+    settings.image_max_frames = 100;
+    settings.length_limit = 1024;
+    let upstream = get_body_mock(&get_png_header(10, 10, Some(101)), "image/png").await;
+    let resp = run_valid_upstream_request(settings, &upstream)
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 422);
+}
+
+#[tokio::test]
+async fn rejects_gifs_over_the_frame_limit() {
+    let mut settings = get_test_settings();
+    settings.image_max_frames = 2;
+    settings.length_limit = 4096;
+    let mut gif = Vec::new();
+    {
+        let mut encoder = image::codecs::gif::GifEncoder::new(&mut gif);
+        let frames = (0..3).map(|_| image::Frame::new(image::RgbaImage::new(4, 4)));
+        encoder.encode_frames(frames).unwrap();
+    }
+    let upstream = get_body_mock(&gif, "image/gif").await;
+    let resp = run_valid_upstream_request(settings, &upstream)
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 422);
+}
+
+#[tokio::test]
+async fn aborts_streamed_gifs_over_the_frame_limit() {
+    let mut settings = get_test_settings();
+    settings.image_max_frames = 30;
+    settings.length_limit = 8192;
+    let mut gif = Vec::new();
+    {
+        let mut encoder = image::codecs::gif::GifEncoder::new(&mut gif);
+        let frames = (0..40).map(|_| image::Frame::new(image::RgbaImage::new(4, 4)));
+        encoder.encode_frames(frames).unwrap();
+    }
+    let upstream = get_split_body_server("image/gif", gif, 600).await;
+    let target = format!("http://{upstream}/");
+    let auth_target = AuthenticatedTarget::from_target(settings.key.as_bytes(), &target);
+
+    let (listen_addr, client) = run_test_server(settings).await;
+    let resp = client
+        .get(get_test_url(listen_addr, &auth_target))
+        .send()
+        .await
+        .unwrap();
+
+    // The frames after the first bytes are counted while streaming, so the
+    // status code is already sent when the limit is exceeded.
+    assert_eq!(resp.status(), 200);
+    assert!(resp.bytes().await.is_err());
+}
+
+#[tokio::test]
+async fn rejects_images_with_the_header_after_the_first_bytes() {
+    let mut settings = get_test_settings();
+    settings.image_max_width = 100;
+    settings.length_limit = 8192;
+    let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe1, 0x10, 0x00];
+    jpeg.extend_from_slice(&[0; 0x0ffe]);
+    let mut encoded = std::io::Cursor::new(Vec::new());
+    image::RgbImage::new(200, 10)
+        .write_to(&mut encoded, image::ImageFormat::Jpeg)
+        .unwrap();
+    jpeg.extend_from_slice(&encoded.into_inner()[2..]);
+
+    let upstream = get_body_mock(&jpeg, "image/jpeg").await;
+    let resp = run_valid_upstream_request(settings, &upstream)
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 422);
+}
+
+#[tokio::test]
+async fn passes_images_within_the_limits() {
+    let mut settings = get_test_settings();
+    settings.image_max_frames = 1;
+    settings.image_max_height = 100;
+    settings.image_max_pixels = 20_000;
+    settings.image_max_width = 200;
+    settings.length_limit = 4096;
+    let png = get_test_png(200, 100);
+    let upstream = get_body_mock(&png, "image/png").await;
+    let resp = run_valid_upstream_request(settings, &upstream)
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.bytes().await.unwrap(), png);
+}
+
+async fn run_meta_request(settings: Settings, upstream: &MockServer) -> reqwest::Response {
+    let auth_target = AuthenticatedTarget::from_target(settings.key.as_bytes(), &upstream.uri());
+
+    let (listen_addr, client) = run_test_server(settings).await;
+    client
+        .get(format!("{}/meta", get_test_url(listen_addr, &auth_target)))
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn describes_images_on_the_meta_endpoint() {
+    let mut settings = get_test_settings();
+    settings.meta_endpoint = true;
+    settings.length_limit = 4096;
+    let mut png = std::io::Cursor::new(Vec::new());
+    image::RgbImage::from_pixel(200, 100, image::Rgb([255, 0, 0]))
+        .write_to(&mut png, image::ImageFormat::Png)
+        .unwrap();
+    let png = png.into_inner();
+    let upstream = get_body_mock(&png, "image/png").await;
+    let resp = run_meta_request(settings, &upstream).await;
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        resp.headers().get("content-type").unwrap(),
+        "application/json"
+    );
+    let meta: serde_json::Value = serde_json::from_slice(&resp.bytes().await.unwrap()).unwrap();
+    assert_eq!(
+        meta,
+        serde_json::json!({
+            "content_type": "image/png",
+            "byte_length": png.len(),
+            "width": 200,
+            "height": 100,
+            "frames": 1,
+            "dominant_color": "#ff0000",
+        })
+    );
+}
+
+#[tokio::test]
+async fn describes_other_files_with_nulls_on_the_meta_endpoint() {
+    let mut settings = get_test_settings();
+    settings.meta_endpoint = true;
+    settings.length_limit = 1024;
+    let upstream = get_body_mock(b"BM not really a bitmap", "image/bmp").await;
+    let resp = run_meta_request(settings, &upstream).await;
+
+    assert_eq!(resp.status(), 200);
+    let meta: serde_json::Value = serde_json::from_slice(&resp.bytes().await.unwrap()).unwrap();
+    assert_eq!(meta["content_type"], "image/bmp");
+    assert_eq!(meta["byte_length"], 22);
+    assert!(meta["width"].is_null());
+    assert!(meta["dominant_color"].is_null());
+}
+
+#[tokio::test]
+async fn runs_the_usual_checks_on_the_meta_endpoint() {
+    let mut settings = get_test_settings();
+    settings.meta_endpoint = true;
+    let upstream = get_textplain_content_type_mock().await;
+    let resp = run_meta_request(settings, &upstream).await;
+
+    assert_eq!(resp.status(), 422);
+}
+
+#[tokio::test]
+async fn rejects_invalid_targets_on_the_meta_endpoint() {
+    let mut settings = get_test_settings();
+    settings.meta_endpoint = true;
+    let (listen_addr, client) = run_test_server(settings).await;
+    let auth_target =
+        AuthenticatedTarget::from_target("some random key".as_bytes(), "http://example.com");
+
+    let resp = client
+        .get(format!("{}/meta", get_test_url(listen_addr, &auth_target)))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 403);
+}
+
+#[tokio::test]
+async fn rewrites_redirects_to_the_meta_endpoint() {
+    let mut settings = get_test_settings();
+    settings.meta_endpoint = true;
+    let redirect_target = "https://example.com/another-image";
+    let upstream = get_redirect_mock(redirect_target).await;
+    let key = settings.key.clone();
+    let auth_target = AuthenticatedTarget::from_target(key.as_bytes(), &upstream.uri());
+
+    let (listen_addr, client) = run_test_server(settings).await;
+    let resp = client
+        .get(format!("{}/meta", get_test_url(listen_addr, &auth_target)))
+        .send()
+        .await
+        .unwrap();
+
+    let expected_target =
+        AuthenticatedTarget::from_target(key.as_bytes(), redirect_target).encoded_full_path();
+    assert_eq!(resp.status(), 302);
+    assert_eq!(
+        resp.headers().get("location").unwrap().to_str().unwrap(),
+        format!("http://{listen_addr}/{expected_target}/meta")
+    );
+}
+
+#[tokio::test]
+async fn does_not_serve_the_meta_endpoint_by_default() {
+    let upstream = MockServer::start().await;
+    let resp = run_meta_request(get_test_settings(), &upstream).await;
+
+    assert_eq!(resp.status(), 404);
+}
+
+async fn run_preview_request(settings: Settings, upstream: &MockServer) -> reqwest::Response {
+    let auth_target = AuthenticatedTarget::from_target(settings.key.as_bytes(), &upstream.uri());
+
+    let (listen_addr, client) = run_test_server(settings).await;
+    client
+        .get(format!(
+            "{}/preview",
+            get_test_url(listen_addr, &auth_target)
+        ))
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn renders_previews_on_the_preview_endpoint() {
+    let mut settings = get_test_settings();
+    settings.preview_endpoint = true;
+    settings.length_limit = 4096;
+    let upstream = get_body_mock(&get_test_png(200, 100), "image/png").await;
+    let resp = run_preview_request(settings, &upstream).await;
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/jpeg");
+    assert_eq!(
+        resp.headers().get("cache-control").unwrap(),
+        "public, max-age=86400"
+    );
+    let preview = image::load_from_memory(&resp.bytes().await.unwrap()).unwrap();
+    assert_eq!((preview.width(), preview.height()), (32, 16));
+}
+
+#[tokio::test]
+async fn requests_uncompressed_images_for_previews() {
+    let mut settings = get_test_settings();
+    settings.preview_endpoint = true;
+    settings.length_limit = 4096;
+    let upstream = get_uncompressed_body_mock(&get_test_png(20, 10), "image/png").await;
+    let auth_target = AuthenticatedTarget::from_target(settings.key.as_bytes(), &upstream.uri());
+
+    let (listen_addr, client) = run_test_server(settings).await;
+    let resp = client
+        .get(format!(
+            "{}/preview",
+            get_test_url(listen_addr, &auth_target)
+        ))
+        .header("accept-encoding", "gzip, br")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/jpeg");
+}
+
+#[tokio::test]
+async fn keeps_the_upstream_cache_control_for_previews() {
+    let mut settings = get_test_settings();
+    settings.preview_endpoint = true;
+    settings.length_limit = 4096;
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_raw(get_test_png(20, 10), "image/png")
+                .insert_header("cache-control", "max-age=60"),
+        )
+        .expect(1)
+        .mount(&upstream)
+        .await;
+    let resp = run_preview_request(settings, &upstream).await;
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("cache-control").unwrap(), "max-age=60");
+}
+
+#[tokio::test]
+async fn rejects_undecodable_images_on_the_preview_endpoint() {
+    let mut settings = get_test_settings();
+    settings.preview_endpoint = true;
+    settings.length_limit = 1024;
+    let upstream = get_body_mock(b"not really a png", "image/png").await;
+    let resp = run_preview_request(settings, &upstream).await;
+
+    assert_eq!(resp.status(), 422);
+}
+
+#[tokio::test]
+async fn rewrites_redirects_to_the_preview_endpoint() {
+    let mut settings = get_test_settings();
+    settings.preview_endpoint = true;
+    let redirect_target = "https://example.com/another-image";
+    let upstream = get_redirect_mock(redirect_target).await;
+    let key = settings.key.clone();
+    let auth_target = AuthenticatedTarget::from_target(key.as_bytes(), &upstream.uri());
+
+    let (listen_addr, client) = run_test_server(settings).await;
+    let resp = client
+        .get(format!(
+            "{}/preview",
+            get_test_url(listen_addr, &auth_target)
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    let expected_target =
+        AuthenticatedTarget::from_target(key.as_bytes(), redirect_target).encoded_full_path();
+    assert_eq!(resp.status(), 302);
+    assert_eq!(
+        resp.headers().get("location").unwrap().to_str().unwrap(),
+        format!("http://{listen_addr}/{expected_target}/preview")
+    );
+}
+
+#[tokio::test]
+async fn does_not_serve_the_preview_endpoint_by_default() {
+    let upstream = MockServer::start().await;
+    let resp = run_preview_request(get_test_settings(), &upstream).await;
+
+    assert_eq!(resp.status(), 404);
+}
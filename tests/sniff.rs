@@ -0,0 +1,104 @@
+use camo_rs::sniff::*;
+
+const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+#[test]
+fn detects_common_formats() {
+    let cases: &[(&[u8], &str)] = &[
+        (PNG, "image/png"),
+        (b"\xff\xd8\xff\xe0\0\x10JFIF", "image/jpeg"),
+        (b"GIF89a\x01\0\x01\0", "image/gif"),
+        (b"RIFF\x24\0\0\0WEBPVP8 ", "image/webp"),
+        (b"\0\0\0\x1cftypavif\0\0\0\0", "image/avif"),
+        (b"\0\0\0\x18ftypisom\0\0\x02\0", "video/mp4"),
+        (b"\x1a\x45\xdf\xa3\x9f\x42\x86\x81", "video/webm"),
+        (b"OggS\0\x02\0\0", "audio/ogg"),
+        (b"ID3\x04\0\0\0\0", "audio/mpeg"),
+        (b"\xff\xfb\x90\x64", "audio/mpeg"),
+        (b"PK\x03\x04\x14\0", "application/zip"),
+        (b"\n  <!DOCTYPE html>\n<html>", "text/html"),
+        (
+            b"<svg xmlns=\"http://www.w3.org/2000/svg\">",
+            "image/svg+xml",
+        ),
+    ];
+
+    for (prefix, mime) in cases {
+        assert_eq!(detect(prefix).map(|format| format.mime), Some(*mime));
+    }
+}
+
+#[test]
+fn detects_avifs_by_their_compatible_brands() {
+    let avif = b"\0\0\0\x1cftypmif1\0\0\0\0mif1avifmiaf";
+    let heif = b"\0\0\0\x18ftypmif1\0\0\0\0mif1heic";
+
+    assert_eq!(detect(avif).map(|format| format.mime), Some("image/avif"));
+    assert_eq!(detect(heif).map(|format| format.mime), Some("image/heif"));
+}
+
+#[test]
+fn does_not_detect_unknown_data() {
+    assert!(detect(b"hello world").is_none());
+    assert!(detect(b"<htmlfoo>").is_none());
+}
+
+#[test]
+fn accepts_matching_content_types() {
+    assert_eq!(check("image/png", PNG), Verdict::Matches);
+    assert_eq!(check("IMAGE/PNG; charset=binary", PNG), Verdict::Matches);
+    assert_eq!(
+        check("audio/mp4", b"\0\0\0\x18ftypM4A \0\0\x02\0"),
+        Verdict::Matches
+    );
+}
+
+#[test]
+fn rejects_contradicting_content_types() {
+    assert!(matches!(
+        check("image/png", b"<!doctype html><title>hi</title>"),
+        Verdict::Contradicts(Some(format)) if format.mime == "text/html"
+    ));
+    assert!(matches!(
+        check("image/jpeg", PNG),
+        Verdict::Contradicts(Some(format)) if format.mime == "image/png"
+    ));
+    assert_eq!(
+        check("image/png", b"definitely not a png"),
+        Verdict::Contradicts(None)
+    );
+}
+
+#[test]
+fn ignores_unknown_content_types_and_bodies() {
+    assert_eq!(
+        check("image/svg+xml", b"<!-- comment -->"),
+        Verdict::Unknown
+    );
+    assert_eq!(check("application/x-custom", b"data"), Verdict::Unknown);
+    assert_eq!(check("image/png", b""), Verdict::Unknown);
+}
+
+#[test]
+fn knows_generic_content_types() {
+    assert!(is_generic(None));
+    assert!(is_generic(Some("application/octet-stream")));
+    assert!(is_generic(Some("Binary/Octet-Stream; charset=binary")));
+    assert!(!is_generic(Some("image/png")));
+    assert!(!is_generic(Some("text/plain")));
+}
+
+#[test]
+fn guesses_types_from_url_extensions() {
+    assert_eq!(
+        guess_from_url("https://example.com/cat.PNG?size=large#top"),
+        Some("image/png")
+    );
+    assert_eq!(
+        guess_from_url("https://example.com/a/b/song.mp3"),
+        Some("audio/mpeg")
+    );
+    assert_eq!(guess_from_url("https://example.com/page.html"), None);
+    assert_eq!(guess_from_url("https://example.com/image"), None);
+    assert_eq!(guess_from_url("https://example.com/"), None);
+}
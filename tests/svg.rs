@@ -0,0 +1,74 @@
+use camo_rs::svg::*;
+
+fn sanitize_str(svg: &str) -> String {
+    String::from_utf8(sanitize(svg.as_bytes()).unwrap()).unwrap()
+}
+
+#[test]
+fn keeps_harmless_svgs() {
+    let svg = sanitize_str(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 10 10"><defs><linearGradient id="g"><stop offset="0" stop-color="red"/></linearGradient></defs><rect width="10" height="10" fill="url(#g)"/><text x="1" y="5">a &amp; b</text></svg>"##,
+    );
+
+    assert!(svg.contains(r#"viewBox="0 0 10 10""#));
+    assert!(svg.contains(r##"fill="url(#g)""##));
+    assert!(svg.contains("<stop offset=\"0\" stop-color=\"red\"></stop>"));
+    assert!(svg.contains("a &amp; b"));
+}
+
+#[test]
+fn removes_scripts_and_event_handlers() {
+    let svg = sanitize_str(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" onload="alert(1)"><script>alert(2)</script><rect ONCLICK="alert(3)" width="1"/><set attributeName="onmouseover" to="alert(4)"/></svg>"#,
+    );
+
+    assert!(!svg.contains("alert"));
+    assert!(svg.contains(r#"<rect width="1"></rect>"#));
+}
+
+#[test]
+fn removes_foreign_objects() {
+    let svg = sanitize_str(
+        r#"<svg xmlns="http://www.w3.org/2000/svg"><foreignObject><body xmlns="http://www.w3.org/1999/xhtml"><iframe src="https://evil.example.com"/></body></foreignObject></svg>"#,
+    );
+
+    assert!(!svg.contains("foreignObject"));
+    assert!(!svg.contains("evil"));
+}
+
+#[test]
+fn removes_external_references() {
+    let svg = sanitize_str(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink"><style>@import url(https://evil.example.com/a.css);</style><image href="https://evil.example.com/a.png"/><image xlink:href="data:image/png;base64,AAAA"/><use xlink:href="#local"/><a href="javascript:alert(1)"><rect fill="url(https://evil.example.com/#x)" style="fill: u\72l(https://evil.example.com)"/></a></svg>"##,
+    );
+
+    assert!(!svg.contains("evil"));
+    assert!(!svg.contains("javascript"));
+    assert!(svg.contains(r#"xlink:href="data:image/png;base64,AAAA""#));
+    assert!(svg.contains(r##"<use xlink:href="#local"></use>"##));
+    assert!(svg.contains("<rect></rect>"));
+}
+
+#[test]
+fn rejects_invalid_svgs() {
+    assert!(sanitize(b"<html><body></body></html>").is_err());
+    assert!(sanitize(b"<svg xmlns=\"http://www.w3.org/2000/svg\">").is_err());
+    assert!(
+        sanitize(
+            br#"<?xml version="1.0"?><!DOCTYPE svg [<!ENTITY a "aaaa">]><svg xmlns="http://www.w3.org/2000/svg">&a;</svg>"#
+        )
+        .is_err()
+    );
+}
+
+#[test]
+fn rasterizes_svgs() {
+    let png = rasterize(
+        br#"<svg xmlns="http://www.w3.org/2000/svg" width="20" height="10"><rect width="20" height="10" fill="red"/></svg>"#,
+    )
+    .unwrap();
+
+    assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+    // The IHDR chunk has the width and height right after the signature.
+    assert_eq!(&png[16..24], &[0, 0, 0, 20, 0, 0, 0, 10]);
+}
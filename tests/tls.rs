@@ -0,0 +1,100 @@
+use std::path::PathBuf;
+
+use camo_rs::{
+    errors::SetupError,
+    settings::{TlsRoots, TlsVersion},
+    tls::*,
+};
+
+pub mod helpers;
+use helpers::application::*;
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+#[test]
+fn builds_with_bundled_roots() {
+    let settings = get_test_settings();
+
+    assert!(build_client_config(&settings, false).is_ok());
+}
+
+#[test]
+fn builds_with_tls13_only() {
+    let mut settings = get_test_settings();
+    settings.upstream_tls_min_version = TlsVersion::Tls13;
+
+    assert!(build_client_config(&settings, false).is_ok());
+}
+
+#[test]
+fn builds_with_ca_bundle() {
+    let mut settings = get_test_settings();
+    settings.upstream_ca_bundle = Some(fixture("self-signed.pem"));
+
+    assert!(build_client_config(&settings, false).is_ok());
+}
+
+#[test]
+fn fails_gracefully_for_missing_ca_bundle() {
+    let mut settings = get_test_settings();
+    settings.upstream_ca_bundle = Some(fixture("does-not-exist.pem"));
+
+    assert!(matches!(
+        build_client_config(&settings, false),
+        Err(SetupError::CaBundleUnreadable(..))
+    ));
+}
+
+#[test]
+fn fails_gracefully_for_ca_bundle_without_certificates() {
+    let mut settings = get_test_settings();
+    settings.upstream_ca_bundle = Some(fixture("self-signed.key"));
+
+    assert!(matches!(
+        build_client_config(&settings, false),
+        Err(SetupError::CaBundleUnreadable(..))
+    ));
+}
+
+#[test]
+fn builds_with_client_certificate() {
+    let mut settings = get_test_settings();
+    settings.upstream_client_cert = Some(fixture("self-signed.pem"));
+    settings.upstream_client_key = Some(fixture("self-signed.key"));
+
+    assert!(build_client_config(&settings, true).is_ok());
+}
+
+#[test]
+fn fails_gracefully_for_invalid_client_key() {
+    let mut settings = get_test_settings();
+    settings.upstream_client_cert = Some(fixture("self-signed.pem"));
+    settings.upstream_client_key = Some(fixture("self-signed.pem"));
+
+    assert!(matches!(
+        build_client_config(&settings, true),
+        Err(SetupError::ClientKeyUnreadable(..))
+    ));
+}
+
+#[test]
+fn fails_gracefully_without_any_roots() {
+    let mut settings = get_test_settings();
+    settings.upstream_tls_roots = TlsRoots::Native;
+
+    // Pointing the native loader to an empty directory leaves no roots.
+    // SAFETY: no other test in this binary reads these variables.
+    unsafe {
+        std::env::set_var("SSL_CERT_DIR", fixture("empty"));
+        std::env::set_var("SSL_CERT_FILE", fixture("does-not-exist.pem"));
+    }
+
+    assert!(matches!(
+        build_client_config(&settings, false),
+        Err(SetupError::NoTlsRoots)
+    ));
+}
@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use camo_rs::{Settings, metrics::Metrics, upstream_health::*};
+use tokio::time::Instant;
+
+pub mod helpers;
+use helpers::application::*;
+
+fn get_breaker_settings(threshold: usize, cooldown: usize) -> Settings {
+    let mut settings = get_test_settings();
+    settings.upstream_circuit_breaker_threshold = threshold;
+    settings.upstream_circuit_breaker_cooldown = cooldown;
+    settings
+}
+
+fn deadline() -> Instant {
+    Instant::now() + Duration::from_secs(1)
+}
+
+#[tokio::test]
+async fn records_opening_a_circuit_once() {
+    let metrics = Metrics::default();
+    let registry = HostRegistry::new(&get_breaker_settings(1, 30), metrics.clone());
+
+    // Both requests were in flight before the circuit opened.
+    let first = registry.acquire("example.com", deadline()).await.unwrap();
+    let second = registry.acquire("example.com", deadline()).await.unwrap();
+    first.record_failure();
+    second.record_failure();
+
+    let rendered = metrics.render();
+    assert!(rendered.contains("camo_upstream_circuit_transitions_total{state=\"open\"} 1"));
+    assert!(rendered.contains("camo_upstream_circuits_open 1"));
+    assert!(!rendered.contains("example.com"));
+}
+
+#[tokio::test]
+async fn records_probes_and_closing_a_circuit() {
+    let metrics = Metrics::default();
+    let registry = HostRegistry::new(&get_breaker_settings(1, 0), metrics.clone());
+
+    registry
+        .acquire("example.com", deadline())
+        .await
+        .unwrap()
+        .record_failure();
+    registry
+        .acquire("example.com", deadline())
+        .await
+        .unwrap()
+        .record_success();
+
+    let rendered = metrics.render();
+    assert!(rendered.contains("camo_upstream_circuit_transitions_total{state=\"half_open\"} 1"));
+    assert!(rendered.contains("camo_upstream_circuit_transitions_total{state=\"closed\"} 1"));
+    assert!(rendered.contains("camo_upstream_circuits_open 0"));
+}
+
+#[tokio::test]
+async fn fails_fast_while_the_circuit_is_open() {
+    let registry = HostRegistry::new(&get_breaker_settings(1, 30), Metrics::default());
+
+    registry
+        .acquire("example.com", deadline())
+        .await
+        .unwrap()
+        .record_failure();
+
+    assert!(registry.acquire("example.com", deadline()).await.is_err());
+    assert!(registry.acquire("example.org", deadline()).await.is_ok());
+}